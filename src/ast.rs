@@ -1,108 +1,540 @@
-use std::fmt::{Debug, Error, Formatter};
+use std::fmt::{Debug, Display, Error, Formatter};
+use std::ops::{Index, IndexMut};
+use std::str::FromStr;
+
+use id_arena::{Arena, Id};
 use thiserror::Error;
 
+use crate::lexer::LexError;
+
 #[derive(Error, Debug)]
 pub enum ChipParseError<'a> {
     #[error("Tag was closed incorrectly: {0:?} != {1:?}")]
-    TagClosedIncorrectly(Ident<'a>, Ident<'a>),
-    #[error("Integer literal was too large (couldn't fit into i64): {0}")]
-    IntTooLarge(&'a str),
-    #[error("Float literal was too large (couldn't fit into f64): {0}")]
-    FloatTooLarge(&'a str),
+    TagClosedIncorrectly(Ident, Ident),
+    #[error("Integer literal was too large (couldn't fit into {}): {0}", .1.suffix())]
+    IntTooLarge(&'a str, NumType, Span),
+    #[error("Float literal was too large (couldn't fit into {}): {0}", .1.suffix())]
+    FloatTooLarge(&'a str, NumType, Span),
+    #[error("Unsigned literal can't be negative ({}): {0}", .1.suffix())]
+    NegativeUnsigned(&'a str, NumType, Span),
+    #[error("Float literal can't have an integer suffix ({}): {0}", .1.suffix())]
+    FloatWithIntSuffix(&'a str, NumType, Span),
+    #[error(transparent)]
+    Lex(#[from] LexError),
+}
+
+impl ChipParseError<'_> {
+    /// Where in the source this error should be reported
+    pub fn span(&self) -> Span {
+        match self {
+            ChipParseError::TagClosedIncorrectly(_, right) => right.1,
+            ChipParseError::IntTooLarge(_, _, span)
+            | ChipParseError::FloatTooLarge(_, _, span)
+            | ChipParseError::NegativeUnsigned(_, _, span)
+            | ChipParseError::FloatWithIntSuffix(_, _, span) => *span,
+            ChipParseError::Lex(err) => err.span(),
+        }
+    }
+}
+
+pub type StmtId = Id<Statement>;
+pub type IdentId = Id<Ident>;
+
+/// A parsed file. It owns all of its nodes: statements and identifiers live in arenas
+/// and are referred to by id, so later passes can key their side tables by [`StmtId`] and [`IdentId`].
+#[derive(Default)]
+pub struct CompilationUnit {
+    pub defs: Vec<TopLevelDef>,
+    statements: Arena<Statement>,
+    /// The span of every statement, indexed like `statements`, see [`CompilationUnit::span`]
+    spans: Vec<Option<Span>>,
+    idents: Arena<Ident>,
+}
+
+impl CompilationUnit {
+    /// Adds a statement whose children were already allocated in this unit
+    pub fn alloc(&mut self, statement: Statement) -> StmtId {
+        let span = self.statement_span(&statement);
+        self.spans.push(span);
+        self.statements.alloc(statement)
+    }
+
+    pub fn alloc_ident(&mut self, name: impl Into<String>, span: Span) -> IdentId {
+        self.idents.alloc(Ident(name.into(), span))
+    }
+
+    /// The source covered by the identifiers and literals in a statement,
+    /// `None` if it contains neither (e.g. an empty block)
+    pub fn span(&self, id: StmtId) -> Option<Span> {
+        self.spans[id.index()]
+    }
+
+    /// Every statement in allocation order, children come before their parents
+    pub fn statements(&self) -> impl Iterator<Item = (StmtId, &Statement)> {
+        self.statements.iter()
+    }
+
+    /// Pairs `node` with this unit, to print or compare it
+    pub fn with<'u, T: ?Sized>(&'u self, node: &'u T) -> WithUnit<'u, T> {
+        WithUnit { unit: self, node }
+    }
+
+    fn statement_span(&self, statement: &Statement) -> Option<Span> {
+        fn join(l: Option<Span>, r: Option<Span>) -> Option<Span> {
+            match (l, r) {
+                (Some(l), Some(r)) => Some(l.to(r)),
+                (l, r) => l.or(r),
+            }
+        }
+        let list_span = |list: &[StmtId]| {
+            list.iter()
+                .fold(None, |span, id| join(span, self.span(*id)))
+        };
+        let ident = |id: &IdentId| Some(self[*id].1);
+
+        use self::Statement::*;
+        match statement {
+            Number(_, span) => Some(*span),
+            Op(l, _, r) => join(self.span(*l), self.span(*r)),
+            FunctionCall(call) => join(ident(&call.ident), list_span(&call.args.0)),
+            If(cond, block) => join(self.span(*cond), list_span(&block.0)),
+            IfElse(cond, if_block, else_block) => join(
+                join(self.span(*cond), list_span(&if_block.0)),
+                list_span(&else_block.0),
+            ),
+            Let(_, ident_id, _) => ident(ident_id),
+            LetAssign(_, ident_id, _, value) => join(ident(ident_id), self.span(*value)),
+            Assign(idents, value) => idents
+                .0
+                .iter()
+                .fold(self.span(*value), |span, id| join(span, ident(id))),
+            Tag(tag) => ident(&tag.ident),
+            Ident(ident_id) => ident(ident_id),
+            Block(list) => list_span(&list.0),
+            Cast(value, chip_type) => join(self.span(*value), ident(chip_type)),
+            Error => None,
+        }
+    }
+}
+
+impl Index<StmtId> for CompilationUnit {
+    type Output = Statement;
+
+    fn index(&self, id: StmtId) -> &Statement {
+        &self.statements[id]
+    }
+}
+
+/// Rewrites keep the span computed when the statement was allocated
+impl IndexMut<StmtId> for CompilationUnit {
+    fn index_mut(&mut self, id: StmtId) -> &mut Statement {
+        &mut self.statements[id]
+    }
+}
+
+impl Index<IdentId> for CompilationUnit {
+    type Output = Ident;
+
+    fn index(&self, id: IdentId) -> &Ident {
+        &self.idents[id]
+    }
+}
+
+/// A node together with the unit its ids point into, see [`CompilationUnit::with`]
+pub struct WithUnit<'u, T: ?Sized> {
+    pub unit: &'u CompilationUnit,
+    pub node: &'u T,
+}
+
+impl<'u, T: ?Sized> WithUnit<'u, T> {
+    /// Another node of the same unit
+    pub fn with<U: ?Sized>(&self, node: &'u U) -> WithUnit<'u, U> {
+        WithUnit {
+            unit: self.unit,
+            node,
+        }
+    }
+}
+
+impl<T: ?Sized> Clone for WithUnit<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
 }
 
-pub struct CompilationUnit<'a>(pub Vec<TopLevelDef<'a>>);
+impl<T: ?Sized> Copy for WithUnit<'_, T> {}
 
-pub enum TopLevelDef<'a> {
-    Func(FunctionDef<'a>),
-    Export(Ident<'a>, TagList<'a>),
-    TagDef(TagDef<'a>),
+pub enum TopLevelDef {
+    Func(FunctionDef),
+    Export(IdentId, TagList),
+    TagDef(TagDef),
 }
 
-pub struct TagDef<'a> {
-    pub ident: Ident<'a>,
-    pub states: StateList<'a>,
-    pub fns: FunctionDefList<'a>,
+pub struct TagDef {
+    pub ident: IdentId,
+    pub props: PropList,
+    pub states: StateList,
+    pub fns: FunctionDefList,
 }
 
-pub struct StateList<'a>(pub Vec<State<'a>>);
+/// Properties a parent passes in via `<MyTag name=value/>`
+pub struct PropList(pub Vec<Prop>);
 
-pub struct State<'a> {
-    pub ident: Ident<'a>,
-    pub chip_type: Option<Ident<'a>>,
-    pub default: Option<Box<Statement<'a>>>,
+/// `prop name: Type` is required, `prop name: Type = default` is optional
+pub struct Prop {
+    pub ident: IdentId,
+    pub chip_type: IdentId,
+    pub default: Option<StmtId>,
 }
 
-pub struct FunctionDefList<'a>(pub Vec<FunctionDef<'a>>);
+/// A single `prop` or `state` line inside a tag definition, only used while parsing
+pub enum TagMember {
+    Prop(Prop),
+    State(State),
+}
+
+pub struct StateList(pub Vec<State>);
+
+pub struct State {
+    pub ident: IdentId,
+    pub chip_type: Option<IdentId>,
+    pub default: Option<StmtId>,
+}
+
+pub struct FunctionDefList(pub Vec<FunctionDef>);
 
-pub struct FunctionDef<'a> {
-    pub ident: Ident<'a>,
-    pub args: ArgDefList<'a>,
-    pub return_type: Option<Ident<'a>>,
-    pub block: StatementList<'a>,
+pub struct FunctionDef {
+    pub ident: IdentId,
+    pub args: ArgDefList,
+    pub return_type: Option<IdentId>,
+    pub block: StatementList,
 }
 
-pub struct ArgDefList<'a>(pub Vec<ArgDef<'a>>);
+pub struct ArgDefList(pub Vec<ArgDef>);
 
-pub struct ArgDef<'a> {
-    pub name: Ident<'a>,
-    pub chip_type: Ident<'a>,
+pub struct ArgDef {
+    pub name: IdentId,
+    pub chip_type: IdentId,
 }
 
-pub struct StatementList<'a>(pub Vec<Box<Statement<'a>>>);
+pub struct StatementList(pub Vec<StmtId>);
 
-pub enum Statement<'a> {
-    Number(Number),
-    Op(Box<Statement<'a>>, Opcode, Box<Statement<'a>>),
-    FunctionCall(FunctionCall<'a>),
-    If(Box<Statement<'a>>, StatementList<'a>),
-    IfElse(Box<Statement<'a>>, StatementList<'a>, StatementList<'a>),
-    Let(Ident<'a>),
-    LetAssign(Ident<'a>, Box<Statement<'a>>),
-    Assign(IdentList<'a>, Box<Statement<'a>>),
-    Tag(Tag<'a>),
-    Ident(Ident<'a>),
-    Block(StatementList<'a>),
+pub enum Statement {
+    Number(Number, Span),
+    Op(StmtId, Opcode, StmtId),
+    FunctionCall(FunctionCall),
+    If(StmtId, StatementList),
+    IfElse(StmtId, StatementList, StatementList),
+    /// `let name` or `let name: Type`, assigned later
+    Let(Mutability, IdentId, Option<IdentId>),
+    LetAssign(Mutability, IdentId, Option<IdentId>, StmtId),
+    Assign(IdentList, StmtId),
+    Tag(Tag),
+    Ident(IdentId),
+    Block(StatementList),
+    /// `value as Type`, only between numeric types
+    Cast(StmtId, IdentId),
     Error,
 }
 
-pub struct FunctionCall<'a> {
-    pub ident: Ident<'a>,
-    pub args: ArgList<'a>,
+impl Statement {
+    /// How tightly the printed statement binds, see [`Opcode::precedence`]
+    fn precedence(&self) -> u8 {
+        use self::Statement::*;
+        match self {
+            Number(..) | Ident(_) | Error => 4,
+            Cast(..) => 3,
+            Op(_, op, _) => op.precedence(),
+            FunctionCall(_) | Tag(_) => 1,
+            If(..) | IfElse(..) | Let(..) | LetAssign(..) | Assign(..) | Block(_) => 0,
+        }
+    }
+}
+
+/// Bindings are immutable unless declared with `let mut`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mutability {
+    Immutable,
+    Mutable,
+}
+
+pub struct FunctionCall {
+    pub ident: IdentId,
+    pub args: ArgList,
 }
 
-pub struct ArgList<'a>(pub Vec<Box<Statement<'a>>>);
+pub struct ArgList(pub Vec<StmtId>);
 
-pub struct IdentList<'a>(pub Vec<Ident<'a>>);
+/// `variable.field.field`, in source order
+pub struct IdentList(pub Vec<IdentId>);
 
-pub struct TagList<'a>(pub Vec<Tag<'a>>);
+pub struct TagList(pub Vec<Tag>);
 
-pub struct Tag<'a> {
-    pub ident: Ident<'a>,
-    pub properties: PropertyList<'a>,
-    pub children: TagList<'a>,
+pub struct Tag {
+    pub ident: IdentId,
+    pub properties: PropertyList,
+    pub children: TagChildList,
 }
 
-pub struct PropertyList<'a>(pub Vec<(Ident<'a>, Ident<'a>)>);
+pub struct TagChildList(pub Vec<TagChild>);
 
-#[derive(PartialEq, Eq)]
-pub struct Ident<'a>(pub &'a str);
+impl TagChildList {
+    /// The children that are tags, skipping text
+    pub fn tags(&self) -> impl Iterator<Item = &Tag> {
+        self.0.iter().filter_map(|child| match child {
+            TagChild::Tag(tag) => Some(tag),
+            TagChild::Text(..) => None,
+        })
+    }
+}
+
+/// Something between an opening and a closing tag
+pub enum TagChild {
+    Tag(Tag),
+    /// Text without the surrounding whitespace
+    Text(String, Span),
+}
+
+pub struct PropertyList(pub Vec<(IdentId, StmtId)>);
+
+/// Byte offsets into the source text, `end` is exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    /// The smallest span covering both `self` and `other`
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+/// Two idents are equal if their names are, regardless of where they are in the source
+#[derive(Clone)]
+pub struct Ident(pub String, pub Span);
+
+impl PartialEq for Ident {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
 
-pub struct Struct<'a> {
-    pub ident: Ident<'a>,
-    pub generics: Vec<Ident<'a>>,
+impl Eq for Ident {}
+
+#[allow(dead_code)]
+pub struct Struct {
+    pub ident: IdentId,
+    pub generics: Vec<IdentId>,
+}
+
+#[allow(dead_code)]
+pub struct Type {
+    pub ident: IdentId,
+    pub generics: Vec<IdentId>,
 }
 
-pub struct Type<'a> {
-    pub ident: Ident<'a>,
-    pub generics: Vec<Ident<'a>>,
+/// The numeric types, named `Int`, `Float`, `I32`, `U8`, ... in type annotations
+/// and `i64`, `f64`, `i32`, `u8`, ... as literal suffixes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NumType {
+    I32,
+    I64,
+    U8,
+    U32,
+    U64,
+    F32,
+    F64,
 }
 
+impl NumType {
+    pub const ALL: [NumType; 7] = [
+        NumType::I32,
+        NumType::I64,
+        NumType::U8,
+        NumType::U32,
+        NumType::U64,
+        NumType::F32,
+        NumType::F64,
+    ];
+
+    /// Type names are case sensitive: `Int`, `Float`, and the suffix in capitals like `U8`.
+    /// `I64` and `F64` are the same as `Int` and `Float`.
+    pub fn from_name(name: &str) -> Option<NumType> {
+        match name {
+            "Int" => Some(NumType::I64),
+            "Float" => Some(NumType::F64),
+            _ => NumType::ALL
+                .into_iter()
+                .find(|ty| ty.suffix().to_uppercase() == name),
+        }
+    }
+
+    /// How this type is written in type annotations
+    pub fn name(self) -> &'static str {
+        match self {
+            NumType::I32 => "I32",
+            NumType::I64 => "Int",
+            NumType::U8 => "U8",
+            NumType::U32 => "U32",
+            NumType::U64 => "U64",
+            NumType::F32 => "F32",
+            NumType::F64 => "Float",
+        }
+    }
+
+    /// How this type is written as a literal suffix
+    pub fn suffix(self) -> &'static str {
+        match self {
+            NumType::I32 => "i32",
+            NumType::I64 => "i64",
+            NumType::U8 => "u8",
+            NumType::U32 => "u32",
+            NumType::U64 => "u64",
+            NumType::F32 => "f32",
+            NumType::F64 => "f64",
+        }
+    }
+
+    pub fn is_float(self) -> bool {
+        matches!(self, NumType::F32 | NumType::F64)
+    }
+
+    pub fn is_signed(self) -> bool {
+        matches!(
+            self,
+            NumType::I32 | NumType::I64 | NumType::F32 | NumType::F64
+        )
+    }
+
+    /// Whether an integer `value` can be represented by this type
+    pub fn fits(self, value: i128) -> bool {
+        match self {
+            NumType::I32 => i32::try_from(value).is_ok(),
+            NumType::I64 => i64::try_from(value).is_ok(),
+            NumType::U8 => u8::try_from(value).is_ok(),
+            NumType::U32 => u32::try_from(value).is_ok(),
+            NumType::U64 => u64::try_from(value).is_ok(),
+            NumType::F32 | NumType::F64 => true,
+        }
+    }
+
+    /// Splits a type suffix like `u8` off the end of a literal.
+    /// In hexadecimal literals `f32` and `f64` are digits, not suffixes.
+    pub fn split_suffix(literal: &str, hex: bool) -> (&str, Option<NumType>) {
+        NumType::ALL
+            .into_iter()
+            .filter(|ty| !(hex && ty.is_float()))
+            .find_map(|ty| {
+                literal
+                    .strip_suffix(ty.suffix())
+                    .map(|digits| (digits, Some(ty)))
+            })
+            .unwrap_or((literal, None))
+    }
+}
+
+impl Display for NumType {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The type is `None` if the literal has no suffix
+#[derive(PartialEq)]
 pub enum Number {
-    Int(i64),
-    Float(f64),
+    /// `U64`s above `i64::MAX` are stored with the same bits
+    Int(i64, Option<NumType>),
+    Float(f64, Option<NumType>),
+}
+
+impl<'a> Number {
+    /// Parses any numeric literal: an optional sign, decimal digits or `0x`, `0o` and `0b`
+    /// prefixed digits, underscores between digits and an optional type suffix.
+    /// Decimal floats have an `e` exponent, hexadecimal floats need a `p` exponent (`0x1.8p3`).
+    pub fn parse(literal: &'a str, span: Span) -> Result<Number, ChipParseError<'a>> {
+        let (negative, unsigned) = match literal.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, literal.strip_prefix('+').unwrap_or(literal)),
+        };
+        let (radix, digits) = [("0x", 16), ("0o", 8), ("0b", 2)]
+            .into_iter()
+            .find_map(|(prefix, radix)| unsigned.strip_prefix(prefix).map(|rest| (radix, rest)))
+            .unwrap_or((10, unsigned));
+        let hex_float = radix == 16 && digits.contains(['p', 'P']);
+
+        // `f32` and `f64` are digits at the end of a hexadecimal int
+        let (digits, suffix) = NumType::split_suffix(digits, radix == 16 && !hex_float);
+        let digits = digits.replace('_', "");
+        let is_float = hex_float
+            || suffix.is_some_and(NumType::is_float)
+            || (radix == 10 && digits.contains(['.', 'e', 'E']));
+
+        if !is_float {
+            let num_type = suffix.unwrap_or(NumType::I64);
+            let too_large = || ChipParseError::IntTooLarge(literal, num_type, span);
+            let magnitude = u64::from_str_radix(&digits, radix).map_err(|_| too_large())? as i128;
+            let value = if negative { -magnitude } else { magnitude };
+            if value < 0 && !num_type.is_signed() {
+                return Err(ChipParseError::NegativeUnsigned(literal, num_type, span));
+            }
+            if !num_type.fits(value) {
+                return Err(too_large());
+            }
+            return Ok(Number::Int(value as i64, suffix));
+        }
+
+        let num_type = suffix.unwrap_or(NumType::F64);
+        if !num_type.is_float() {
+            return Err(ChipParseError::FloatWithIntSuffix(literal, num_type, span));
+        }
+        let too_large = || ChipParseError::FloatTooLarge(literal, num_type, span);
+        let magnitude = if hex_float {
+            parse_hex_float(&digits).ok_or_else(too_large)?
+        } else {
+            f64::from_str(&digits).map_err(|_| too_large())?
+        };
+        let value = if negative { -magnitude } else { magnitude };
+        if value.is_infinite() || (num_type == NumType::F32 && (value as f32).is_infinite()) {
+            return Err(too_large());
+        }
+
+        Ok(Number::Float(value, suffix))
+    }
+
+    pub fn num_type(&self) -> NumType {
+        match self {
+            Number::Int(_, suffix) => suffix.unwrap_or(NumType::I64),
+            Number::Float(_, suffix) => suffix.unwrap_or(NumType::F64),
+        }
+    }
+}
+
+/// The value of hexadecimal float digits without the `0x` prefix, like `1.8p3`
+fn parse_hex_float(digits: &str) -> Option<f64> {
+    let (mantissa, exponent) = digits.split_once(['p', 'P'])?;
+    let (int, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+
+    let mut value = 0.0;
+    for digit in int.chars() {
+        value = value * 16.0 + f64::from(digit.to_digit(16)?);
+    }
+    let mut scale = 1.0 / 16.0;
+    for digit in fraction.chars() {
+        value += f64::from(digit.to_digit(16)?) * scale;
+        scale /= 16.0;
+    }
+
+    let exponent = i32::from_str(exponent).ok()?;
+    Some(value * 2f64.powi(exponent))
 }
 
+#[derive(PartialEq, Eq)]
 pub enum Opcode {
     Mul,
     Div,
@@ -116,139 +548,415 @@ pub enum Opcode {
     Ne,
 }
 
-impl Debug for CompilationUnit<'_> {
+impl Opcode {
+    /// `*` and `/` bind tighter than `+` and `-`, which bind tighter than comparisons
+    pub fn precedence(&self) -> u8 {
+        use self::Opcode::*;
+        match self {
+            Mul | Div => 2,
+            Add | Sub => 1,
+            Gt | Ge | Lt | Le | Eq | Ne => 0,
+        }
+    }
+}
+
+// Nodes of two units are compared through `WithUnit`, structurally like a derived impl would,
+// ignoring spans like `Ident` does.
+
+impl PartialEq for CompilationUnit {
+    fn eq(&self, other: &Self) -> bool {
+        self.with(self.defs.as_slice()) == other.with(other.defs.as_slice())
+    }
+}
+
+impl<'u, T> PartialEq for WithUnit<'u, [T]>
+where
+    WithUnit<'u, T>: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.node.len() == other.node.len()
+            && self
+                .node
+                .iter()
+                .zip(other.node)
+                .all(|(l, r)| self.with(l) == other.with(r))
+    }
+}
+
+impl<'u, T> PartialEq for WithUnit<'u, Option<T>>
+where
+    WithUnit<'u, T>: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        match (self.node, other.node) {
+            (Some(l), Some(r)) => self.with(l) == other.with(r),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+impl PartialEq for WithUnit<'_, IdentId> {
+    fn eq(&self, other: &Self) -> bool {
+        self.unit[*self.node] == other.unit[*other.node]
+    }
+}
+
+impl PartialEq for WithUnit<'_, StmtId> {
+    fn eq(&self, other: &Self) -> bool {
+        self.with(&self.unit[*self.node]) == other.with(&other.unit[*other.node])
+    }
+}
+
+impl PartialEq for WithUnit<'_, TopLevelDef> {
+    fn eq(&self, other: &Self) -> bool {
+        use self::TopLevelDef::*;
+        match (self.node, other.node) {
+            (Func(l), Func(r)) => self.with(l) == other.with(r),
+            (Export(i1, t1), Export(i2, t2)) => {
+                self.with(i1) == other.with(i2)
+                    && self.with(t1.0.as_slice()) == other.with(t2.0.as_slice())
+            }
+            (TagDef(l), TagDef(r)) => self.with(l) == other.with(r),
+            _ => false,
+        }
+    }
+}
+
+impl PartialEq for WithUnit<'_, TagDef> {
+    fn eq(&self, other: &Self) -> bool {
+        let (l, r) = (self.node, other.node);
+        self.with(&l.ident) == other.with(&r.ident)
+            && self.with(l.props.0.as_slice()) == other.with(r.props.0.as_slice())
+            && self.with(l.states.0.as_slice()) == other.with(r.states.0.as_slice())
+            && self.with(l.fns.0.as_slice()) == other.with(r.fns.0.as_slice())
+    }
+}
+
+impl PartialEq for WithUnit<'_, Prop> {
+    fn eq(&self, other: &Self) -> bool {
+        let (l, r) = (self.node, other.node);
+        self.with(&l.ident) == other.with(&r.ident)
+            && self.with(&l.chip_type) == other.with(&r.chip_type)
+            && self.with(&l.default) == other.with(&r.default)
+    }
+}
+
+impl PartialEq for WithUnit<'_, State> {
+    fn eq(&self, other: &Self) -> bool {
+        let (l, r) = (self.node, other.node);
+        self.with(&l.ident) == other.with(&r.ident)
+            && self.with(&l.chip_type) == other.with(&r.chip_type)
+            && self.with(&l.default) == other.with(&r.default)
+    }
+}
+
+impl PartialEq for WithUnit<'_, FunctionDef> {
+    fn eq(&self, other: &Self) -> bool {
+        let (l, r) = (self.node, other.node);
+        self.with(&l.ident) == other.with(&r.ident)
+            && self.with(l.args.0.as_slice()) == other.with(r.args.0.as_slice())
+            && self.with(&l.return_type) == other.with(&r.return_type)
+            && self.with(&l.block) == other.with(&r.block)
+    }
+}
+
+impl PartialEq for WithUnit<'_, ArgDef> {
+    fn eq(&self, other: &Self) -> bool {
+        self.with(&self.node.name) == other.with(&other.node.name)
+            && self.with(&self.node.chip_type) == other.with(&other.node.chip_type)
+    }
+}
+
+impl PartialEq for WithUnit<'_, StatementList> {
+    fn eq(&self, other: &Self) -> bool {
+        self.with(self.node.0.as_slice()) == other.with(other.node.0.as_slice())
+    }
+}
+
+/// The spans of literals are ignored like those of [`Ident`]s
+impl PartialEq for WithUnit<'_, Statement> {
+    fn eq(&self, other: &Self) -> bool {
+        let l = |node| self.with(node);
+        let r = |node| other.with(node);
+        use self::Statement::*;
+        match (self.node, other.node) {
+            (Number(n1, _), Number(n2, _)) => n1 == n2,
+            (Op(l1, op1, r1), Op(l2, op2, r2)) => l(l1) == r(l2) && op1 == op2 && l(r1) == r(r2),
+            (FunctionCall(c1), FunctionCall(c2)) => self.with(c1) == other.with(c2),
+            (If(c1, b1), If(c2, b2)) => l(c1) == r(c2) && self.with(b1) == other.with(b2),
+            (IfElse(c1, b1, e1), IfElse(c2, b2, e2)) => {
+                l(c1) == r(c2) && self.with(b1) == other.with(b2) && self.with(e1) == other.with(e2)
+            }
+            (Let(m1, i1, t1), Let(m2, i2, t2)) => {
+                m1 == m2 && self.with(i1) == other.with(i2) && self.with(t1) == other.with(t2)
+            }
+            (LetAssign(m1, i1, t1, v1), LetAssign(m2, i2, t2, v2)) => {
+                m1 == m2
+                    && self.with(i1) == other.with(i2)
+                    && self.with(t1) == other.with(t2)
+                    && l(v1) == r(v2)
+            }
+            (Assign(i1, v1), Assign(i2, v2)) => {
+                self.with(i1.0.as_slice()) == other.with(i2.0.as_slice()) && l(v1) == r(v2)
+            }
+            (Tag(t1), Tag(t2)) => self.with(t1) == other.with(t2),
+            (Ident(i1), Ident(i2)) => self.with(i1) == other.with(i2),
+            (Block(b1), Block(b2)) => self.with(b1) == other.with(b2),
+            (Cast(v1, t1), Cast(v2, t2)) => l(v1) == r(v2) && self.with(t1) == other.with(t2),
+            (Error, Error) => true,
+            _ => false,
+        }
+    }
+}
+
+impl PartialEq for WithUnit<'_, FunctionCall> {
+    fn eq(&self, other: &Self) -> bool {
+        self.with(&self.node.ident) == other.with(&other.node.ident)
+            && self.with(self.node.args.0.as_slice()) == other.with(other.node.args.0.as_slice())
+    }
+}
+
+impl PartialEq for WithUnit<'_, Tag> {
+    fn eq(&self, other: &Self) -> bool {
+        let (l, r) = (self.node, other.node);
+        self.with(&l.ident) == other.with(&r.ident)
+            && self.with(l.properties.0.as_slice()) == other.with(r.properties.0.as_slice())
+            && self.with(l.children.0.as_slice()) == other.with(r.children.0.as_slice())
+    }
+}
+
+/// Texts are equal if their contents are, regardless of where they are in the source
+impl PartialEq for WithUnit<'_, TagChild> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.node, other.node) {
+            (TagChild::Tag(l), TagChild::Tag(r)) => self.with(l) == other.with(r),
+            (TagChild::Text(l, _), TagChild::Text(r, _)) => l == r,
+            _ => false,
+        }
+    }
+}
+
+impl PartialEq for WithUnit<'_, (IdentId, StmtId)> {
+    fn eq(&self, other: &Self) -> bool {
+        self.with(&self.node.0) == other.with(&other.node.0)
+            && self.with(&self.node.1) == other.with(&other.node.1)
+    }
+}
+
+impl Debug for CompilationUnit {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         let mut builder = String::new();
-        for item in &self.0 {
-            builder.push_str(&format!("{:?}", item));
+        for item in &self.defs {
+            builder.push_str(&format!("{:?}", self.with(item)));
         }
-        return write!(f, "{}", builder);
+        write!(f, "{}", builder)
     }
 }
 
-impl Debug for TopLevelDef<'_> {
+impl Debug for WithUnit<'_, TopLevelDef> {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         use self::TopLevelDef::*;
-        match self {
-            Func(fun) => write!(f, "{:?}", fun),
-            Export(i, t) => write!(f, "export {:?} {{\n{:?}}}\n", i, t),
-            TagDef(t) => write!(f, "tag {:?} {{\n{:?}\n{:?}}}", t.ident, t.states, t.fns),
+        match self.node {
+            Func(fun) => write!(f, "{:?}", self.with(fun)),
+            Export(i, t) => write!(f, "export {:?} {{\n{:?}}}\n", self.with(i), self.with(t)),
+            TagDef(t) => write!(
+                f,
+                "tag {:?} {{\n{:?}{:?}\n{:?}}}",
+                self.with(&t.ident),
+                self.with(&t.props),
+                self.with(&t.states),
+                self.with(&t.fns)
+            ),
+        }
+    }
+}
+
+impl Debug for WithUnit<'_, TagDef> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        writeln!(f, "tag {:?} {{", self.with(&self.node.ident))?;
+        for prop in &self.node.props.0 {
+            writeln!(f, "{:?}", self.with(prop))?;
         }
+        for state in &self.node.states.0 {
+            writeln!(f, "{:?}", self.with(state))?;
+        }
+        for func in &self.node.fns.0 {
+            writeln!(f, "{:?}", self.with(func))?;
+        }
+        writeln!(f, "}}")
     }
 }
 
-impl Debug for TagDef<'_> {
+impl Debug for WithUnit<'_, PropList> {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        write!(f, "tag {:?} {{\n", self.ident)?;
-        for state in &self.states.0 {
-            write!(f, "{:?}\n", state)?;
+        for item in &self.node.0 {
+            writeln!(f, "{:?}", self.with(item))?;
         }
-        for func in &self.fns.0 {
-            write!(f, "{:?}\n", func)?;
+        Ok(())
+    }
+}
+
+impl Debug for WithUnit<'_, Prop> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(
+            f,
+            "prop {:?}: {:?}",
+            self.with(&self.node.ident),
+            self.with(&self.node.chip_type)
+        )?;
+        if let Some(s) = &self.node.default {
+            write!(f, " = {:?}", self.with(s))?;
         }
-        write!(f, "}}\n")
+        Ok(())
     }
 }
 
-impl Debug for StateList<'_> {
+impl Debug for WithUnit<'_, StateList> {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        for item in &self.0 {
-            write!(f, "{:?}\n", item)?;
+        for item in &self.node.0 {
+            writeln!(f, "{:?}", self.with(item))?;
         }
         Ok(())
     }
 }
 
-impl Debug for State<'_> {
+impl Debug for WithUnit<'_, State> {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        write!(f, "state {:?}", self.ident)?;
-        if let Some(t) = &self.chip_type {
-            write!(f, ": {:?}", t)?;
+        write!(f, "state {:?}", self.with(&self.node.ident))?;
+        if let Some(t) = &self.node.chip_type {
+            write!(f, ": {:?}", self.with(t))?;
         }
-        if let Some(s) = &self.default {
-            write!(f, " = {:?}", s)?;
+        if let Some(s) = &self.node.default {
+            write!(f, " = {:?}", self.with(s))?;
         }
         Ok(())
     }
 }
 
-impl Debug for FunctionDefList<'_> {
+impl Debug for WithUnit<'_, FunctionDefList> {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        for fun in &self.0 {
-            write!(f, "{:?}\n", fun)?;
+        for fun in &self.node.0 {
+            writeln!(f, "{:?}", self.with(fun))?;
         }
         Ok(())
     }
 }
 
-impl Debug for FunctionDef<'_> {
+impl Debug for WithUnit<'_, FunctionDef> {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        if let Some(ret) = &self.return_type {
+        let fun = self.node;
+        if let Some(ret) = &fun.return_type {
             write!(
                 f,
                 "{:?}({:?}) -> {:?} {:?}",
-                self.ident, self.args, ret, self.block
+                self.with(&fun.ident),
+                self.with(&fun.args),
+                self.with(ret),
+                self.with(&fun.block)
             )
         } else {
-            write!(f, "{:?}({:?}) {:?}", self.ident, self.args, self.block)
+            write!(
+                f,
+                "{:?}({:?}) {:?}",
+                self.with(&fun.ident),
+                self.with(&fun.args),
+                self.with(&fun.block)
+            )
         }
     }
 }
 
-impl Debug for ArgDefList<'_> {
+impl Debug for WithUnit<'_, ArgDefList> {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        if self.0.is_empty() {
-            return Ok(());
-        }
-        let mut builder = String::new();
-        for item in &self.0[0..self.0.len() - 1] {
-            builder.push_str(&format!("{:?}, ", item));
-        }
-        builder.push_str(&format!("{:?}", self.0[self.0.len() - 1]));
-        write!(f, "{}", builder)
+        let args = self
+            .node
+            .0
+            .iter()
+            .map(|arg| format!("{:?}", self.with(arg)))
+            .collect::<Vec<_>>();
+        write!(f, "{}", args.join(", "))
     }
 }
 
-impl Debug for ArgDef<'_> {
+impl Debug for WithUnit<'_, ArgDef> {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        write!(f, "{:?}: {:?}", self.name, self.chip_type)
+        write!(
+            f,
+            "{:?}: {:?}",
+            self.with(&self.node.name),
+            self.with(&self.node.chip_type)
+        )
     }
 }
 
-impl Debug for StatementList<'_> {
+impl Debug for WithUnit<'_, StatementList> {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
-        if self.0.is_empty() {
+        let list = &self.node.0;
+        if list.is_empty() {
             return write!(fmt, "{{ \n }}");
         }
         let mut comma_separated = String::from('\n');
 
-        for item in &self.0[0..self.0.len() - 1] {
-            comma_separated.push_str(&format!("{:?};\n", item));
+        for item in &list[0..list.len() - 1] {
+            comma_separated.push_str(&format!("{:?};\n", self.with(item)));
         }
 
-        comma_separated.push_str(&format!("{:?}", self.0[self.0.len() - 1]));
+        comma_separated.push_str(&format!("{:?}", self.with(&list[list.len() - 1])));
         write!(fmt, "{{ {};\n }}", comma_separated)
     }
 }
 
-impl Debug for Statement<'_> {
+impl Debug for WithUnit<'_, StmtId> {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "{:?}", self.with(&self.unit[*self.node]))
+    }
+}
+
+impl Debug for WithUnit<'_, Statement> {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        let w = |node| self.with(node);
         use self::Statement::*;
-        match self {
-            Number(n) => write!(fmt, "{:?}", n),
-            Op(ref l, op, ref r) => write!(fmt, "({:?} {:?} {:?})", l, op, r),
-            FunctionCall(f) => write!(fmt, "{:?}", f),
-            If(cond, exprs) => write!(fmt, "if {:?} {:?}", cond, exprs),
-            IfElse(cond, if_exprs, else_exprs) => {
-                write!(fmt, "if {:?} {:?} else {:?}", cond, if_exprs, else_exprs)
-            }
-            Let(i) => write!(fmt, "let {:?}", i),
-            LetAssign(i, a) => write!(fmt, "let {:?} = {:?}", i, a),
-            Assign(l, r) => write!(fmt, "{:?} = {:?}", l, r),
-            Tag(t) => write!(fmt, "{:?}", t),
-            Ident(i) => write!(fmt, "{:?}", i),
+        match self.node {
+            Number(n, _) => write!(fmt, "{:?}", n),
+            Op(l, op, r) => write!(fmt, "({:?} {:?} {:?})", w(l), op, w(r)),
+            FunctionCall(f) => write!(fmt, "{:?}", self.with(f)),
+            If(cond, exprs) => write!(fmt, "if {:?} {:?}", w(cond), self.with(exprs)),
+            IfElse(cond, if_exprs, else_exprs) => write!(
+                fmt,
+                "if {:?} {:?} else {:?}",
+                w(cond),
+                self.with(if_exprs),
+                self.with(else_exprs)
+            ),
+            Let(m, i, None) => write!(fmt, "let {:?}{:?}", m, self.with(i)),
+            Let(m, i, Some(t)) => write!(fmt, "let {:?}{:?}: {:?}", m, self.with(i), self.with(t)),
+            LetAssign(m, i, None, a) => write!(fmt, "let {:?}{:?} = {:?}", m, self.with(i), w(a)),
+            LetAssign(m, i, Some(t), a) => write!(
+                fmt,
+                "let {:?}{:?}: {:?} = {:?}",
+                m,
+                self.with(i),
+                self.with(t),
+                w(a)
+            ),
+            Assign(l, r) => write!(fmt, "{:?} = {:?}", self.with(l), w(r)),
+            Tag(t) => write!(fmt, "{:?}", self.with(t)),
+            Ident(i) => write!(fmt, "{:?}", self.with(i)),
             Error => write!(fmt, "error"),
-            Block(list) => write!(fmt, "{:?}", list),
+            Block(list) => write!(fmt, "{:?}", self.with(list)),
+            Cast(value, chip_type) => {
+                write!(fmt, "({:?} as {:?})", w(value), self.with(chip_type))
+            }
+        }
+    }
+}
+
+impl Debug for Mutability {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        match self {
+            Mutability::Immutable => Ok(()),
+            Mutability::Mutable => write!(fmt, "mut "),
         }
     }
 }
@@ -257,106 +965,130 @@ impl Debug for Number {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
         use self::Number::*;
         match *self {
-            Int(i) => write!(fmt, "{}", i),
-            Float(i) => write!(fmt, "{}", i),
+            Int(i, Some(NumType::U64)) => write!(fmt, "{}u64", i as u64),
+            Int(i, suffix) => write!(fmt, "{}{}", i, suffix.map_or("", NumType::suffix)),
+            Float(i, suffix) => write!(fmt, "{}{}", i, suffix.map_or("", NumType::suffix)),
         }
     }
 }
 
-impl Debug for FunctionCall<'_> {
+impl Debug for WithUnit<'_, FunctionCall> {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
-        write!(fmt, "{:?}{:?}", self.ident, self.args)
+        write!(
+            fmt,
+            "{:?}{:?}",
+            self.with(&self.node.ident),
+            self.with(&self.node.args)
+        )
     }
 }
 
-impl Debug for ArgList<'_> {
+impl Debug for WithUnit<'_, ArgList> {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
-        if self.0.is_empty() {
-            return write!(fmt, "()");
-        }
-        let mut comma_separated = String::new();
-
-        for item in &self.0[0..self.0.len() - 1] {
-            comma_separated.push_str(&format!("{:?}, ", item));
-        }
+        let args = self
+            .node
+            .0
+            .iter()
+            .map(|arg| format!("{:?}", self.with(arg)))
+            .collect::<Vec<_>>();
+        write!(fmt, "({})", args.join(", "))
+    }
+}
 
-        comma_separated.push_str(&format!("{:?}", self.0[self.0.len() - 1]));
-        write!(fmt, "({})", comma_separated)
+impl Debug for WithUnit<'_, IdentList> {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        let dot_separated = self
+            .node
+            .0
+            .iter()
+            .map(|item| format!("{:?}", self.with(item)))
+            .collect::<Vec<_>>()
+            .join(".");
+        write!(fmt, "{}", dot_separated)
     }
 }
 
-impl Debug for IdentList<'_> {
+impl Debug for WithUnit<'_, TagList> {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
-        if self.0.is_empty() {
-            // This should never happen
-            return Err(Error);
-        }
-        let mut dot_separated = String::new();
+        let mut builder = String::from(" ");
 
-        for item in &self.0[0..self.0.len() - 1] {
-            dot_separated.push_str(&format!("{:?}.", item));
+        for item in &self.node.0 {
+            builder.push_str(&format!("{:?} ", self.with(item)));
         }
 
-        dot_separated.push_str(&format!("{:?}", self.0[self.0.len() - 1]));
-        write!(fmt, "{}", dot_separated)
+        write!(fmt, "{}", builder)
     }
 }
 
-impl Debug for TagList<'_> {
+impl Debug for WithUnit<'_, TagChildList> {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
-        if self.0.is_empty() {
-            return Err(Error);
+        for child in &self.node.0 {
+            write!(fmt, " {:?}", self.with(child))?;
         }
-        let mut builder = String::from(" ");
+        write!(fmt, " ")
+    }
+}
 
-        for item in &self.0[0..self.0.len() - 1] {
-            builder.push_str(&format!("{:?} ", item));
+impl Debug for WithUnit<'_, TagChild> {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        match self.node {
+            TagChild::Tag(tag) => write!(fmt, "{:?}", self.with(tag)),
+            TagChild::Text(text, _) => write!(fmt, "{}", text),
         }
-
-        let last = &self.0[self.0.len() - 1];
-        builder.push_str(&format!("{:?} ", last));
-        write!(fmt, "{}", builder)
     }
 }
 
-impl Debug for Tag<'_> {
+impl Debug for WithUnit<'_, Tag> {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
-        if self.children.0.is_empty() {
-            write!(fmt, "<{:?}{:?}/>", self.ident, self.properties)
+        let tag = self.node;
+        if tag.children.0.is_empty() {
+            write!(
+                fmt,
+                "<{:?}{:?}/>",
+                self.with(&tag.ident),
+                self.with(&tag.properties)
+            )
         } else {
             write!(
                 fmt,
                 "<{:?}{:?}>{:?}</{:?}>",
-                self.ident, self.properties, self.children, self.ident
+                self.with(&tag.ident),
+                self.with(&tag.properties),
+                self.with(&tag.children),
+                self.with(&tag.ident)
             )
         }
     }
 }
 
-impl Debug for PropertyList<'_> {
+impl Debug for WithUnit<'_, PropertyList> {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
-        if self.0.is_empty() {
-            return Ok(());
-        }
-        let mut builder = String::from(" ");
-
-        for item in &self.0[0..self.0.len() - 1] {
-            builder.push_str(&format!("{:?}={:?} ", item.0, item.1));
+        for (key, value) in &self.node.0 {
+            write!(fmt, " {:?}={:?}", self.with(key), self.with(value))?;
         }
+        Ok(())
+    }
+}
 
-        let last = &self.0[self.0.len() - 1];
-        builder.push_str(&format!("{:?}={:?}", last.0, last.1));
-        write!(fmt, "{}", builder)
+impl Debug for WithUnit<'_, IdentId> {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "{:?}", self.unit[*self.node])
     }
 }
 
-impl Debug for Ident<'_> {
+impl Debug for Ident {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
         write!(fmt, "{}", self.0)
     }
 }
 
 impl Debug for Opcode {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "{}", self)
+    }
+}
+
+impl Display for Opcode {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
         use self::Opcode::*;
         match *self {
@@ -373,3 +1105,279 @@ impl Debug for Opcode {
         }
     }
 }
+
+// `Display` prints source that parses back to an equal AST, wherever the grammar allows the node.
+// It puts everything but top level definitions on one line, see `format_source` for a layout.
+
+impl Display for CompilationUnit {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        for def in &self.defs {
+            writeln!(f, "{}", self.with(def))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for WithUnit<'_, TopLevelDef> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self.node {
+            TopLevelDef::Func(fun) => write!(f, "{}", self.with(fun)),
+            TopLevelDef::Export(ident, tags) => {
+                write!(f, "export {} {{ {} }}", self.with(ident), self.with(tags))
+            }
+            TopLevelDef::TagDef(tag_def) => write!(f, "{}", self.with(tag_def)),
+        }
+    }
+}
+
+impl Display for WithUnit<'_, TagDef> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "tag {} {{", self.with(&self.node.ident))?;
+        for prop in &self.node.props.0 {
+            write!(f, " {}", self.with(prop))?;
+        }
+        for state in &self.node.states.0 {
+            write!(f, " {}", self.with(state))?;
+        }
+        for fun in &self.node.fns.0 {
+            write!(f, " {}", self.with(fun))?;
+        }
+        write!(f, " }}")
+    }
+}
+
+impl Display for WithUnit<'_, Prop> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(
+            f,
+            "prop {}: {}",
+            self.with(&self.node.ident),
+            self.with(&self.node.chip_type)
+        )?;
+        if let Some(default) = &self.node.default {
+            write!(f, " = {}", Operand(self.with(default), 1))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for WithUnit<'_, State> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "state {}", self.with(&self.node.ident))?;
+        if let Some(chip_type) = &self.node.chip_type {
+            write!(f, ": {}", self.with(chip_type))?;
+        }
+        if let Some(default) = &self.node.default {
+            write!(f, " = {}", Operand(self.with(default), 1))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for WithUnit<'_, FunctionDef> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(
+            f,
+            "{}({})",
+            self.with(&self.node.ident),
+            self.with(&self.node.args)
+        )?;
+        if let Some(return_type) = &self.node.return_type {
+            write!(f, " -> {}", self.with(return_type))?;
+        }
+        write!(f, " {}", self.with(&self.node.block))
+    }
+}
+
+impl Display for WithUnit<'_, ArgDefList> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        let args = self
+            .node
+            .0
+            .iter()
+            .map(|arg| self.with(arg).to_string())
+            .collect::<Vec<_>>();
+        write!(f, "{}", args.join(", "))
+    }
+}
+
+impl Display for WithUnit<'_, ArgDef> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(
+            f,
+            "{}: {}",
+            self.with(&self.node.name),
+            self.with(&self.node.chip_type)
+        )
+    }
+}
+
+impl Display for WithUnit<'_, StatementList> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        if self.node.0.is_empty() {
+            return write!(f, "{{}}");
+        }
+        let statements = self
+            .node
+            .0
+            .iter()
+            .map(|statement| self.with(statement).to_string())
+            .collect::<Vec<_>>();
+        write!(f, "{{ {} }}", statements.join("; "))
+    }
+}
+
+/// A statement that's parenthesized if it binds less tightly than its position requires
+struct Operand<'u>(WithUnit<'u, StmtId>, u8);
+
+impl Display for Operand<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        let statement = &self.0.unit[*self.0.node];
+        if statement.precedence() < self.1 {
+            write!(f, "({})", self.0)
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+impl Display for WithUnit<'_, StmtId> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "{}", self.with(&self.unit[*self.node]))
+    }
+}
+
+impl Display for WithUnit<'_, Statement> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        let w = |node| self.with(node);
+        use self::Statement::*;
+        match self.node {
+            Number(n, _) => write!(f, "{}", n),
+            Op(l, op, r) => {
+                let precedence = op.precedence();
+                // Operators are left associative
+                write!(
+                    f,
+                    "{} {} {}",
+                    Operand(w(l), precedence),
+                    op,
+                    Operand(w(r), precedence + 1)
+                )
+            }
+            FunctionCall(call) => write!(f, "{}", self.with(call)),
+            If(cond, block) => write!(f, "if {} {}", w(cond), self.with(block)),
+            IfElse(cond, if_block, else_block) => write!(
+                f,
+                "if {} {} else {}",
+                w(cond),
+                self.with(if_block),
+                self.with(else_block)
+            ),
+            Let(m, ident, chip_type) | LetAssign(m, ident, chip_type, _) => {
+                write!(f, "let {}{}", m, self.with(ident))?;
+                if let Some(chip_type) = chip_type {
+                    write!(f, ": {}", self.with(chip_type))?;
+                }
+                match self.node {
+                    LetAssign(.., value) => write!(f, " = {}", Operand(w(value), 1)),
+                    _ => Ok(()),
+                }
+            }
+            Assign(idents, value) => {
+                write!(f, "{} = {}", self.with(idents), Operand(w(value), 1))
+            }
+            // Tags are only expressions inside of parentheses
+            Tag(tag) => write!(f, "({})", self.with(tag)),
+            Ident(ident) => write!(f, "{}", self.with(ident)),
+            Block(list) => write!(f, "{}", self.with(list)),
+            Cast(value, chip_type) => {
+                write!(f, "{} as {}", Operand(w(value), 3), self.with(chip_type))
+            }
+            Error => write!(f, "error"),
+        }
+    }
+}
+
+impl Display for Mutability {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Floats always have a `.` or an exponent so they aren't read back as ints
+impl Display for Number {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match *self {
+            Number::Float(value, suffix) => {
+                write!(f, "{:?}{}", value, suffix.map_or("", NumType::suffix))
+            }
+            Number::Int(..) => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl Display for WithUnit<'_, FunctionCall> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        let args = self
+            .node
+            .args
+            .0
+            .iter()
+            .map(|arg| self.with(arg).to_string())
+            .collect::<Vec<_>>();
+        write!(f, "{}({})", self.with(&self.node.ident), args.join(", "))
+    }
+}
+
+impl Display for WithUnit<'_, IdentList> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Display for WithUnit<'_, TagList> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        let tags = self
+            .node
+            .0
+            .iter()
+            .map(|tag| self.with(tag).to_string())
+            .collect::<Vec<_>>();
+        write!(f, "{}", tags.join(" "))
+    }
+}
+
+impl Display for WithUnit<'_, Tag> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        let tag = self.node;
+        write!(f, "<{}", self.with(&tag.ident))?;
+        for (key, value) in &tag.properties.0 {
+            write!(f, " {}={}", self.with(key), self.with(value))?;
+        }
+        if tag.children.0.is_empty() {
+            return write!(f, "/>");
+        }
+        let children = tag
+            .children
+            .0
+            .iter()
+            .map(|child| match child {
+                TagChild::Tag(tag) => self.with(tag).to_string(),
+                TagChild::Text(text, _) => text.clone(),
+            })
+            .collect::<Vec<_>>();
+        write!(f, ">{}</{}>", children.join(" "), self.with(&tag.ident))
+    }
+}
+
+impl Display for WithUnit<'_, IdentId> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "{}", self.unit[*self.node])
+    }
+}
+
+impl Display for Ident {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "{}", self.0)
+    }
+}
@@ -0,0 +1,172 @@
+//! Compiling source that is already in memory, see [`compile_source`]
+
+use std::fmt::{Display, Error, Formatter};
+
+use lalrpop_util::ParseError;
+
+use crate::ast::{CompilationUnit, Span, TopLevelDef};
+use crate::lexer::Lexer;
+use crate::text::CodeText;
+use crate::{main_parser, resolve, typeck, validate, wasm};
+
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    /// Stop after checking the source, [`CompileOutput::wasm`] is always `None`
+    pub check_only: bool,
+}
+
+#[derive(Debug)]
+pub struct CompileOutput {
+    /// The compiled module, `None` if there were errors or with [`CompileOptions::check_only`]
+    pub wasm: Option<Vec<u8>>,
+    pub diagnostics: Vec<Diagnostic>,
+    pub metadata: Metadata,
+}
+
+impl CompileOutput {
+    pub fn is_ok(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+}
+
+/// The names the source defines, as far as it could be parsed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub name: String,
+    pub functions: Vec<String>,
+    pub tags: Vec<String>,
+    pub exports: Vec<String>,
+}
+
+/// A problem found in the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+    /// Zero based, like `column`
+    pub line: usize,
+    pub column: usize,
+    /// The message with its location and source line, see [`CodeText::describe`]
+    rendered: String,
+}
+
+impl Diagnostic {
+    fn new(source: &CodeText, message: impl Display, span: Span) -> Diagnostic {
+        let location = source.line_col(span.start);
+        Diagnostic {
+            message: message.to_string(),
+            span,
+            line: location.0,
+            column: location.1,
+            rendered: source.describe(span.start, &message),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.write_str(&self.rendered)
+    }
+}
+
+/// Compiles a single file without touching the filesystem, `name` is only used in diagnostics.
+/// Every problem is returned as a [`Diagnostic`] rather than an error.
+pub fn compile_source(name: &str, source: &str, options: &CompileOptions) -> CompileOutput {
+    let source = CodeText::new(name.into(), source.into());
+    let (unit, diagnostics) = parse(&source);
+    let mut output = CompileOutput {
+        wasm: None,
+        diagnostics,
+        metadata: metadata(name, &unit),
+    };
+    if !output.is_ok() {
+        return output;
+    }
+
+    let (resolution, types, errors) = analyze(&unit);
+    output.diagnostics.extend(
+        errors
+            .into_iter()
+            .map(|(message, span)| Diagnostic::new(&source, message, span)),
+    );
+    if output.is_ok() && !options.check_only {
+        match wasm::compile_unit(&unit, &resolution, &types) {
+            Ok(mut module) => output.wasm = Some(module.emit_wasm()),
+            Err(err) => output
+                .diagnostics
+                .push(Diagnostic::new(&source, &err, err.span())),
+        }
+    }
+    output
+}
+
+/// Syntax errors the parser recovered from are reported along with the unit they were found in
+pub(crate) fn parse(source: &CodeText) -> (CompilationUnit, Vec<Diagnostic>) {
+    let mut unit = CompilationUnit::default();
+    let mut recovered = vec![];
+    let result = main_parser::CompilationUnitParser::new().parse(
+        &mut recovered,
+        &mut unit,
+        Lexer::new(source.text()),
+    );
+    let diagnostics = recovered
+        .into_iter()
+        .map(|recovery| recovery.error)
+        .chain(result.err())
+        .map(|err| {
+            let span = match &err {
+                ParseError::InvalidToken { location }
+                | ParseError::UnrecognizedEOF { location, .. } => Span::new(*location, *location),
+                ParseError::UnrecognizedToken {
+                    token: (start, _, end),
+                    ..
+                }
+                | ParseError::ExtraToken {
+                    token: (start, _, end),
+                } => Span::new(*start, *end),
+                ParseError::User { error } => error.span(),
+            };
+            Diagnostic::new(source, &err, span)
+        })
+        .collect();
+    (unit, diagnostics)
+}
+
+/// Runs every check on a parsed unit, each problem found comes with where to report it
+pub(crate) fn analyze(
+    unit: &CompilationUnit,
+) -> (
+    resolve::Resolution,
+    typeck::TypeckResults,
+    Vec<(String, Span)>,
+) {
+    let mut errors = validate::validate(unit)
+        .iter()
+        .map(|err| (err.to_string(), err.span()))
+        .collect::<Vec<_>>();
+    let (resolution, resolve_errors) = resolve::resolve(unit);
+    errors.extend(
+        resolve_errors
+            .iter()
+            .map(|err| (err.to_string(), err.span())),
+    );
+    let (types, type_errors) = typeck::typeck(unit, &resolution);
+    errors.extend(type_errors.iter().map(|err| (err.to_string(), err.span())));
+    (resolution, types, errors)
+}
+
+fn metadata(name: &str, unit: &CompilationUnit) -> Metadata {
+    let mut metadata = Metadata {
+        name: name.into(),
+        ..Metadata::default()
+    };
+    for def in &unit.defs {
+        let (names, ident) = match def {
+            TopLevelDef::Func(fun) => (&mut metadata.functions, fun.ident),
+            TopLevelDef::TagDef(tag_def) => (&mut metadata.tags, tag_def.ident),
+            TopLevelDef::Export(ident, _) => (&mut metadata.exports, *ident),
+        };
+        names.push(unit[ident].0.clone());
+    }
+    metadata
+}
@@ -0,0 +1,30 @@
+use thiserror::Error;
+
+use crate::ast::Span;
+use crate::lexer::LexError;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum SyntaxError {
+    #[error("Expected {0}, found {1}")]
+    Expected(&'static str, String, Span),
+    #[error("Tag was closed incorrectly: {0} != {1}")]
+    TagClosedIncorrectly(String, String, Span),
+    /// A literal that was lexed but couldn't be parsed, see [`crate::ast::Number::parse`]
+    #[error("{0}")]
+    InvalidLiteral(String, Span),
+    #[error(transparent)]
+    Lex(#[from] LexError),
+}
+
+impl SyntaxError {
+    /// Where in the source this error should be reported
+    pub fn span(&self) -> Span {
+        use self::SyntaxError::*;
+        match self {
+            Expected(_, _, span) | TagClosedIncorrectly(_, _, span) | InvalidLiteral(_, span) => {
+                *span
+            }
+            Lex(err) => err.span(),
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use crate::ast::{
+    ArgDef, ArgDefList, ArgList, CompilationUnit, FunctionCall, FunctionDef, FunctionDefList,
+    IdentId, IdentList, Mutability, Number, Opcode, Prop, PropList, PropertyList, State, StateList,
+    Statement, StatementList, StmtId, Tag, TagChild, TagChildList, TagDef, TagList, TopLevelDef,
+};
+
+use super::SyntaxKind;
+use super::{SyntaxElement, SyntaxError, SyntaxNode, SyntaxToken};
+
+pub fn lower(source: &str, root: &SyntaxNode) -> (CompilationUnit, Vec<SyntaxError>) {
+    let mut lowering = Lowering {
+        source,
+        unit: CompilationUnit::default(),
+        errors: vec![],
+    };
+    let defs = root
+        .children()
+        .filter_map(|node| lowering.top_level_def(&node))
+        .collect();
+    lowering.unit.defs = defs;
+    (lowering.unit, lowering.errors)
+}
+
+/// Nodes that are missing a required part were already reported by the parser
+/// and are left out, or become [`Statement::Error`]
+struct Lowering<'a> {
+    source: &'a str,
+    unit: CompilationUnit,
+    errors: Vec<SyntaxError>,
+}
+
+impl<'a> Lowering<'a> {
+    fn ident(&mut self, token: &SyntaxToken) -> IdentId {
+        let span = token.span();
+        self.unit
+            .alloc_ident(&self.source[span.start..span.end], span)
+    }
+
+    fn alloc(&mut self, statement: Statement) -> StmtId {
+        self.unit.alloc(statement)
+    }
+
+    /// The first identifier directly inside of `node`
+    fn name(&mut self, node: &SyntaxNode) -> Option<IdentId> {
+        node.token(SyntaxKind::Ident)
+            .map(|token| self.ident(&token))
+    }
+
+    /// The type of a child `: Type` or `-> Type` node of `kind`
+    fn type_name(&mut self, node: &SyntaxNode, kind: SyntaxKind) -> Option<IdentId> {
+        node.child(kind).and_then(|node| self.name(&node))
+    }
+
+    /// The first child node that isn't a type, which is the value of lets, props and states
+    fn value(&mut self, node: &SyntaxNode) -> Option<StmtId> {
+        node.children()
+            .find(|child| child.kind() != SyntaxKind::TypeAnnotation)
+            .map(|child| self.expression(&child))
+    }
+
+    fn top_level_def(&mut self, node: &SyntaxNode) -> Option<TopLevelDef> {
+        match node.kind() {
+            SyntaxKind::FunctionDef => self.function_def(node).map(TopLevelDef::Func),
+            SyntaxKind::TagDef => {
+                let ident = self.name(node)?;
+                let mut props = vec![];
+                let mut states = vec![];
+                let mut fns = vec![];
+                for child in node.children() {
+                    match child.kind() {
+                        SyntaxKind::Prop => {
+                            if let (Some(ident), Some(chip_type)) = (
+                                self.name(&child),
+                                self.type_name(&child, SyntaxKind::TypeAnnotation),
+                            ) {
+                                let default = self.value(&child);
+                                props.push(Prop {
+                                    ident,
+                                    chip_type,
+                                    default,
+                                });
+                            }
+                        }
+                        SyntaxKind::State => {
+                            if let Some(ident) = self.name(&child) {
+                                let chip_type = self.type_name(&child, SyntaxKind::TypeAnnotation);
+                                let default = self.value(&child);
+                                states.push(State {
+                                    ident,
+                                    chip_type,
+                                    default,
+                                });
+                            }
+                        }
+                        SyntaxKind::FunctionDef => fns.extend(self.function_def(&child)),
+                        _ => {}
+                    }
+                }
+                Some(TopLevelDef::TagDef(TagDef {
+                    ident,
+                    props: PropList(props),
+                    states: StateList(states),
+                    fns: FunctionDefList(fns),
+                }))
+            }
+            SyntaxKind::Export => {
+                let ident = self.name(node)?;
+                let tags = node
+                    .children()
+                    .filter(|child| child.kind() == SyntaxKind::Tag)
+                    .filter_map(|child| self.tag(&child))
+                    .collect();
+                Some(TopLevelDef::Export(ident, TagList(tags)))
+            }
+            _ => None,
+        }
+    }
+
+    fn function_def(&mut self, node: &SyntaxNode) -> Option<FunctionDef> {
+        let ident = self.name(node)?;
+        let args = node
+            .child(SyntaxKind::ArgDefList)?
+            .children()
+            .filter_map(|arg| {
+                Some(ArgDef {
+                    name: self.name(&arg)?,
+                    chip_type: self.type_name(&arg, SyntaxKind::TypeAnnotation)?,
+                })
+            })
+            .collect();
+        let return_type = self.type_name(node, SyntaxKind::ReturnType);
+        let block = self.statement_list(&node.child(SyntaxKind::Block)?);
+        Some(FunctionDef {
+            ident,
+            args: ArgDefList(args),
+            return_type,
+            block,
+        })
+    }
+
+    fn statement_list(&mut self, block: &SyntaxNode) -> StatementList {
+        StatementList(
+            block
+                .children()
+                .map(|statement| self.statement(&statement))
+                .collect(),
+        )
+    }
+
+    fn statement(&mut self, node: &SyntaxNode) -> StmtId {
+        let statement = match node.kind() {
+            SyntaxKind::LetStmt | SyntaxKind::AssignStmt => self.binding(node),
+            _ => return self.expression(node),
+        };
+        self.alloc(statement)
+    }
+
+    /// A `let` or an assignment
+    fn binding(&mut self, node: &SyntaxNode) -> Statement {
+        match node.kind() {
+            SyntaxKind::LetStmt => {
+                let ident = match self.name(node) {
+                    Some(ident) => ident,
+                    None => return Statement::Error,
+                };
+                let mutability = if node.token(SyntaxKind::MutKw).is_some() {
+                    Mutability::Mutable
+                } else {
+                    Mutability::Immutable
+                };
+                let annotation = self.type_name(node, SyntaxKind::TypeAnnotation);
+                match self.value(node) {
+                    Some(value) => Statement::LetAssign(mutability, ident, annotation, value),
+                    None => Statement::Let(mutability, ident, annotation),
+                }
+            }
+            _ => {
+                let path = match node.child(SyntaxKind::Path) {
+                    Some(path) => path,
+                    None => return Statement::Error,
+                };
+                let idents = path
+                    .tokens()
+                    .filter(|token| token.kind() == SyntaxKind::Ident)
+                    .map(|token| self.ident(&token))
+                    .collect::<Vec<_>>();
+                if idents.is_empty() {
+                    return Statement::Error;
+                }
+
+                let value = match node
+                    .children()
+                    .find(|child| child.kind() != SyntaxKind::Path)
+                {
+                    Some(value) => self.expression(&value),
+                    None => self.alloc(Statement::Error),
+                };
+                Statement::Assign(IdentList(idents), value)
+            }
+        }
+    }
+
+    fn expression(&mut self, node: &SyntaxNode) -> StmtId {
+        match node.kind() {
+            SyntaxKind::ParenExpr => match node.children().next() {
+                Some(inner) => self.expression(&inner),
+                None => self.alloc(Statement::Error),
+            },
+            SyntaxKind::LetStmt | SyntaxKind::AssignStmt => self.statement(node),
+            _ => {
+                let statement = self.expression_inner(node);
+                self.alloc(statement)
+            }
+        }
+    }
+
+    fn expression_inner(&mut self, node: &SyntaxNode) -> Statement {
+        match node.kind() {
+            SyntaxKind::Literal => {
+                let token = match node.token(SyntaxKind::Num) {
+                    Some(token) => token,
+                    None => return Statement::Error,
+                };
+                let span = token.span();
+                match Number::parse(&self.source[span.start..span.end], span) {
+                    Ok(number) => Statement::Number(number, span),
+                    Err(err) => {
+                        self.errors
+                            .push(SyntaxError::InvalidLiteral(err.to_string(), span));
+                        Statement::Error
+                    }
+                }
+            }
+            SyntaxKind::NameRef => self.name(node).map_or(Statement::Error, Statement::Ident),
+            SyntaxKind::BinaryExpr => {
+                let mut operands = node.children();
+                let (l, r) = match (operands.next(), operands.next()) {
+                    (Some(l), Some(r)) => (l, r),
+                    _ => return Statement::Error,
+                };
+                let op = match node.tokens().next().map(|token| token.kind()) {
+                    Some(SyntaxKind::Star) => Opcode::Mul,
+                    Some(SyntaxKind::Slash) => Opcode::Div,
+                    Some(SyntaxKind::Plus) => Opcode::Add,
+                    Some(SyntaxKind::Minus) => Opcode::Sub,
+                    Some(SyntaxKind::Gt) => Opcode::Gt,
+                    Some(SyntaxKind::Ge) => Opcode::Ge,
+                    Some(SyntaxKind::Lt) => Opcode::Lt,
+                    Some(SyntaxKind::Le) => Opcode::Le,
+                    Some(SyntaxKind::Eq) => Opcode::Eq,
+                    Some(SyntaxKind::Ne) => Opcode::Ne,
+                    _ => return Statement::Error,
+                };
+                Statement::Op(self.expression(&l), op, self.expression(&r))
+            }
+            SyntaxKind::CastExpr => match (node.children().next(), self.name(node)) {
+                (Some(value), Some(chip_type)) => {
+                    Statement::Cast(self.expression(&value), chip_type)
+                }
+                _ => Statement::Error,
+            },
+            SyntaxKind::CallExpr => {
+                let ident = match self.name(node) {
+                    Some(ident) => ident,
+                    None => return Statement::Error,
+                };
+                let args = node
+                    .child(SyntaxKind::ArgList)
+                    .map(|args| args.children().map(|arg| self.statement(&arg)).collect())
+                    .unwrap_or_default();
+                Statement::FunctionCall(FunctionCall {
+                    ident,
+                    args: ArgList(args),
+                })
+            }
+            SyntaxKind::IfExpr => {
+                // The condition can be a block too, so the parts are told apart by their position
+                let parts = node.children().collect::<Vec<_>>();
+                match (parts.as_slice(), node.token(SyntaxKind::ElseKw)) {
+                    ([cond, if_block, else_block], Some(_)) => Statement::IfElse(
+                        self.expression(cond),
+                        self.statement_list(if_block),
+                        self.statement_list(else_block),
+                    ),
+                    ([cond, block], None) => {
+                        Statement::If(self.expression(cond), self.statement_list(block))
+                    }
+                    _ => Statement::Error,
+                }
+            }
+            SyntaxKind::Block => Statement::Block(self.statement_list(node)),
+            SyntaxKind::Tag => self.tag(node).map_or(Statement::Error, Statement::Tag),
+            _ => Statement::Error,
+        }
+    }
+
+    fn tag(&mut self, node: &SyntaxNode) -> Option<Tag> {
+        let ident = self.name(node)?;
+        let mut properties = vec![];
+        let mut children = vec![];
+        for child in node.children_with_tokens() {
+            match child {
+                SyntaxElement::Node(child) if child.kind() == SyntaxKind::Property => {
+                    let key = match self.name(&child) {
+                        Some(key) => key,
+                        None => continue,
+                    };
+                    let value = match child.children().next() {
+                        Some(value) => self.expression(&value),
+                        None => self.alloc(Statement::Error),
+                    };
+                    properties.push((key, value));
+                }
+                SyntaxElement::Node(child) if child.kind() == SyntaxKind::Tag => {
+                    children.extend(self.tag(&child).map(TagChild::Tag));
+                }
+                SyntaxElement::Token(token) if token.kind() == SyntaxKind::Text => {
+                    let span = token.span();
+                    children.push(TagChild::Text(
+                        self.source[span.start..span.end].into(),
+                        span,
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Some(Tag {
+            ident,
+            properties: PropertyList(properties),
+            children: TagChildList(children),
+        })
+    }
+}
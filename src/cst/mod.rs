@@ -0,0 +1,373 @@
+//! A lossless syntax tree: unlike the AST it keeps every byte of the source,
+//! including whitespace, comments, parentheses and trailing commas.
+//!
+//! The green tree is immutable and only knows the kinds and text of its children,
+//! the red tree ([`SyntaxNode`]) is created on demand on top of it and adds offsets and parents.
+
+use std::fmt::{Debug, Display, Error, Formatter};
+use std::rc::Rc;
+
+use crate::ast::{CompilationUnit, Span};
+use crate::lexer::Token;
+
+pub use self::error::SyntaxError;
+
+mod error;
+mod lower;
+mod parser;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SyntaxKind {
+    // Trivia
+    Whitespace,
+    Comment,
+
+    // Tokens, see [`Token`]
+    Ident,
+    Num,
+    Text,
+    TagKw,
+    ExportKw,
+    PropKw,
+    StateKw,
+    LetKw,
+    MutKw,
+    IfKw,
+    ElseKw,
+    AsKw,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Colon,
+    Semicolon,
+    Comma,
+    Dot,
+    Arrow,
+    Assign,
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    LtSlash,
+    SlashGt,
+    /// Anything the lexer couldn't make sense of
+    ErrorToken,
+
+    // Nodes
+    CompilationUnit,
+    FunctionDef,
+    ArgDefList,
+    ArgDef,
+    /// `-> Type`
+    ReturnType,
+    /// `: Type`
+    TypeAnnotation,
+    TagDef,
+    Prop,
+    State,
+    Export,
+    /// Statements between braces
+    Block,
+    LetStmt,
+    AssignStmt,
+    /// `a.b.c` on the left of an assignment
+    Path,
+    /// `if` with an optional `else`
+    IfExpr,
+    BinaryExpr,
+    CastExpr,
+    ParenExpr,
+    CallExpr,
+    ArgList,
+    NameRef,
+    Literal,
+    Tag,
+    /// `name=value` inside of a tag
+    Property,
+    /// Tokens the parser skipped over
+    Error,
+}
+
+impl SyntaxKind {
+    pub fn is_trivia(self) -> bool {
+        matches!(self, SyntaxKind::Whitespace | SyntaxKind::Comment)
+    }
+
+    fn from_token(token: &Token) -> SyntaxKind {
+        match token {
+            Token::Ident(_) => SyntaxKind::Ident,
+            Token::Num(_) => SyntaxKind::Num,
+            Token::Text(_) => SyntaxKind::Text,
+            Token::Tag => SyntaxKind::TagKw,
+            Token::Export => SyntaxKind::ExportKw,
+            Token::Prop => SyntaxKind::PropKw,
+            Token::State => SyntaxKind::StateKw,
+            Token::Let => SyntaxKind::LetKw,
+            Token::Mut => SyntaxKind::MutKw,
+            Token::If => SyntaxKind::IfKw,
+            Token::Else => SyntaxKind::ElseKw,
+            Token::As => SyntaxKind::AsKw,
+            Token::LParen => SyntaxKind::LParen,
+            Token::RParen => SyntaxKind::RParen,
+            Token::LBrace => SyntaxKind::LBrace,
+            Token::RBrace => SyntaxKind::RBrace,
+            Token::Colon => SyntaxKind::Colon,
+            Token::Semicolon => SyntaxKind::Semicolon,
+            Token::Comma => SyntaxKind::Comma,
+            Token::Dot => SyntaxKind::Dot,
+            Token::Arrow => SyntaxKind::Arrow,
+            Token::Assign => SyntaxKind::Assign,
+            Token::Eq => SyntaxKind::Eq,
+            Token::Ne => SyntaxKind::Ne,
+            Token::Gt => SyntaxKind::Gt,
+            Token::Ge => SyntaxKind::Ge,
+            Token::Lt => SyntaxKind::Lt,
+            Token::Le => SyntaxKind::Le,
+            Token::Plus => SyntaxKind::Plus,
+            Token::Minus => SyntaxKind::Minus,
+            Token::Star => SyntaxKind::Star,
+            Token::Slash => SyntaxKind::Slash,
+            Token::LtSlash => SyntaxKind::LtSlash,
+            Token::SlashGt => SyntaxKind::SlashGt,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GreenToken {
+    kind: SyntaxKind,
+    text: Box<str>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GreenNode {
+    kind: SyntaxKind,
+    /// Length of the text of all children
+    len: usize,
+    children: Vec<GreenElement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GreenElement {
+    Node(Rc<GreenNode>),
+    Token(Rc<GreenToken>),
+}
+
+impl GreenNode {
+    fn new(kind: SyntaxKind, children: Vec<GreenElement>) -> GreenNode {
+        let len = children.iter().map(GreenElement::len).sum();
+        GreenNode {
+            kind,
+            len,
+            children,
+        }
+    }
+}
+
+impl GreenElement {
+    fn len(&self) -> usize {
+        match self {
+            GreenElement::Node(node) => node.len,
+            GreenElement::Token(token) => token.text.len(),
+        }
+    }
+}
+
+impl Display for GreenNode {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        for child in &self.children {
+            match child {
+                GreenElement::Node(node) => write!(f, "{}", node)?,
+                GreenElement::Token(token) => write!(f, "{}", token.text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A node of the red tree, cheap to clone
+#[derive(Clone)]
+pub struct SyntaxNode(Rc<NodeData>);
+
+struct NodeData {
+    green: Rc<GreenNode>,
+    offset: usize,
+    parent: Option<SyntaxNode>,
+}
+
+#[derive(Clone)]
+pub struct SyntaxToken {
+    green: Rc<GreenToken>,
+    offset: usize,
+    parent: SyntaxNode,
+}
+
+#[derive(Clone)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+impl SyntaxNode {
+    pub fn new_root(green: Rc<GreenNode>) -> SyntaxNode {
+        SyntaxNode(Rc::new(NodeData {
+            green,
+            offset: 0,
+            parent: None,
+        }))
+    }
+
+    pub fn kind(&self) -> SyntaxKind {
+        self.0.green.kind
+    }
+
+    pub fn span(&self) -> Span {
+        Span::new(self.0.offset, self.0.offset + self.0.green.len)
+    }
+
+    pub fn parent(&self) -> Option<&SyntaxNode> {
+        self.0.parent.as_ref()
+    }
+
+    pub fn green(&self) -> &Rc<GreenNode> {
+        &self.0.green
+    }
+
+    /// All direct children, including trivia
+    pub fn children_with_tokens(&self) -> impl Iterator<Item = SyntaxElement> + '_ {
+        let mut offset = self.0.offset;
+        self.0.green.children.iter().map(move |child| {
+            let start = offset;
+            offset += child.len();
+            match child {
+                GreenElement::Node(green) => SyntaxElement::Node(SyntaxNode(Rc::new(NodeData {
+                    green: green.clone(),
+                    offset: start,
+                    parent: Some(self.clone()),
+                }))),
+                GreenElement::Token(green) => SyntaxElement::Token(SyntaxToken {
+                    green: green.clone(),
+                    offset: start,
+                    parent: self.clone(),
+                }),
+            }
+        })
+    }
+
+    /// The direct children that are nodes
+    pub fn children(&self) -> impl Iterator<Item = SyntaxNode> + '_ {
+        self.children_with_tokens().filter_map(|child| match child {
+            SyntaxElement::Node(node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        })
+    }
+
+    /// The direct children that are tokens, without trivia
+    pub fn tokens(&self) -> impl Iterator<Item = SyntaxToken> + '_ {
+        self.children_with_tokens().filter_map(|child| match child {
+            SyntaxElement::Token(token) if !token.kind().is_trivia() => Some(token),
+            _ => None,
+        })
+    }
+
+    /// The first direct child token of `kind`
+    pub fn token(&self, kind: SyntaxKind) -> Option<SyntaxToken> {
+        self.tokens().find(|token| token.kind() == kind)
+    }
+
+    /// The first direct child node of `kind`
+    pub fn child(&self, kind: SyntaxKind) -> Option<SyntaxNode> {
+        self.children().find(|node| node.kind() == kind)
+    }
+}
+
+impl SyntaxToken {
+    pub fn kind(&self) -> SyntaxKind {
+        self.green.kind
+    }
+
+    pub fn text(&self) -> &str {
+        &self.green.text
+    }
+
+    pub fn span(&self) -> Span {
+        Span::new(self.offset, self.offset + self.green.text.len())
+    }
+
+    pub fn parent(&self) -> &SyntaxNode {
+        &self.parent
+    }
+}
+
+/// The exact source text the node was parsed from
+impl Display for SyntaxNode {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "{}", self.0.green)
+    }
+}
+
+/// One line per node and token, indented by depth
+impl Debug for SyntaxNode {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        let depth = std::iter::successors(self.parent(), |node| node.parent()).count();
+        let span = self.span();
+        writeln!(
+            f,
+            "{:indent$}{:?}@{}..{}",
+            "",
+            self.kind(),
+            span.start,
+            span.end,
+            indent = depth * 2
+        )?;
+        for child in self.children_with_tokens() {
+            match child {
+                SyntaxElement::Node(node) => write!(f, "{:?}", node)?,
+                SyntaxElement::Token(token) => {
+                    let span = token.span();
+                    writeln!(
+                        f,
+                        "{:indent$}{:?}@{}..{} {:?}",
+                        "",
+                        token.kind(),
+                        span.start,
+                        span.end,
+                        token.text(),
+                        indent = depth * 2 + 2
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The result of parsing a file, the tree is complete even if there are errors
+pub struct Parse {
+    pub green: Rc<GreenNode>,
+    pub errors: Vec<SyntaxError>,
+}
+
+impl Parse {
+    pub fn syntax(&self) -> SyntaxNode {
+        SyntaxNode::new_root(self.green.clone())
+    }
+}
+
+/// Parses a whole file into a lossless tree, `parse(source).syntax().to_string() == source`
+pub fn parse(source: &str) -> Parse {
+    parser::parse(source)
+}
+
+/// Lowers a tree of a whole file parsed from `source` to the AST.
+/// Parts of the tree that contain errors become [`crate::ast::Statement::Error`] or are left out.
+pub fn lower(source: &str, root: &SyntaxNode) -> (CompilationUnit, Vec<SyntaxError>) {
+    lower::lower(source, root)
+}
@@ -0,0 +1,529 @@
+use std::rc::Rc;
+
+use crate::ast::Span;
+use crate::lexer::Lexer;
+
+use super::SyntaxKind::{self, *};
+use super::{GreenElement, GreenNode, GreenToken, Parse, SyntaxError};
+
+/// A token with its text, trivia included
+struct RawToken<'s> {
+    kind: SyntaxKind,
+    text: &'s str,
+    span: Span,
+}
+
+pub fn parse(source: &str) -> Parse {
+    let mut errors = vec![];
+    let tokens = tokenize(source, &mut errors);
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        stack: vec![(CompilationUnit, vec![])],
+        errors,
+    };
+
+    parser.compilation_unit();
+    parser.flush_trivia();
+
+    let (kind, children) = parser.stack.pop().expect("The root node is never finished");
+    Parse {
+        green: Rc::new(GreenNode::new(kind, children)),
+        errors: parser.errors,
+    }
+}
+
+/// Lexes `source`, filling the gaps between tokens with whitespace and comments
+fn tokenize<'s>(source: &'s str, errors: &mut Vec<SyntaxError>) -> Vec<RawToken<'s>> {
+    let mut tokens = vec![];
+    let mut end = 0;
+    let push = |tokens: &mut Vec<RawToken<'s>>, kind, span: Span| {
+        tokens.push(RawToken {
+            kind,
+            text: &source[span.start..span.end],
+            span,
+        })
+    };
+
+    for result in Lexer::new(source).tokens() {
+        let (kind, span) = match result {
+            Ok((start, token, end)) => (SyntaxKind::from_token(&token), Span::new(start, end)),
+            Err(err) => {
+                let span = err.span();
+                errors.push(err.into());
+                (ErrorToken, span)
+            }
+        };
+        for trivia in trivia(&source[end..span.start], end) {
+            push(&mut tokens, trivia.0, trivia.1);
+        }
+        push(&mut tokens, kind, span);
+        end = span.end;
+    }
+    for trivia in trivia(&source[end..], end) {
+        push(&mut tokens, trivia.0, trivia.1);
+    }
+
+    tokens
+}
+
+/// Splits the text between two tokens, which starts at `offset`, into trivia
+fn trivia(text: &str, offset: usize) -> Vec<(SyntaxKind, Span)> {
+    let mut pieces = vec![];
+    let mut pos = 0;
+    while pos < text.len() {
+        let rest = &text[pos..];
+        let (kind, len) = if rest.starts_with("//") {
+            (Comment, rest.find('\n').unwrap_or(rest.len()))
+        } else if rest.starts_with("/*") {
+            (Comment, rest.find("*/").map_or(rest.len(), |end| end + 2))
+        } else {
+            let len = rest
+                .find(|c: char| !c.is_ascii_whitespace())
+                .unwrap_or(rest.len());
+            // Anything else would have been a token
+            (Whitespace, len.max(1))
+        };
+        pieces.push((kind, Span::new(offset + pos, offset + pos + len)));
+        pos += len;
+    }
+    pieces
+}
+
+struct Parser<'s> {
+    tokens: Vec<RawToken<'s>>,
+    pos: usize,
+    /// The nodes that are being built, innermost last
+    stack: Vec<(SyntaxKind, Vec<GreenElement>)>,
+    errors: Vec<SyntaxError>,
+}
+
+impl<'s> Parser<'s> {
+    // Building the tree
+
+    fn push_token(&mut self) {
+        let token = &self.tokens[self.pos];
+        let green = GreenToken {
+            kind: token.kind,
+            text: token.text.into(),
+        };
+        self.stack
+            .last_mut()
+            .expect("The root node is never finished")
+            .1
+            .push(GreenElement::Token(Rc::new(green)));
+        self.pos += 1;
+    }
+
+    /// Trivia before a node belongs to the node's parent
+    fn flush_trivia(&mut self) {
+        while self
+            .tokens
+            .get(self.pos)
+            .is_some_and(|t| t.kind.is_trivia())
+        {
+            self.push_token();
+        }
+    }
+
+    fn start_node(&mut self, kind: SyntaxKind) {
+        self.flush_trivia();
+        self.stack.push((kind, vec![]));
+    }
+
+    fn finish_node(&mut self) {
+        let (kind, children) = self.stack.pop().expect("Started nodes are finished once");
+        self.stack
+            .last_mut()
+            .expect("The root node is never finished")
+            .1
+            .push(GreenElement::Node(Rc::new(GreenNode::new(kind, children))));
+    }
+
+    /// Marks where a node might start, before it's known if there is one
+    fn checkpoint(&mut self) -> usize {
+        self.flush_trivia();
+        self.stack.last().expect("There is always a node").1.len()
+    }
+
+    /// Starts a node containing everything since `checkpoint`
+    fn start_node_at(&mut self, checkpoint: usize, kind: SyntaxKind) {
+        let children = self
+            .stack
+            .last_mut()
+            .expect("There is always a node")
+            .1
+            .split_off(checkpoint);
+        self.stack.push((kind, children));
+    }
+
+    // Looking at tokens
+
+    /// The `n`th token from here that isn't trivia
+    fn nth_token(&self, n: usize) -> Option<&RawToken<'s>> {
+        self.tokens[self.pos..]
+            .iter()
+            .filter(|token| !token.kind.is_trivia())
+            .nth(n)
+    }
+
+    fn nth(&self, n: usize) -> Option<SyntaxKind> {
+        self.nth_token(n).map(|token| token.kind)
+    }
+
+    fn at(&self, kind: SyntaxKind) -> bool {
+        self.nth(0) == Some(kind)
+    }
+
+    fn at_end(&self) -> bool {
+        self.nth(0).is_none()
+    }
+
+    fn bump(&mut self) {
+        self.flush_trivia();
+        if self.pos < self.tokens.len() {
+            self.push_token();
+        }
+    }
+
+    fn eat(&mut self, kind: SyntaxKind) -> bool {
+        let at = self.at(kind);
+        if at {
+            self.bump();
+        }
+        at
+    }
+
+    fn error(&mut self, expected: &'static str) {
+        let (found, span) = match self.nth_token(0) {
+            Some(token) => (format!("`{}`", token.text), token.span),
+            None => {
+                let end = self.tokens.last().map_or(0, |token| token.span.end);
+                ("the end of the file".into(), Span::new(end, end))
+            }
+        };
+        self.errors
+            .push(SyntaxError::Expected(expected, found, span));
+    }
+
+    fn expect(&mut self, kind: SyntaxKind, expected: &'static str) -> bool {
+        let found = self.eat(kind);
+        if !found {
+            self.error(expected);
+        }
+        found
+    }
+
+    /// Reports an error and skips the current token, unless it closes an enclosing node
+    fn error_bump(&mut self, expected: &'static str) {
+        self.error(expected);
+        if !self.at_end() && !self.at(RBrace) {
+            self.start_node(Error);
+            self.bump();
+            self.finish_node();
+        }
+    }
+
+    // Grammar, mirrors `main_parser.lalrpop`
+
+    fn compilation_unit(&mut self) {
+        while let Some(kind) = self.nth(0) {
+            match kind {
+                TagKw => self.tag_def(),
+                ExportKw => self.export(),
+                Ident => self.function_def(),
+                _ => {
+                    self.error("a function, tag or export");
+                    self.start_node(Error);
+                    self.bump();
+                    self.finish_node();
+                }
+            }
+        }
+    }
+
+    fn tag_def(&mut self) {
+        self.start_node(TagDef);
+        self.bump();
+        self.expect(Ident, "a tag name");
+        if self.expect(LBrace, "`{`") {
+            while !self.at(RBrace) && !self.at_end() {
+                match self.nth(0) {
+                    Some(PropKw) => self.prop_or_state(Prop),
+                    Some(StateKw) => self.prop_or_state(State),
+                    Some(Ident) => self.function_def(),
+                    _ => self.error_bump("a prop, state or function"),
+                }
+            }
+            self.expect(RBrace, "`}`");
+        }
+        self.finish_node();
+    }
+
+    /// `prop name: Type = default` and `state name: Type = default`
+    fn prop_or_state(&mut self, kind: SyntaxKind) {
+        self.start_node(kind);
+        self.bump();
+        self.expect(Ident, "a name");
+        if self.at(Colon) {
+            self.type_annotation();
+        }
+        if self.eat(Assign) {
+            self.expr();
+        }
+        self.finish_node();
+    }
+
+    fn type_annotation(&mut self) {
+        self.start_node(TypeAnnotation);
+        self.bump();
+        self.expect(Ident, "a type");
+        self.finish_node();
+    }
+
+    fn export(&mut self) {
+        self.start_node(Export);
+        self.bump();
+        self.expect(Ident, "an export name");
+        if self.expect(LBrace, "`{`") {
+            while !self.at(RBrace) && !self.at_end() {
+                if self.at(Lt) {
+                    self.tag();
+                } else {
+                    self.error_bump("a tag");
+                }
+            }
+            self.expect(RBrace, "`}`");
+        }
+        self.finish_node();
+    }
+
+    fn function_def(&mut self) {
+        self.start_node(FunctionDef);
+        self.bump();
+
+        self.start_node(ArgDefList);
+        if self.expect(LParen, "`(`") {
+            while self.at(Ident) {
+                self.start_node(ArgDef);
+                self.bump();
+                if self.at(Colon) {
+                    self.type_annotation();
+                } else {
+                    self.error("`:`");
+                }
+                self.finish_node();
+                if !self.eat(Comma) {
+                    break;
+                }
+            }
+            self.expect(RParen, "`)`");
+        }
+        self.finish_node();
+
+        if self.at(Arrow) {
+            self.start_node(ReturnType);
+            self.bump();
+            self.expect(Ident, "a return type");
+            self.finish_node();
+        }
+        self.block();
+        self.finish_node();
+    }
+
+    fn block(&mut self) {
+        self.start_node(Block);
+        if self.expect(LBrace, "`{`") {
+            while !self.at(RBrace) && !self.at_end() {
+                self.statement();
+                self.eat(Semicolon);
+            }
+            self.expect(RBrace, "`}`");
+        }
+        self.finish_node();
+    }
+
+    fn statement(&mut self) {
+        match (self.nth(0), self.nth(1)) {
+            (Some(LetKw), _) => {
+                self.start_node(LetStmt);
+                self.bump();
+                self.eat(MutKw);
+                self.expect(Ident, "a name");
+                if self.at(Colon) {
+                    self.type_annotation();
+                }
+                if self.eat(Assign) {
+                    self.expr();
+                }
+                self.finish_node();
+            }
+            (Some(Ident), Some(Dot | Assign)) => {
+                self.start_node(AssignStmt);
+                self.start_node(Path);
+                self.bump();
+                while self.eat(Dot) {
+                    self.expect(Ident, "a field name");
+                }
+                self.finish_node();
+                self.expect(Assign, "`=`");
+                self.expr();
+                self.finish_node();
+            }
+            _ => self.expr(),
+        }
+    }
+
+    /// Comparisons bind the weakest, then `+` and `-`, then `*` and `/`, then `as`
+    fn expr(&mut self) {
+        let checkpoint = self.checkpoint();
+        self.additive();
+        while matches!(self.nth(0), Some(Gt | Ge | Lt | Le | Eq | Ne)) {
+            self.start_node_at(checkpoint, BinaryExpr);
+            self.bump();
+            self.additive();
+            self.finish_node();
+        }
+    }
+
+    fn additive(&mut self) {
+        let checkpoint = self.checkpoint();
+        self.multiplicative();
+        while matches!(self.nth(0), Some(Plus | Minus)) {
+            self.start_node_at(checkpoint, BinaryExpr);
+            self.bump();
+            self.multiplicative();
+            self.finish_node();
+        }
+    }
+
+    fn multiplicative(&mut self) {
+        let checkpoint = self.checkpoint();
+        self.cast();
+        while matches!(self.nth(0), Some(Star | Slash)) {
+            self.start_node_at(checkpoint, BinaryExpr);
+            self.bump();
+            self.cast();
+            self.finish_node();
+        }
+    }
+
+    fn cast(&mut self) {
+        let checkpoint = self.checkpoint();
+        self.primary();
+        while self.at(AsKw) {
+            self.start_node_at(checkpoint, CastExpr);
+            self.bump();
+            self.expect(Ident, "a type");
+            self.finish_node();
+        }
+    }
+
+    fn primary(&mut self) {
+        match (self.nth(0), self.nth(1)) {
+            (Some(Num), _) => {
+                self.start_node(Literal);
+                self.bump();
+                self.finish_node();
+            }
+            (Some(Ident), Some(LParen)) => {
+                self.start_node(CallExpr);
+                self.bump();
+                self.start_node(ArgList);
+                self.bump();
+                while !self.at(RParen) && !self.at(RBrace) && !self.at_end() {
+                    self.statement();
+                    if !self.eat(Comma) {
+                        break;
+                    }
+                }
+                self.expect(RParen, "`)`");
+                self.finish_node();
+                self.finish_node();
+            }
+            (Some(Ident), _) => {
+                self.start_node(NameRef);
+                self.bump();
+                self.finish_node();
+            }
+            (Some(LParen), _) => {
+                self.start_node(ParenExpr);
+                self.bump();
+                if self.at(Lt) {
+                    self.tag();
+                } else {
+                    self.expr();
+                }
+                self.expect(RParen, "`)`");
+                self.finish_node();
+            }
+            (Some(IfKw), _) => {
+                self.start_node(IfExpr);
+                self.bump();
+                self.expr();
+                self.block();
+                if self.eat(ElseKw) {
+                    self.block();
+                }
+                self.finish_node();
+            }
+            (Some(LBrace), _) => self.block(),
+            (Some(Lt), _) => self.tag(),
+            _ => self.error_bump("an expression"),
+        }
+    }
+
+    fn tag(&mut self) {
+        self.start_node(Tag);
+        self.bump();
+        let name = self.nth_token(0).map(|token| token.text);
+        self.expect(Ident, "a tag name");
+
+        while self.at(Ident) {
+            self.start_node(Property);
+            self.bump();
+            self.expect(Assign, "`=`");
+            match self.nth(0) {
+                Some(Num) => self.primary(),
+                Some(Ident) => {
+                    self.start_node(NameRef);
+                    self.bump();
+                    self.finish_node();
+                }
+                _ => self.error("a property value"),
+            }
+            self.finish_node();
+        }
+
+        if self.eat(Gt) {
+            loop {
+                match self.nth(0) {
+                    Some(Lt) => self.tag(),
+                    Some(Text) => self.bump(),
+                    Some(LtSlash) | None => break,
+                    _ => self.error_bump("a tag or text"),
+                }
+            }
+            if self.expect(LtSlash, "`</`") {
+                if let (Some(name), Some(Ident)) = (name, self.nth(0)) {
+                    let closing = &self.tokens[self.pos..]
+                        .iter()
+                        .find(|token| !token.kind.is_trivia())
+                        .expect("There is an identifier");
+                    if closing.text != name {
+                        self.errors.push(SyntaxError::TagClosedIncorrectly(
+                            name.into(),
+                            closing.text.into(),
+                            closing.span,
+                        ));
+                    }
+                }
+                self.expect(Ident, "a tag name");
+                self.expect(Gt, "`>`");
+            }
+        } else {
+            self.expect(SlashGt, "`/>` or `>`");
+        }
+        self.finish_node();
+    }
+}
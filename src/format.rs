@@ -0,0 +1,459 @@
+//! The canonical layout of chip source, see [`format_source`]
+
+use crate::cst::{self, SyntaxElement, SyntaxError, SyntaxKind, SyntaxNode, SyntaxToken};
+
+const INDENT: &str = "    ";
+/// Tags that would reach past this column are broken over multiple lines
+const MAX_WIDTH: usize = 100;
+
+/// Formats a whole file. Comments are kept and everything else is laid out the same way
+/// no matter how it was written, so formatting the result again doesn't change it.
+/// Files with syntax errors are left alone and their errors are returned instead.
+pub fn format_source(source: &str) -> Result<String, Vec<SyntaxError>> {
+    let parse = cst::parse(source);
+    if !parse.errors.is_empty() {
+        return Err(parse.errors);
+    }
+    let mut printer = Printer::default();
+    printer.compilation_unit(&parse.syntax());
+    Ok(printer.out)
+}
+
+/// Something that's printed on its own line
+enum Entry {
+    Item(SyntaxElement),
+    Comment(SyntaxToken),
+}
+
+impl Entry {
+    fn is_node(&self, kind: SyntaxKind) -> bool {
+        matches!(self, Entry::Item(SyntaxElement::Node(node)) if node.kind() == kind)
+    }
+}
+
+/// The elements accepted by `is_item` and the comments between them,
+/// each with the number of line breaks in front of it
+fn entries(
+    elements: impl IntoIterator<Item = SyntaxElement>,
+    is_item: impl Fn(&SyntaxElement) -> bool,
+) -> Vec<(Entry, usize)> {
+    let mut entries = vec![];
+    let mut newlines = 0;
+    for element in elements {
+        match &element {
+            SyntaxElement::Token(token) if token.kind() == SyntaxKind::Whitespace => {
+                newlines += token.text().matches('\n').count();
+            }
+            SyntaxElement::Token(token) if token.kind() == SyntaxKind::Comment => {
+                entries.push((Entry::Comment(token.clone()), newlines));
+                newlines = 0;
+            }
+            _ => {
+                if is_item(&element) {
+                    entries.push((Entry::Item(element), newlines));
+                }
+                newlines = 0;
+            }
+        }
+    }
+    entries
+}
+
+fn is_node(element: &SyntaxElement) -> bool {
+    matches!(element, SyntaxElement::Node(_))
+}
+
+fn is_token(element: &SyntaxElement, kind: SyntaxKind) -> bool {
+    matches!(element, SyntaxElement::Token(token) if token.kind() == kind)
+}
+
+/// Splits a tag into everything before `>` or `/>`, its children and the rest
+fn tag_parts(node: &SyntaxNode) -> (Vec<SyntaxElement>, Vec<SyntaxElement>, Vec<SyntaxElement>) {
+    let mut elements = node.children_with_tokens().collect::<Vec<_>>();
+    let head_end = elements
+        .iter()
+        .position(|element| {
+            is_token(element, SyntaxKind::Gt) || is_token(element, SyntaxKind::SlashGt)
+        })
+        .unwrap_or(elements.len());
+    let mut rest = elements.split_off(head_end);
+    if rest
+        .first()
+        .is_some_and(|element| is_token(element, SyntaxKind::Gt))
+    {
+        elements.push(rest.remove(0));
+        let body_end = rest
+            .iter()
+            .position(|element| is_token(element, SyntaxKind::LtSlash))
+            .unwrap_or(rest.len());
+        let tail = rest.split_off(body_end);
+        (elements, rest, tail)
+    } else {
+        (elements, vec![], rest)
+    }
+}
+
+#[derive(Default)]
+struct Printer {
+    out: String,
+    indent: usize,
+    /// The next token on the same line is separated by a space
+    space: bool,
+}
+
+impl Printer {
+    fn at_line_start(&self) -> bool {
+        self.out.is_empty() || self.out.ends_with('\n')
+    }
+
+    fn push(&mut self, text: &str) {
+        if self.at_line_start() {
+            for _ in 0..self.indent {
+                self.out.push_str(INDENT);
+            }
+        } else if self.space && !self.out.ends_with('(') {
+            self.out.push(' ');
+        }
+        self.space = false;
+        self.out.push_str(text);
+    }
+
+    fn line_break(&mut self) {
+        if !self.at_line_start() {
+            self.out.push('\n');
+        }
+        self.space = false;
+    }
+
+    fn blank_line(&mut self) {
+        self.line_break();
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    /// The column the next token starts at
+    fn column(&self) -> usize {
+        if self.at_line_start() {
+            return self.indent * INDENT.len();
+        }
+        let line_start = self.out.rfind('\n').map_or(0, |newline| newline + 1);
+        self.out[line_start..].chars().count() + usize::from(self.space)
+    }
+
+    /// Whether `text` fits on the current line
+    fn fits(&self, text: &str) -> bool {
+        !text.contains('\n') && self.column() + text.chars().count() <= MAX_WIDTH
+    }
+
+    fn comment(&mut self, comment: &SyntaxToken) {
+        let text = comment.text().trim_end();
+        self.push(text);
+        if text.starts_with("//") {
+            self.line_break();
+        } else {
+            self.space = true;
+        }
+    }
+
+    /// Prints `entries` one per line, comments that followed an item on its line stay there.
+    /// `blank` decides if two entries are always separated by a blank line,
+    /// otherwise only a single blank line of the source is kept.
+    fn lines(
+        &mut self,
+        entries: &[(Entry, usize)],
+        blank: impl Fn(&Entry, &Entry) -> bool,
+        mut item: impl FnMut(&mut Self, &SyntaxElement, bool),
+    ) {
+        let last_item = entries
+            .iter()
+            .rposition(|(entry, _)| matches!(entry, Entry::Item(_)));
+        let mut previous: Option<&Entry> = None;
+        for (i, (entry, newlines)) in entries.iter().enumerate() {
+            match (previous, entry) {
+                (Some(Entry::Item(_)), Entry::Comment(_)) if *newlines == 0 => self.space = true,
+                (Some(previous), _) if *newlines > 1 || blank(previous, entry) => self.blank_line(),
+                _ => self.line_break(),
+            }
+            match entry {
+                Entry::Item(element) => item(self, element, Some(i) == last_item),
+                Entry::Comment(comment) => self.comment(comment),
+            }
+            previous = Some(entry);
+        }
+    }
+
+    fn compilation_unit(&mut self, root: &SyntaxNode) {
+        let entries = entries(root.children_with_tokens(), is_node);
+        self.lines(
+            &entries,
+            |previous, _| matches!(previous, Entry::Item(_)),
+            |printer, item, _| printer.element(item),
+        );
+        self.line_break();
+    }
+
+    fn element(&mut self, element: &SyntaxElement) {
+        match element {
+            SyntaxElement::Node(node) => self.node(node),
+            SyntaxElement::Token(token) => self.push(token.text()),
+        }
+    }
+
+    fn node(&mut self, node: &SyntaxNode) {
+        match node.kind() {
+            SyntaxKind::TagDef => self.braced(
+                node,
+                is_node,
+                |previous, next| {
+                    previous.is_node(SyntaxKind::FunctionDef)
+                        || (matches!(previous, Entry::Item(_))
+                            && next.is_node(SyntaxKind::FunctionDef))
+                },
+                |printer, item, _| printer.element(item),
+            ),
+            SyntaxKind::Export => self.braced(
+                node,
+                is_node,
+                |_, _| false,
+                |printer, item, _| printer.element(item),
+            ),
+            SyntaxKind::Block => self.braced(
+                node,
+                is_node,
+                |_, _| false,
+                |printer, item, last| {
+                    printer.element(item);
+                    // Blocks end a statement on their own
+                    if !last && !printer.out.ends_with('}') {
+                        printer.space = false;
+                        printer.push(";");
+                    }
+                },
+            ),
+            SyntaxKind::Tag => self.tag(node),
+            SyntaxKind::ParenExpr => match node.child(SyntaxKind::Tag) {
+                Some(tag) => self.paren_tag(node, &tag),
+                None => self.inline(node),
+            },
+            _ => self.inline(node),
+        }
+    }
+
+    /// Prints everything up to `{` on the current line and the items inside the braces
+    /// on their own lines
+    fn braced(
+        &mut self,
+        node: &SyntaxNode,
+        is_item: impl Fn(&SyntaxElement) -> bool,
+        blank: impl Fn(&Entry, &Entry) -> bool,
+        item: impl FnMut(&mut Self, &SyntaxElement, bool),
+    ) {
+        let mut elements = node.children_with_tokens();
+        for element in elements.by_ref() {
+            if is_token(&element, SyntaxKind::LBrace) {
+                break;
+            }
+            self.inline_element(node.kind(), &element, None);
+        }
+        self.space = true;
+        self.push("{");
+
+        let entries = entries(elements, is_item);
+        if !entries.is_empty() {
+            self.indent += 1;
+            self.lines(&entries, blank, item);
+            self.indent -= 1;
+            self.line_break();
+        }
+        self.push("}");
+    }
+
+    /// Prints a node on the current line
+    fn inline(&mut self, node: &SyntaxNode) {
+        let elements = node.children_with_tokens().collect::<Vec<_>>();
+        for (i, element) in elements.iter().enumerate() {
+            let next = elements[i + 1..].iter().find(|element| match element {
+                SyntaxElement::Token(token) => !token.kind().is_trivia(),
+                SyntaxElement::Node(_) => true,
+            });
+            self.inline_element(node.kind(), element, next);
+        }
+    }
+
+    fn inline_element(
+        &mut self,
+        parent: SyntaxKind,
+        element: &SyntaxElement,
+        next: Option<&SyntaxElement>,
+    ) {
+        use SyntaxKind::*;
+
+        let token = match element {
+            SyntaxElement::Node(node) => return self.node(node),
+            SyntaxElement::Token(token) => token,
+        };
+        match token.kind() {
+            Whitespace => {}
+            Comment => {
+                self.space = true;
+                self.comment(token);
+            }
+            // Trailing commas are left out
+            Comma if next.is_some_and(|next| is_token(next, RParen)) => {}
+            Comma | Colon => {
+                self.space = false;
+                self.push(token.text());
+                self.space = true;
+            }
+            Dot | RParen | Semicolon => {
+                self.space = false;
+                self.push(token.text());
+            }
+            LParen if matches!(parent, ArgList | ArgDefList) => {
+                self.space = false;
+                self.push("(");
+            }
+            Assign if parent == Property => {
+                self.space = false;
+                self.push("=");
+            }
+            TagKw | ExportKw | PropKw | StateKw | LetKw | MutKw | IfKw | ElseKw | AsKw | Arrow
+            | Assign | Eq | Ne | Gt | Ge | Lt | Le | Plus | Minus | Star | Slash => {
+                self.space = true;
+                self.push(token.text());
+                self.space = true;
+            }
+            _ => self.push(token.text()),
+        }
+    }
+
+    /// Prints a tag on one line if it fits, otherwise its children go on their own lines
+    fn tag(&mut self, node: &SyntaxNode) {
+        let mut flat = Printer::default();
+        flat.flat_tag(node);
+        if self.fits(&flat.out) {
+            self.push(&flat.out);
+            return;
+        }
+
+        let (head, body, tail) = tag_parts(node);
+        // `>` is part of the head, `/>` of the tail
+        let end = head
+            .iter()
+            .position(|element| is_token(element, SyntaxKind::Gt))
+            .unwrap_or(head.len());
+        let mut flat_head = Printer::default();
+        flat_head.tag_elements(&head);
+        if end < head.len() && self.fits(&flat_head.out) {
+            self.push(&flat_head.out);
+        } else {
+            // One property per line
+            let properties = head
+                .iter()
+                .position(|element| is_node(element) || is_token(element, SyntaxKind::Comment))
+                .unwrap_or(end);
+            self.tag_elements(&head[..properties]);
+            let entries = entries(head[properties..end].iter().cloned(), is_node);
+            self.indent += 1;
+            self.lines(
+                &entries,
+                |_, _| false,
+                |printer, item, _| printer.element(item),
+            );
+            self.indent -= 1;
+            self.line_break();
+            self.tag_elements(&head[end..]);
+        }
+
+        let entries = entries(body, |element| {
+            is_node(element) || is_token(element, SyntaxKind::Text)
+        });
+        if !entries.is_empty() {
+            self.indent += 1;
+            self.lines(
+                &entries,
+                |_, _| false,
+                |printer, item, _| printer.element(item),
+            );
+            self.indent -= 1;
+            self.line_break();
+        }
+        self.tag_elements(&tail);
+    }
+
+    /// Prints a tag and all of its children on the current line
+    fn flat_tag(&mut self, node: &SyntaxNode) {
+        let (head, body, tail) = tag_parts(node);
+        self.tag_elements(&head);
+        let mut first = true;
+        for element in &body {
+            match element {
+                SyntaxElement::Node(tag) => {
+                    self.space = !first;
+                    self.flat_tag(tag);
+                    first = false;
+                }
+                SyntaxElement::Token(token) if token.kind() == SyntaxKind::Text => {
+                    self.space = !first;
+                    self.push(token.text());
+                    first = false;
+                }
+                SyntaxElement::Token(token) if token.kind() == SyntaxKind::Comment => {
+                    self.comment(token)
+                }
+                SyntaxElement::Token(_) => {}
+            }
+        }
+        self.tag_elements(&tail);
+    }
+
+    /// Prints the tokens and properties of a tag, `<Name prop=value>` or `</Name>`
+    fn tag_elements(&mut self, elements: &[SyntaxElement]) {
+        for element in elements {
+            match element {
+                SyntaxElement::Node(property) => {
+                    self.space = true;
+                    self.inline(property);
+                }
+                SyntaxElement::Token(token) => match token.kind() {
+                    SyntaxKind::Whitespace => {}
+                    SyntaxKind::Comment => {
+                        self.space = true;
+                        self.comment(token);
+                    }
+                    // A space after a comment is kept
+                    SyntaxKind::Ident | SyntaxKind::Lt => self.push(token.text()),
+                    _ => {
+                        self.space = false;
+                        self.push(token.text());
+                    }
+                },
+            }
+        }
+    }
+
+    /// A parenthesized tag keeps its parentheses on their own lines if it's too long
+    fn paren_tag(&mut self, node: &SyntaxNode, tag: &SyntaxNode) {
+        let mut flat = Printer::default();
+        flat.flat_tag(tag);
+        let has_comments = node
+            .children_with_tokens()
+            .any(|element| is_token(&element, SyntaxKind::Comment));
+        if !has_comments && self.fits(&format!("({})", flat.out)) {
+            return self.inline(node);
+        }
+
+        self.push("(");
+        let entries = entries(node.children_with_tokens(), is_node);
+        self.indent += 1;
+        self.lines(
+            &entries,
+            |_, _| false,
+            |printer, item, _| printer.element(item),
+        );
+        self.indent -= 1;
+        self.line_break();
+        self.push(")");
+    }
+}
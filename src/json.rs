@@ -0,0 +1,428 @@
+//! A JSON dump of a [`CompilationUnit`] together with its resolution and types, for external tools.
+//! Every node is an object with a `kind`, statements also have their `id`, `span` and inferred `type`,
+//! identifiers the `def` they resolved to, an index into `definitions`.
+
+use std::fmt::{self, Display, Formatter, Write};
+
+use crate::ast::{
+    ArgDef, CompilationUnit, FunctionDef, IdentId, Mutability, NumType, Number, Prop, Span, State,
+    Statement, StatementList, StmtId, Tag, TagChild, TagDef, TopLevelDef,
+};
+use crate::resolve::{DefId, Resolution};
+use crate::typeck::TypeckResults;
+
+pub enum Json {
+    Null,
+    Bool(bool),
+    /// Already formatted, so integers above `i64::MAX` and floats are written exactly
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    fn string(s: impl Into<String>) -> Json {
+        Json::String(s.into())
+    }
+
+    fn number(n: impl Display) -> Json {
+        Json::Number(n.to_string())
+    }
+
+    fn option<T>(value: Option<T>, f: impl FnOnce(T) -> Json) -> Json {
+        value.map_or(Json::Null, f)
+    }
+
+    fn write(&self, f: &mut Formatter, indent: usize) -> fmt::Result {
+        // `{:#}` puts every element of a non-empty array or object on its own line
+        let pretty = f.alternate();
+        let newline = |f: &mut Formatter, indent: usize| {
+            if pretty {
+                write!(f, "\n{:1$}", "", indent * 2)
+            } else {
+                Ok(())
+            }
+        };
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{b}"),
+            Json::Number(n) => f.write_str(n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) if items.is_empty() => f.write_str("[]"),
+            Json::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    newline(f, indent + 1)?;
+                    item.write(f, indent + 1)?;
+                }
+                newline(f, indent)?;
+                f.write_char(']')
+            }
+            Json::Object(fields) if fields.is_empty() => f.write_str("{}"),
+            Json::Object(fields) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    newline(f, indent + 1)?;
+                    write_string(f, key)?;
+                    f.write_str(if pretty { ": " } else { ":" })?;
+                    value.write(f, indent + 1)?;
+                }
+                newline(f, indent)?;
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+fn write_string(f: &mut Formatter, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+/// The whole unit, with the problems found while checking it as `errors`
+pub fn unit_json(
+    unit: &CompilationUnit,
+    resolution: &Resolution,
+    results: &TypeckResults,
+    errors: &[(String, Span)],
+) -> Json {
+    let dump = Dump {
+        unit,
+        resolution,
+        results,
+    };
+    let definitions = resolution
+        .defs
+        .iter()
+        .enumerate()
+        .map(|(id, def)| {
+            let id = DefId(id);
+            Json::Object(vec![
+                ("id", Json::number(id.0)),
+                ("kind", Json::string(format!("{:?}", def.kind))),
+                ("name", Json::string(&def.name)),
+                ("span", span_json(def.span)),
+                ("mutable", Json::Bool(def.mutable)),
+                (
+                    "type",
+                    Json::option(results.def_types.get(&id), |ty| {
+                        Json::string(ty.to_string())
+                    }),
+                ),
+                (
+                    "signature",
+                    Json::option(results.fn_sigs.get(&id), |sig| {
+                        Json::Object(vec![
+                            (
+                                "args",
+                                Json::Array(
+                                    sig.args
+                                        .iter()
+                                        .map(|ty| Json::string(ty.to_string()))
+                                        .collect(),
+                                ),
+                            ),
+                            ("ret", Json::string(sig.ret.to_string())),
+                        ])
+                    }),
+                ),
+            ])
+        })
+        .collect();
+    let errors = errors
+        .iter()
+        .map(|(message, span)| {
+            Json::Object(vec![
+                ("message", Json::string(message)),
+                ("span", span_json(*span)),
+            ])
+        })
+        .collect();
+    Json::Object(vec![
+        ("kind", Json::string("CompilationUnit")),
+        (
+            "defs",
+            Json::Array(
+                unit.defs
+                    .iter()
+                    .map(|def| dump.top_level_def(def))
+                    .collect(),
+            ),
+        ),
+        ("definitions", Json::Array(definitions)),
+        ("errors", Json::Array(errors)),
+    ])
+}
+
+fn span_json(span: Span) -> Json {
+    Json::Object(vec![
+        ("start", Json::number(span.start)),
+        ("end", Json::number(span.end)),
+    ])
+}
+
+struct Dump<'a> {
+    unit: &'a CompilationUnit,
+    resolution: &'a Resolution,
+    results: &'a TypeckResults,
+}
+
+impl Dump<'_> {
+    fn top_level_def(&self, def: &TopLevelDef) -> Json {
+        match def {
+            TopLevelDef::Func(fun) => self.function_def(fun),
+            TopLevelDef::Export(ident, tags) => Json::Object(vec![
+                ("kind", Json::string("Export")),
+                ("name", self.ident(*ident)),
+                (
+                    "tags",
+                    Json::Array(tags.0.iter().map(|tag| self.tag(tag)).collect()),
+                ),
+            ]),
+            TopLevelDef::TagDef(tag_def) => self.tag_def(tag_def),
+        }
+    }
+
+    fn tag_def(&self, tag_def: &TagDef) -> Json {
+        let prop = |prop: &Prop| {
+            Json::Object(vec![
+                ("kind", Json::string("Prop")),
+                ("name", self.ident(prop.ident)),
+                ("annotation", self.ident(prop.chip_type)),
+                ("default", self.optional_statement(prop.default)),
+            ])
+        };
+        let state = |state: &State| {
+            Json::Object(vec![
+                ("kind", Json::string("State")),
+                ("name", self.ident(state.ident)),
+                (
+                    "annotation",
+                    Json::option(state.chip_type, |id| self.ident(id)),
+                ),
+                ("default", self.optional_statement(state.default)),
+            ])
+        };
+        Json::Object(vec![
+            ("kind", Json::string("TagDef")),
+            ("name", self.ident(tag_def.ident)),
+            (
+                "props",
+                Json::Array(tag_def.props.0.iter().map(prop).collect()),
+            ),
+            (
+                "states",
+                Json::Array(tag_def.states.0.iter().map(state).collect()),
+            ),
+            (
+                "fns",
+                Json::Array(
+                    tag_def
+                        .fns
+                        .0
+                        .iter()
+                        .map(|fun| self.function_def(fun))
+                        .collect(),
+                ),
+            ),
+        ])
+    }
+
+    fn function_def(&self, fun: &FunctionDef) -> Json {
+        let arg = |arg: &ArgDef| {
+            Json::Object(vec![
+                ("kind", Json::string("ArgDef")),
+                ("name", self.ident(arg.name)),
+                ("annotation", self.ident(arg.chip_type)),
+            ])
+        };
+        Json::Object(vec![
+            ("kind", Json::string("FunctionDef")),
+            ("name", self.ident(fun.ident)),
+            ("args", Json::Array(fun.args.0.iter().map(arg).collect())),
+            (
+                "return_type",
+                Json::option(fun.return_type, |id| self.ident(id)),
+            ),
+            ("block", self.statement_list(&fun.block)),
+        ])
+    }
+
+    fn statement_list(&self, list: &StatementList) -> Json {
+        Json::Array(list.0.iter().map(|id| self.statement(*id)).collect())
+    }
+
+    fn optional_statement(&self, id: Option<StmtId>) -> Json {
+        Json::option(id, |id| self.statement(id))
+    }
+
+    fn statement(&self, id: StmtId) -> Json {
+        let statement = &self.unit[id];
+        let kind = match statement {
+            Statement::Number(..) => "Number",
+            Statement::Op(..) => "Op",
+            Statement::FunctionCall(_) => "FunctionCall",
+            Statement::If(..) => "If",
+            Statement::IfElse(..) => "IfElse",
+            Statement::Let(..) => "Let",
+            Statement::LetAssign(..) => "LetAssign",
+            Statement::Assign(..) => "Assign",
+            Statement::Tag(_) => "Tag",
+            Statement::Ident(_) => "Ident",
+            Statement::Block(_) => "Block",
+            Statement::Cast(..) => "Cast",
+            Statement::Error => "Error",
+        };
+        let mut fields = vec![
+            ("kind", Json::string(kind)),
+            ("id", Json::number(id.index())),
+            ("span", Json::option(self.unit.span(id), span_json)),
+            (
+                "type",
+                Json::option(self.results.types.get(&id), |ty| {
+                    Json::string(ty.to_string())
+                }),
+            ),
+        ];
+        let mutable = |mutability: &Mutability| Json::Bool(*mutability == Mutability::Mutable);
+        match statement {
+            Statement::Number(number, _) => {
+                let (value, suffix) = match *number {
+                    Number::Int(value, Some(NumType::U64)) => {
+                        (Json::number(value as u64), Some(NumType::U64))
+                    }
+                    Number::Int(value, suffix) => (Json::number(value), suffix),
+                    Number::Float(value, suffix) => (Json::number(format!("{value:?}")), suffix),
+                };
+                fields.push(("value", value));
+                fields.push(("suffix", Json::option(suffix, |t| Json::string(t.suffix()))));
+            }
+            Statement::Op(l, op, r) => {
+                fields.push(("left", self.statement(*l)));
+                fields.push(("op", Json::string(op.to_string())));
+                fields.push(("right", self.statement(*r)));
+            }
+            Statement::FunctionCall(call) => {
+                fields.push(("name", self.ident(call.ident)));
+                fields.push((
+                    "args",
+                    Json::Array(call.args.0.iter().map(|arg| self.statement(*arg)).collect()),
+                ));
+            }
+            Statement::If(cond, block) => {
+                fields.push(("condition", self.statement(*cond)));
+                fields.push(("block", self.statement_list(block)));
+            }
+            Statement::IfElse(cond, if_block, else_block) => {
+                fields.push(("condition", self.statement(*cond)));
+                fields.push(("block", self.statement_list(if_block)));
+                fields.push(("else_block", self.statement_list(else_block)));
+            }
+            Statement::Let(mutability, ident, annotation) => {
+                fields.push(("mutable", mutable(mutability)));
+                fields.push(("name", self.ident(*ident)));
+                fields.push(("annotation", Json::option(*annotation, |id| self.ident(id))));
+            }
+            Statement::LetAssign(mutability, ident, annotation, value) => {
+                fields.push(("mutable", mutable(mutability)));
+                fields.push(("name", self.ident(*ident)));
+                fields.push(("annotation", Json::option(*annotation, |id| self.ident(id))));
+                fields.push(("value", self.statement(*value)));
+            }
+            Statement::Assign(idents, value) => {
+                fields.push((
+                    "path",
+                    Json::Array(idents.0.iter().map(|id| self.ident(*id)).collect()),
+                ));
+                fields.push(("value", self.statement(*value)));
+            }
+            Statement::Tag(tag) => fields.extend(self.tag_fields(tag)),
+            Statement::Ident(ident) => fields.push(("name", self.ident(*ident))),
+            Statement::Block(list) => fields.push(("statements", self.statement_list(list))),
+            Statement::Cast(value, chip_type) => {
+                fields.push(("value", self.statement(*value)));
+                fields.push(("target", self.ident(*chip_type)));
+            }
+            Statement::Error => {}
+        }
+        Json::Object(fields)
+    }
+
+    fn tag(&self, tag: &Tag) -> Json {
+        let mut fields = vec![("kind", Json::string("Tag"))];
+        fields.extend(self.tag_fields(tag));
+        Json::Object(fields)
+    }
+
+    fn tag_fields(&self, tag: &Tag) -> Vec<(&'static str, Json)> {
+        let properties = tag
+            .properties
+            .0
+            .iter()
+            .map(|(key, value)| {
+                Json::Object(vec![
+                    ("kind", Json::string("Property")),
+                    ("key", self.ident(*key)),
+                    ("value", self.statement(*value)),
+                ])
+            })
+            .collect();
+        let children = tag
+            .children
+            .0
+            .iter()
+            .map(|child| match child {
+                TagChild::Tag(tag) => self.tag(tag),
+                TagChild::Text(text, span) => Json::Object(vec![
+                    ("kind", Json::string("Text")),
+                    ("text", Json::string(text)),
+                    ("span", span_json(*span)),
+                ]),
+            })
+            .collect();
+        vec![
+            ("name", self.ident(tag.ident)),
+            ("properties", Json::Array(properties)),
+            ("children", Json::Array(children)),
+        ]
+    }
+
+    fn ident(&self, id: IdentId) -> Json {
+        let ident = &self.unit[id];
+        let def = self
+            .resolution
+            .refs
+            .get(&id)
+            .or_else(|| self.resolution.decls.get(&id));
+        Json::Object(vec![
+            ("kind", Json::string("Ident")),
+            ("name", Json::string(&ident.0)),
+            ("span", span_json(ident.1)),
+            ("def", Json::option(def, |def| Json::number(def.0))),
+        ])
+    }
+}
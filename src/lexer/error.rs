@@ -0,0 +1,21 @@
+use thiserror::Error;
+
+use crate::ast::Span;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum LexError {
+    #[error("Unexpected character `{0}`")]
+    UnexpectedChar(char, Span),
+    #[error("Block comment is never closed")]
+    UnterminatedComment(Span),
+}
+
+impl LexError {
+    /// Where in the source this error should be reported
+    pub fn span(&self) -> Span {
+        use self::LexError::*;
+        match self {
+            UnexpectedChar(_, span) | UnterminatedComment(span) => *span,
+        }
+    }
+}
@@ -0,0 +1,398 @@
+use std::fmt::{Display, Error, Formatter};
+
+use crate::ast::{ChipParseError, NumType, Span};
+
+pub use self::error::LexError;
+
+mod error;
+
+/// What the parser gets from the [`Lexer`]: a token and its start and end byte offsets
+pub type Spanned<'a> = (usize, Token<'a>, usize);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Token<'a> {
+    Ident(&'a str),
+    /// A numeric literal with its sign and type suffix, parsed by [`crate::ast::Number::parse`]
+    Num(&'a str),
+    /// Text between tags without the surrounding whitespace
+    Text(&'a str),
+
+    Tag,
+    Export,
+    Prop,
+    State,
+    Let,
+    Mut,
+    If,
+    Else,
+    As,
+
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Colon,
+    Semicolon,
+    Comma,
+    Dot,
+    Arrow,
+    Assign,
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    /// `</`, starts a closing tag
+    LtSlash,
+    /// `/>`, ends a tag without children
+    SlashGt,
+}
+
+impl Token<'_> {
+    fn keyword(ident: &str) -> Option<Token<'static>> {
+        let keyword = match ident {
+            "tag" => Token::Tag,
+            "export" => Token::Export,
+            "prop" => Token::Prop,
+            "state" => Token::State,
+            "let" => Token::Let,
+            "mut" => Token::Mut,
+            "if" => Token::If,
+            "else" => Token::Else,
+            "as" => Token::As,
+            _ => return None,
+        };
+        Some(keyword)
+    }
+
+    /// Whether an operator can follow this token, otherwise an operand has to
+    fn ends_operand(&self) -> bool {
+        matches!(self, Token::Ident(_) | Token::Num(_) | Token::RParen)
+    }
+}
+
+impl Display for Token<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        let text = match self {
+            Token::Ident(text) | Token::Num(text) | Token::Text(text) => text,
+            Token::Tag => "tag",
+            Token::Export => "export",
+            Token::Prop => "prop",
+            Token::State => "state",
+            Token::Let => "let",
+            Token::Mut => "mut",
+            Token::If => "if",
+            Token::Else => "else",
+            Token::As => "as",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::Colon => ":",
+            Token::Semicolon => ";",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            Token::Arrow => "->",
+            Token::Assign => "=",
+            Token::Eq => "==",
+            Token::Ne => "!=",
+            Token::Gt => ">",
+            Token::Ge => ">=",
+            Token::Lt => "<",
+            Token::Le => "<=",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Star => "*",
+            Token::Slash => "/",
+            Token::LtSlash => "</",
+            Token::SlashGt => "/>",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// Statements and expressions
+    Code,
+    /// Between `<` and `>` or `/>`, lexed like code
+    TagHead,
+    /// Between an opening and a closing tag, anything that isn't a tag is text
+    TagBody,
+    /// Between `</` and `>`
+    TagClose,
+}
+
+/// Splits source text into [`Token`]s for the parser.
+///
+/// Whitespace and comments (`// ...` and `/* ... */`) are skipped, except inside of tag
+/// bodies where everything up to the next tag is [`Token::Text`].
+pub struct Lexer<'a> {
+    source: &'a str,
+    pos: usize,
+    /// Innermost mode last, never empty
+    modes: Vec<Mode>,
+    /// Whether the previous token leaves an operand missing,
+    /// which makes `<` open a tag and `+` and `-` part of a number
+    expect_operand: bool,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a str) -> Lexer<'a> {
+        Lexer {
+            source,
+            pos: 0,
+            modes: vec![Mode::Code],
+            expect_operand: true,
+        }
+    }
+
+    /// All remaining tokens, lexing continues after errors
+    pub fn tokens(mut self) -> impl Iterator<Item = Result<Spanned<'a>, LexError>> {
+        std::iter::from_fn(move || self.next_token())
+    }
+
+    fn mode(&self) -> Mode {
+        *self.modes.last().expect("There is always a mode")
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        *self.modes.last_mut().expect("There is always a mode") = mode;
+    }
+
+    /// The byte `offset` bytes after the current position
+    fn peek(&self, offset: usize) -> Option<u8> {
+        self.source.as_bytes().get(self.pos + offset).copied()
+    }
+
+    fn eat_while(&mut self, f: impl Fn(u8) -> bool) {
+        while self.peek(0).is_some_and(&f) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.eat_while(|b| b.is_ascii_whitespace());
+    }
+
+    fn skip_trivia(&mut self) -> Result<(), LexError> {
+        loop {
+            self.skip_whitespace();
+            match (self.peek(0), self.peek(1)) {
+                (Some(b'/'), Some(b'/')) => self.eat_while(|b| b != b'\n'),
+                (Some(b'/'), Some(b'*')) => {
+                    let start = self.pos;
+                    match self.source[start + 2..].find("*/") {
+                        Some(end) => self.pos = start + 2 + end + 2,
+                        None => {
+                            self.pos = self.source.len();
+                            return Err(LexError::UnterminatedComment(Span::new(
+                                start,
+                                self.source.len(),
+                            )));
+                        }
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Option<Result<Spanned<'a>, LexError>> {
+        if self.mode() == Mode::TagBody {
+            self.skip_whitespace();
+            if self.peek(0).is_some_and(|b| b != b'<') {
+                return Some(Ok(self.text()));
+            }
+        } else if let Err(err) = self.skip_trivia() {
+            return Some(Err(err));
+        }
+
+        let start = self.pos;
+        let first = self.peek(0)?;
+        let second = self.peek(1);
+
+        let token = match first {
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => {
+                self.eat_while(is_ident_continue);
+                let ident = &self.source[start..self.pos];
+                Token::keyword(ident).unwrap_or(Token::Ident(ident))
+            }
+            b'0'..=b'9' => self.number(start),
+            b'+' | b'-' if self.expect_operand && second.is_some_and(|b| b.is_ascii_digit()) => {
+                self.pos += 1;
+                self.number(start)
+            }
+            b'<' if self.mode() == Mode::TagBody && second == Some(b'/') => {
+                self.pos += 2;
+                self.set_mode(Mode::TagClose);
+                Token::LtSlash
+            }
+            b'<' if self.mode() == Mode::TagBody
+                || (self.expect_operand && second.is_some_and(is_ident_start)) =>
+            {
+                self.pos += 1;
+                self.modes.push(Mode::TagHead);
+                Token::Lt
+            }
+            b'/' if self.mode() == Mode::TagHead && second == Some(b'>') => {
+                self.pos += 2;
+                self.modes.pop();
+                Token::SlashGt
+            }
+            b'>' if self.mode() == Mode::TagHead => {
+                self.pos += 1;
+                self.set_mode(Mode::TagBody);
+                Token::Gt
+            }
+            b'>' if self.mode() == Mode::TagClose => {
+                self.pos += 1;
+                self.modes.pop();
+                Token::Gt
+            }
+            _ => {
+                let (token, len) = match (first, second) {
+                    (b'-', Some(b'>')) => (Token::Arrow, 2),
+                    (b'=', Some(b'=')) => (Token::Eq, 2),
+                    (b'!', Some(b'=')) => (Token::Ne, 2),
+                    (b'>', Some(b'=')) => (Token::Ge, 2),
+                    (b'<', Some(b'=')) => (Token::Le, 2),
+                    (b'(', _) => (Token::LParen, 1),
+                    (b')', _) => (Token::RParen, 1),
+                    (b'{', _) => (Token::LBrace, 1),
+                    (b'}', _) => (Token::RBrace, 1),
+                    (b':', _) => (Token::Colon, 1),
+                    (b';', _) => (Token::Semicolon, 1),
+                    (b',', _) => (Token::Comma, 1),
+                    (b'.', _) => (Token::Dot, 1),
+                    (b'=', _) => (Token::Assign, 1),
+                    (b'>', _) => (Token::Gt, 1),
+                    (b'<', _) => (Token::Lt, 1),
+                    (b'+', _) => (Token::Plus, 1),
+                    (b'-', _) => (Token::Minus, 1),
+                    (b'*', _) => (Token::Star, 1),
+                    (b'/', _) => (Token::Slash, 1),
+                    _ => {
+                        let c = self.source[start..].chars().next()?;
+                        self.pos += c.len_utf8();
+                        return Some(Err(LexError::UnexpectedChar(c, Span::new(start, self.pos))));
+                    }
+                };
+                self.pos += len;
+                token
+            }
+        };
+
+        self.expect_operand = !token.ends_operand();
+        Some(Ok((start, token, self.pos)))
+    }
+
+    /// Everything up to the next tag, `<` can't appear in text
+    fn text(&mut self) -> Spanned<'a> {
+        let start = self.pos;
+        self.pos = self.source[start..]
+            .find('<')
+            .map_or(self.source.len(), |len| start + len);
+        let text = self.source[start..self.pos].trim_end();
+        (start, Token::Text(text), start + text.len())
+    }
+
+    /// Continues a numeric literal starting at `start` after its sign,
+    /// see [`crate::ast::Number::parse`] for the literals this accepts
+    fn number(&mut self, start: usize) -> Token<'a> {
+        let radix = match (self.peek(0), self.peek(1), self.peek(2)) {
+            (Some(b'0'), Some(b'x'), Some(digit)) if digit.is_ascii_hexdigit() => 16,
+            (Some(b'0'), Some(b'o'), Some(b'0'..=b'7')) => 8,
+            (Some(b'0'), Some(b'b'), Some(b'0'..=b'1')) => 2,
+            _ => 10,
+        };
+
+        let mut is_float = false;
+        if radix == 10 {
+            self.eat_while(|b| b.is_ascii_digit() || b == b'_');
+            if self.peek(0) == Some(b'.') && self.peek(1).is_some_and(|b| b.is_ascii_digit()) {
+                self.pos += 1;
+                self.eat_while(|b| b.is_ascii_digit() || b == b'_');
+                is_float = true;
+            }
+            is_float |= self.exponent(b'e');
+        } else {
+            self.pos += 2;
+            self.eat_while(|b| (b as char).is_digit(radix) || b == b'_');
+            if radix == 16 {
+                // A fraction is only part of the literal if an exponent follows
+                let before_fraction = self.pos;
+                if self.peek(0) == Some(b'.') && self.peek(1).is_some_and(|b| b.is_ascii_hexdigit())
+                {
+                    self.pos += 1;
+                    self.eat_while(|b| b.is_ascii_hexdigit() || b == b'_');
+                }
+                is_float = self.exponent(b'p');
+                if !is_float {
+                    self.pos = before_fraction;
+                }
+            }
+        }
+
+        // Floats only take a float suffix. Prefixed ints can't have one either,
+        // in hexadecimal ints it would be digits anyway.
+        let suffix = NumType::ALL
+            .into_iter()
+            .filter(|ty| match is_float {
+                true => ty.is_float(),
+                false => radix == 10 || !ty.is_float(),
+            })
+            .map(NumType::suffix)
+            .find(|suffix| {
+                let rest = &self.source.as_bytes()[self.pos..];
+                rest.starts_with(suffix.as_bytes())
+                    && !rest
+                        .get(suffix.len())
+                        .is_some_and(|b| is_ident_continue(*b))
+            });
+        if let Some(suffix) = suffix {
+            self.pos += suffix.len();
+        }
+
+        Token::Num(&self.source[start..self.pos])
+    }
+
+    /// Eats an exponent starting with `marker` (or its uppercase version) and an optional sign
+    fn exponent(&mut self, marker: u8) -> bool {
+        if self.peek(0).map(|b| b.to_ascii_lowercase()) != Some(marker) {
+            return false;
+        }
+        let digits_at = match self.peek(1) {
+            Some(b'+' | b'-') => 2,
+            _ => 1,
+        };
+        if !self.peek(digits_at).is_some_and(|b| b.is_ascii_digit()) {
+            return false;
+        }
+        self.pos += digits_at;
+        self.eat_while(|b| b.is_ascii_digit() || b == b'_');
+        true
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Spanned<'a>, ChipParseError<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_token()
+            .map(|token| token.map_err(ChipParseError::from))
+    }
+}
+
+fn is_ident_start(b: u8) -> bool {
+    b.is_ascii_alphabetic() || b == b'_'
+}
+
+fn is_ident_continue(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
@@ -2,79 +2,150 @@
 extern crate lalrpop_util;
 lalrpop_mod!(#[allow(clippy::all, warnings)] pub main_parser);
 mod ast;
+mod compile;
+pub mod cst;
+mod format;
+mod json;
+mod lexer;
+mod output;
+mod resolve;
 mod text;
+mod typeck;
+mod validate;
+#[allow(dead_code)]
+mod visit;
 mod wasm;
 
+pub use ast::Span;
+pub use compile::{compile_source, CompileOptions, CompileOutput, Diagnostic, Metadata};
+pub use format::format_source;
+pub use output::{OutputPaths, Target};
 use std::{
     fs,
     io::{self},
     path::{Path, PathBuf},
-    rc::Rc,
 };
 
-/// Compiles every .chip file in the current directory
-pub fn compile_root() -> anyhow::Result<()> {
+/// Compiles every .chip file in the current directory, into the default [`OutputPaths`]
+pub fn compile_root() -> anyhow::Result<Vec<PathBuf>> {
     let path = std::env::current_dir()?;
-    compile_folder(&path)
+    compile_folder(&path, &OutputPaths::new(&path))
 }
 
-/// Compiles every .chip file in the specified directory
-pub fn compile_folder<P: AsRef<Path>>(path: P) -> anyhow::Result<()> {
+/// Compiles every .chip file in the specified directory, which has to be inside of `outputs.src_dir`.
+/// Returns the files that were written.
+pub fn compile_folder<P: AsRef<Path>>(
+    path: P,
+    outputs: &OutputPaths,
+) -> anyhow::Result<Vec<PathBuf>> {
     let chip_files = chip_files(path)?;
     let mut results = vec![];
     // TODO: Multithreading
     for chip_file in chip_files {
-        results.push(compile_file(chip_file));
+        results.push(compile_file(chip_file, outputs));
     }
+    let mut written = vec![];
     let mut err_results = vec![];
     for res in results {
-        if res.is_err() {
-            err_results.push(res.unwrap_err());
+        match res {
+            Ok(out_file) => written.push(out_file),
+            Err(err) => err_results.push(err),
         }
     }
     if err_results.is_empty() {
-        Ok(())
+        Ok(written)
     } else {
         anyhow::bail!("Multiple errors found: {:?}", err_results);
     }
 }
 
-#[allow(dead_code, unused_variables)]
-fn compile_file(path: PathBuf) -> anyhow::Result<()> {
-    let rs_file = resolve_rust_file(path.as_path());
-    println!("[{:?}] Starting compilation...", path);
-
-    if let Some(path) = rs_file.parent() {
-        fs::create_dir_all(path)?;
+/// Formats every .chip file at `path`, which is either a file or a directory.
+/// Returns the files whose formatting changed; with `check` they are only reported, not rewritten.
+pub fn format_path<P: AsRef<Path>>(path: P, check: bool) -> anyhow::Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    let files = if path.is_dir() {
+        chip_files(path)?
+    } else {
+        vec![path.to_path_buf()]
+    };
+    let mut changed = vec![];
+    for file in files {
+        let in_file = text::CodeText::from_path(file.clone())?;
+        match format_source(in_file.text()) {
+            Ok(formatted) if formatted != *in_file.text() => {
+                if !check {
+                    fs::write(&file, formatted)?;
+                }
+                changed.push(file);
+            }
+            Ok(_) => {}
+            Err(errors) => {
+                let errors = errors
+                    .iter()
+                    .map(|err| in_file.describe(err.span().start, err))
+                    .collect::<Vec<_>>();
+                anyhow::bail!("Could not format {file:?}:\n{}", errors.join("\n"));
+            }
+        }
     }
+    Ok(changed)
+}
 
-    let in_file = Rc::new(text::CodeText::from_path(path.clone())?);
-    if rs_file.exists() {
-        std::fs::remove_file(rs_file.clone())?;
-    }
-    let out_file = fs::File::create(rs_file)?;
+fn compile_file(path: PathBuf, outputs: &OutputPaths) -> anyhow::Result<PathBuf> {
+    let out_file = outputs.output_path(&path)?;
 
-    let ast = {
-        let mut e = vec![];
-        let ast = main_parser::CompilationUnitParser::new().parse(&mut e, in_file.text());
-        match ast {
-            Err(err) => {
-                anyhow::bail!("Parse error in {in_file:?}: {err}");
-            }
-            Ok(ast) => ast,
-        }
-    };
+    let in_file = text::CodeText::from_path(path.clone())?;
+    let output = compile_source(
+        &path.display().to_string(),
+        in_file.text(),
+        &CompileOptions::default(),
+    );
+    if !output.is_ok() {
+        let diagnostics = output
+            .diagnostics
+            .iter()
+            .map(Diagnostic::to_string)
+            .collect::<Vec<_>>();
+        anyhow::bail!("Compilation failed:\n{}", diagnostics.join("\n"));
+    }
 
-    Ok(())
+    if let Some(parent) = out_file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let contents = outputs
+        .target
+        .render(&path.display().to_string(), output.wasm.unwrap_or_default())?;
+    fs::write(&out_file, contents)?;
+    Ok(out_file)
 }
 
-fn resolve_rust_file(path: &Path) -> PathBuf {
-    let in_dir = Path::new(".");
-    let out_dir = in_dir.clone();
-
-    out_dir
-        .join(path.strip_prefix(&in_dir).unwrap_or(path))
-        .with_extension("chip")
+/// Dumps every .chip file at `path`, which is either a file or a directory, as a JSON array
+/// with a `{"path", "unit"}` object per file. Files that don't type check are still dumped, with their `errors`.
+pub fn emit_ast_json<P: AsRef<Path>>(path: P) -> anyhow::Result<String> {
+    let path = path.as_ref();
+    let files = if path.is_dir() {
+        chip_files(path)?
+    } else {
+        vec![path.to_path_buf()]
+    };
+    let mut dumps = vec![];
+    for file in files {
+        let in_file = text::CodeText::from_path(file.clone())?;
+        let (unit, diagnostics) = compile::parse(&in_file);
+        if !diagnostics.is_empty() {
+            let diagnostics = diagnostics
+                .iter()
+                .map(Diagnostic::to_string)
+                .collect::<Vec<_>>();
+            anyhow::bail!("Could not parse {file:?}:\n{}", diagnostics.join("\n"));
+        }
+        let (resolution, types, errors) = compile::analyze(&unit);
+        dumps.push(json::Json::Object(vec![
+            ("path", json::Json::String(file.display().to_string())),
+            ("unit", json::unit_json(&unit, &resolution, &types, &errors)),
+        ]));
+    }
+    Ok(format!("{:#}", json::Json::Array(dumps)))
 }
 
 /// Copied from lalrpop
@@ -112,7 +183,11 @@ fn chip_files<P: AsRef<Path>>(root_dir: P) -> anyhow::Result<Vec<PathBuf>> {
 #[allow(dead_code)]
 #[cfg(test)]
 mod tests {
-    use crate::wasm::{compile_statement_wasm, ChipType, LocalMap, Primitive};
+    use crate::lexer::Lexer;
+    use crate::wasm::{
+        compile_statement_wasm, Analysis, ChipType, FunctionTable, LocalMap, Primitive,
+        WasmCodegenError,
+    };
     use linked_hash_map::LinkedHashMap;
     use std::rc::Rc;
     use std::vec;
@@ -138,7 +213,12 @@ mod tests {
     #[test]
     fn number_too_big() {
         let mut errors = vec![];
-        let expr = main_parser::StatementParser::new().parse(&mut errors, "9223372036854775808");
+        let mut unit = ast::CompilationUnit::default();
+        let expr = main_parser::StatementParser::new().parse(
+            &mut errors,
+            &mut unit,
+            Lexer::new("9223372036854775808"),
+        );
         assert!(expr.is_err());
     }
 
@@ -172,6 +252,229 @@ mod tests {
         parse_statement_expect("0x70Aa", "28842");
     }
 
+    #[test]
+    fn numeric_literals() {
+        parse_statement_expect("1_000_000", "1000000");
+        parse_statement_expect("0b1010_1010u8", "170u8");
+        parse_statement_expect("-0o777", "-511");
+        parse_statement_expect("0xFF_FF", "65535");
+        parse_statement_expect("1.5e-3", "0.0015");
+        parse_statement_expect("2E+2_0", "200000000000000000000");
+        parse_statement_expect("0x1.8p1", "3");
+        parse_statement_expect("0x1p-2f32", "0.25f32");
+        parse_statement_expect("-9223372036854775808", "-9223372036854775808");
+    }
+
+    #[test]
+    fn numeric_literal_overflow() {
+        let literal_error = |l: &str| {
+            let mut errors = vec![];
+            let mut unit = ast::CompilationUnit::default();
+            match main_parser::StatementParser::new().parse(&mut errors, &mut unit, Lexer::new(l)) {
+                Err(lalrpop_util::ParseError::User { error }) => {
+                    let span = error.span();
+                    format!("{error} @ {}", &l[span.start..span.end])
+                }
+                other => panic!("Expected a literal error, got {other:?}"),
+            }
+        };
+        assert_eq!(
+            literal_error("1 + -9_223_372_036_854_775_809"),
+            "Integer literal was too large (couldn't fit into i64): -9_223_372_036_854_775_809 @ -9_223_372_036_854_775_809"
+        );
+        assert_eq!(
+            literal_error("0b1_0000_0000u8"),
+            "Integer literal was too large (couldn't fit into u8): 0b1_0000_0000u8 @ 0b1_0000_0000u8"
+        );
+        assert_eq!(
+            literal_error("x * 1e39f32"),
+            "Float literal was too large (couldn't fit into f32): 1e39f32 @ 1e39f32"
+        );
+    }
+
+    #[test]
+    fn literal_suffixes() {
+        parse_statement_expect("10u8 + 1.5f32", "(10u8 + 1.5f32)");
+        parse_statement_expect("0xffu32", "255u32");
+        parse_statement_expect("0x1f32", "7986");
+        parse_statement_expect("18446744073709551615u64", "18446744073709551615u64");
+        parse_statement_expect("-2e5f32", "-200000f32");
+    }
+
+    #[test]
+    fn literal_suffix_too_big() {
+        let mut errors = vec![];
+        let mut unit = ast::CompilationUnit::default();
+        let expr =
+            main_parser::StatementParser::new().parse(&mut errors, &mut unit, Lexer::new("256u8"));
+        assert_eq!(
+            expr.unwrap_err().to_string(),
+            "Integer literal was too large (couldn't fit into u8): 256u8"
+        );
+        assert_eq!(
+            main_parser::StatementParser::new()
+                .parse(&mut errors, &mut unit, Lexer::new("-1u32"))
+                .unwrap_err()
+                .to_string(),
+            "Unsigned literal can't be negative (u32): -1u32"
+        );
+        assert!(main_parser::StatementParser::new()
+            .parse(&mut errors, &mut unit, Lexer::new("-0u8"))
+            .is_ok());
+        assert_eq!(
+            ast::Number::parse("1.5u8", ast::Span::default())
+                .unwrap_err()
+                .to_string(),
+            "Float literal can't have an integer suffix (u8): 1.5u8"
+        );
+        assert!(ast::Number::parse("0x1p3i64", ast::Span::default()).is_err());
+    }
+
+    #[test]
+    fn num_type_names() {
+        assert_eq!(ast::NumType::from_name("U8"), Some(ast::NumType::U8));
+        assert_eq!(ast::NumType::from_name("I64"), Some(ast::NumType::I64));
+        assert_eq!(ast::NumType::from_name("Int"), Some(ast::NumType::I64));
+        assert_eq!(ast::NumType::from_name("u8"), None);
+        assert_eq!(ast::NumType::from_name("i32"), None);
+        assert_eq!(ast::NumType::from_name("int"), None);
+    }
+
+    #[test]
+    fn cast() {
+        parse_statement_expect("a as U8 * 2 + 1", "(((a as U8) * 2) + 1)");
+        parse_statement_expect("1.5 as Int as F32", "((1.5 as Int) as F32)");
+    }
+
+    #[test]
+    fn lexer_tokens() {
+        let tokens = |l: &str| {
+            Lexer::new(l)
+                .map(|token| token.unwrap().1.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tokens("a-1 < -2"), vec!["a", "-", "1", "<", "-2"]);
+        assert_eq!(
+            tokens("(<Text size=-1/>) // <Text/>"),
+            vec!["(", "<", "Text", "size", "=", "-1", "/>", ")"]
+        );
+        assert_eq!(
+            tokens("{ <Row>\n  Hello, world! <Text/>\n</Row> }"),
+            vec![
+                "{",
+                "<",
+                "Row",
+                ">",
+                "Hello, world!",
+                "<",
+                "Text",
+                "/>",
+                "</",
+                "Row",
+                ">",
+                "}"
+            ]
+        );
+        assert_eq!(tokens("let /* a < b */ s = 1"), vec!["let", "s", "=", "1"]);
+        // Integer suffixes aren't part of a float
+        assert_eq!(tokens("1.5u8"), vec!["1.5", "u8"]);
+        assert_eq!(tokens("1e2u8"), vec!["1e2", "u8"]);
+        assert_eq!(tokens("0x1p3i64"), vec!["0x1p3", "i64"]);
+        assert_eq!(tokens("1.5f32 2f64"), vec!["1.5f32", "2f64"]);
+    }
+
+    #[test]
+    fn lexer_errors() {
+        let lex_error = |l: &str| {
+            let err = Lexer::new(l).find_map(Result::err).unwrap();
+            let span = err.span();
+            format!("{err} @ {}", &l[span.start..span.end])
+        };
+        assert_eq!(lex_error("a = 1 # 2"), "Unexpected character `#` @ #");
+        assert_eq!(lex_error("a /* b"), "Block comment is never closed @ /* b");
+        assert_eq!(lex_error("a = \"b\""), "Unexpected character `\"` @ \"");
+    }
+
+    /// Run with `cargo test --release lexer_speed -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn lexer_speed() {
+        use lalrpop_util::lexer::MatcherBuilder;
+        use std::time::Instant;
+
+        let source = (0..20_000)
+            .map(|i| {
+                format!(
+                    "tag Counter{i} {{
+                        state count: Int = 0
+                        increment(by: Int, scale: Float) -> Int {{
+                            let mut next = count + by * 0x10;
+                            if next >= 1.5e3 {{ next = -1 }} else {{ next = next / 2 }}
+                            count = next as Int;
+                            next
+                        }}
+                        view() -> Tag {{ <Text size=count weight=-2/> }}
+                    }}
+                    "
+                )
+            })
+            .collect::<String>();
+
+        // The terminals of the grammar before it had its own lexer
+        let literals = [
+            "tag", "export", "state", "let", "mut", "if", "else", "as", r"\(", r"\)", r"\{", r"\}",
+            "<", ">", "</", "/>", "=", "==", "!=", "<=", ">=", r"\+", "-", r"\*", "/", ",", ";",
+            ":", "->",
+        ];
+        let patterns = [
+            r"(\+|\-)?[0-9]+",
+            r"0x([0-9a-fA-F])+",
+            r"(\+|\-)0x([0-9a-fA-F])+",
+            r"(\+|\-)?[0-9]+\.[0-9]+((e|E)[0-9]+)?",
+            r"(\+|\-)?[0-9]+(e|E)[0-9]+",
+            r"([a-zA-Z]|_)([a-zA-Z0-9]|_)*",
+        ];
+        let matcher = MatcherBuilder::new(
+            literals
+                .iter()
+                .chain(&patterns)
+                .map(|regex| (format!("^({regex})"), false))
+                .chain([(r"^(\s*)".to_string(), true)]),
+        )
+        .unwrap();
+
+        let start = Instant::now();
+        let regex_tokens = matcher.matcher::<()>(&source).map(Result::unwrap).count();
+        let regex_time = start.elapsed();
+
+        let start = Instant::now();
+        let tokens = Lexer::new(&source).map(Result::unwrap).count();
+        let time = start.elapsed();
+
+        assert_eq!(tokens, regex_tokens);
+        println!(
+            "{} bytes, {tokens} tokens: regex matcher {regex_time:?}, lexer {time:?} ({:.0}x)",
+            source.len(),
+            regex_time.as_secs_f64() / time.as_secs_f64()
+        );
+        assert!(time < regex_time);
+    }
+
+    #[test]
+    fn comments_and_text() {
+        parse_compilation_unit_expect(
+            "
+            // Shown on the home page
+            export Home {
+                /* <Image/> */
+                <Column>Welcome back! <Text/> Have fun</Column>
+            }
+            ",
+            "export Home {\n <Column> Welcome back! <Text/> Have fun </Column> }\n",
+        );
+        parse_statement_expect("a-1 < b", "((a - 1) < b)");
+    }
+
     #[test]
     fn function_call() {
         parse_statement_expect_same("test_function(one, two)");
@@ -207,6 +510,14 @@ mod tests {
         parse_statement_expect_same("let this = 3");
     }
 
+    #[test]
+    fn let_type_annotation() {
+        parse_statement_expect_same("let this: Int = 3");
+        parse_statement_expect_same("let this: Float");
+        parse_statement_expect_same("let mut this: Int = 3");
+        parse_statement_expect_same("let mut this");
+    }
+
     #[test]
     fn compilation_unit() {
         parse_compilation_unit_expect(
@@ -245,97 +556,2170 @@ mod tests {
         );
     }
 
-    /// `parse_statement_expect(l, l);`
-    fn parse_compilation_unit_expect_same(l: &str) {
-        parse_compilation_unit_expect(l, l);
+    #[test]
+    fn parse_tag_def_props() {
+        parse_compilation_unit_expect(
+            "
+        tag Button {
+            prop label: Int
+            state clicks: Int
+            prop disabled: Bool = false
+            view() -> Tag {
+            }
+        }",
+            "tag Button {\nprop label: Int\nprop disabled: Bool = false\nstate clicks: Int\n\nview() -> Tag { \n }\n}",
+        );
     }
 
-    fn parse_compilation_unit_expect(l: &str, r: &str) {
-        println!("Testing that parsed \"{}\" == \"{}\"", l, r);
-        let mut e = vec![];
-        let expr = main_parser::CompilationUnitParser::new().parse(&mut e, l);
-        println!("{:?} with error vec {:?}", expr, e);
-        assert!(expr.is_ok());
-        assert_eq!(&format!("{:?}", expr.unwrap()), r);
+    #[test]
+    fn validate_props() {
+        let errors = validate_compilation_unit(
+            "
+            tag Toggle {
+                prop label: Int
+                prop disabled: Bool = false
+                view() -> Tag { let root = (<Text/>) }
+            }
+            export Main { <Toggle label=3/> <Toggle label=4 disabled=true/> }
+            ",
+        );
+        assert_eq!(errors, vec![]);
+
+        // Only type checking reports the unknown type
+        let errors = validate_compilation_unit(
+            "
+            tag Label { prop width: Pixels view() -> Tag { let root = (<Text/>); root } }
+            export Main { <Label width=1/> }
+            ",
+        );
+        assert_eq!(errors, vec![]);
     }
 
-    /// `parse_statement_expect(l, l);`
-    fn parse_statement_expect_same(l: &str) {
-        parse_statement_expect(l, l);
+    #[test]
+    fn validate_props_errors() {
+        let errors = validate_compilation_unit(
+            "
+            tag Toggle {
+                prop label: Int
+                prop disabled: Bool = false
+                view() -> Tag { let root = (<Text/>) }
+            }
+            export Main { <Toggle size=3/> <Toggle label=2.5 label=1/> }
+            ",
+        );
+        let errors = errors
+            .iter()
+            .map(|err| format!("{err}"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                "Tag `Toggle` has no property `size`",
+                "Tag `Toggle` is missing required property `label`",
+                "Property `label` of tag `Toggle` is declared as Int but was given Float",
+                "Property `label` was passed to tag `Toggle` more than once",
+            ]
+        );
     }
 
-    /// Convenience func, this parses l and makes sure it's string representation equals r
-    fn parse_statement_expect(l: &str, r: &str) {
-        println!("Testing that parsed \"{}\" == \"{}\"", l, r);
-        let mut e = vec![];
-        let expr = main_parser::StatementParser::new().parse(&mut e, l);
-        println!("{:?} with error vec {:?}", expr, e);
-        assert!(expr.is_ok());
-        assert_eq!(&format!("{:?}", expr.unwrap()), r);
+    #[test]
+    fn validate_props_in_view() {
+        let errors = validate_compilation_unit(
+            "
+            tag Icon { prop size: Int view() -> Tag { let root = (<Image/>) } }
+            tag Toggle { view() -> Tag { let root = (<Row> <Icon size=true/> </Row>) } }
+            ",
+        );
+        assert!(matches!(
+            errors.as_slice(),
+            [validate::ValidationError::PropTypeMismatch(..)]
+        ));
     }
 
     #[test]
-    fn wasm_let_binding() {
-        let mut e = vec![];
+    fn validate_tag_defs_and_exports() {
+        let source = "
+            tag Card { prop title: Int }
+            tag Panel { view(size: Int) { } }
+            tag Card { view() -> Int { } }
+            export Main { <Card title=1/> }
+            export Main { <Column> <Missing/> </Column> }
+            export memory { <Text/> }
+            tag Row { view() -> Tag { let root = (<Column/>) } }
+            ";
+        let errors = validate_compilation_unit(source)
+            .iter()
+            .map(|err| {
+                let span = err.span();
+                format!("{err} @ {}", &source[span.start..span.end])
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                "Tag `Card` has no `view()` function @ Card",
+                "`view()` of tag `Panel` can't take arguments, use a `prop` instead @ size",
+                "`view()` of tag `Panel` must return Tag @ view",
+                "Tag `Card` is defined more than once @ Card",
+                "`view()` of tag `Card` must return Tag @ Int",
+                "`Main` is exported more than once @ Main",
+                "`memory` can't be exported, the module exports its memory under that name @ memory",
+                "Tag `Row` is already a built-in tag @ Row",
+                "Tag `Missing` is neither defined nor a built-in tag @ Missing",
+            ]
+        );
+    }
 
-        if !e.is_empty() {
-            panic!("{:?}", e);
-        }
+    #[test]
+    fn resolve_undefined_names() {
+        let errors = resolve_compilation_unit(
+            "
+            view() -> Tag { let a = b; missing(a); helper(a) }
+            helper(x: Int) { x }
+            ",
+        )
+        .1;
+        assert_eq!(
+            errors
+                .iter()
+                .map(|err| format!("{err}"))
+                .collect::<Vec<_>>(),
+            vec![
+                "Cannot find `b` in this scope",
+                "Cannot find function `missing` in this scope",
+            ]
+        );
+    }
 
-        let statement = main_parser::StatementParser::new().parse(
-            &mut e,
-            r#"
-            {   let a = test_struct;
-                a.field_1 = test_struct;
+    #[test]
+    fn resolve_block_scopes() {
+        let source = "f(x: Int) { let y = x; { let x = y; let inner = x }; inner; x = y }";
+        let unit = parse_compilation_unit(source);
+        let (resolution, errors) = resolve::resolve(&unit);
+        assert_eq!(
+            errors,
+            vec![resolve::ResolveError::UndefinedName(
+                "inner".into(),
+                ast::Span::new(53, 58)
+            )]
+        );
+
+        let mut refs = resolution
+            .refs
+            .iter()
+            .map(|(ident, id)| (unit[*ident].1.start, resolution.def(*id)))
+            .collect::<Vec<_>>();
+        refs.sort_by_key(|(start, _)| *start);
+        let refs = refs
+            .iter()
+            .map(|(start, def)| {
+                format!("{}@{start} -> {:?}@{}", def.name, def.kind, def.span.start)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            refs,
+            vec![
+                "x@20 -> Arg@2",
+                "y@33 -> Local@16",
+                "x@48 -> Local@29",
+                "x@60 -> Arg@2",
+                "y@64 -> Local@16",
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_tag_scope() {
+        let (_, errors) = resolve_compilation_unit(
+            "
+            tag Counter {
+                prop start: Int
+                state count: Int = start
+                state doubled = double(count)
+                view() -> Tag { increment(); let root = (<Text value=count/>) }
+                increment() { count = count + 1 }
             }
-            "#,
+            double(x: Int) -> Int { x * 2 }
+            ",
         );
+        assert_eq!(errors, vec![]);
 
-        let mut module = walrus::Module::default();
+        let (_, errors) = resolve_compilation_unit("f(a: Int, a: Int) { } f() { count }");
+        assert_eq!(
+            errors
+                .iter()
+                .map(|err| format!("{err}"))
+                .collect::<Vec<_>>(),
+            vec![
+                "`f` is defined more than once in the same scope",
+                "`a` is defined more than once in the same scope",
+                "Cannot find `count` in this scope",
+            ]
+        );
 
-        let module_locals = &mut module.locals;
+        // A default only sees the members before it, and no tag functions
+        let (_, errors) = resolve_compilation_unit(
+            "
+            tag T {
+                prop p: Int = q
+                prop q: Int = 1
+                state b = a
+                state a: Int = 1
+                state c = get()
+                state d: Int = d
+                get() -> Int { a }
+                view() -> Tag { let root = (<Text value=a/>); root }
+            }
+            ",
+        );
+        assert_eq!(
+            errors
+                .iter()
+                .map(|err| format!("{err}"))
+                .collect::<Vec<_>>(),
+            vec![
+                "Cannot find `q` in this scope",
+                "Cannot find `a` in this scope",
+                "Cannot find function `get` in this scope",
+                "Cannot find `d` in this scope",
+            ]
+        );
+    }
 
-        let mut func = FunctionBuilder::new(&mut module.types, &[], &[ValType::I64]);
+    #[test]
+    fn typeck_inference() {
+        let errors = typeck_compilation_unit(
+            "
+            tag Counter {
+                prop step: Int = 1
+                state count: Int = 0
+                state ratio = 2.5
+                view() -> Tag { let root = (<Text value=count/>); root }
+                increment() { count = count + step; ratio = ratio * 2.0 }
+            }
+            add(a: Int, b: Int) -> Int { let sum = a + b; sum }
+            main() -> Bool { let x; x = add(1, 2); { let y = x; y } > 3 }
+            ",
+        );
+        assert_eq!(errors, Vec::<String>::new());
+    }
 
-        let mut func_locals = LocalMap {
-            names: Default::default(),
-        };
+    #[test]
+    fn typeck_types_by_id() {
+        let source = "f(a: U8) { let b = a as Int; b * 2 }";
+        // The unit doesn't borrow the source, so it can outlive it and move between threads
+        let unit = std::thread::spawn(move || parse_compilation_unit(source))
+            .join()
+            .unwrap();
+        let (resolution, _) = resolve::resolve(&unit);
+        let (results, errors) = typeck::typeck(&unit, &resolution);
+        assert!(errors.is_empty());
 
-        let mut test_struct_def = LinkedHashMap::new();
+        let mut types = unit
+            .statements()
+            .filter_map(|(id, _)| {
+                let span = unit.span(id)?;
+                Some((
+                    span.start,
+                    format!(
+                        "{}: {}",
+                        &source[span.start..span.end],
+                        results.types.get(&id)?
+                    ),
+                ))
+            })
+            .collect::<Vec<_>>();
+        types.sort();
+        let types = types.into_iter().map(|(_, ty)| ty).collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            vec![
+                "b = a as Int: ()",
+                "a as Int: Int",
+                "a: U8",
+                "b * 2: Int",
+                "b: Int",
+                "2: Int",
+            ]
+        );
+    }
 
-        (0..5).for_each(|index| {
-            test_struct_def.insert(
-                format!("field_{}", index),
-                ChipType::Primitive(Primitive::I64),
-            );
+    #[test]
+    fn typeck_errors() {
+        let errors = typeck_compilation_unit(
+            "
+            tag Card {
+                prop title: Bool
+                state count: Int = 1.5
+                view() -> Tag { let r = (<Text/>); r }
+            }
+            add(a: Int, b: Int) -> Int { a + b }
+            f(flag: Bool) -> Float {
+                add(1);
+                add(1, 2.0);
+                let mut x = 1;
+                x = 2.0;
+                1 + 2.5;
+                if x { };
+                let r = (<Card title=x/>);
+                add = 1;
+                x
+            }
+            g(size: Pixels) { }
+            ",
+        );
+        assert_eq!(
+            errors,
+            vec![
+                "Unknown type `Pixels` @ Pixels",
+                "Expected Int, found Float @ 1.5",
+                "`add` takes 2 argument(s) but 1 were given @ add",
+                "Expected Int, found Float @ 2.0",
+                "Expected Int, found Float @ 2.0",
+                "Cannot apply `+` to Int and Float @ 1 + 2.5",
+                "Expected Bool, found Int @ x",
+                "Expected Bool, found Int @ title",
+                "`add` cannot be assigned to @ add",
+                "Expected Float, found Int @ x",
+            ]
+        );
+    }
 
-            let test_field_local = module_locals.add(ValType::I64);
+    #[test]
+    fn typeck_let_annotations() {
+        let errors = typeck_compilation_unit(
+            "
+            f(c: Bool) -> Int {
+                let a: Float = 1;
+                let b: Pixels;
+                let x: Int;
+                let y;
+                let z;
+                x + 1;
+                if c { y = 1 } else { y = 2 };
+                if c { z = 1 };
+                { x = 2 };
+                x + y + z
+            }
+            ",
+        );
+        assert_eq!(
+            errors,
+            vec![
+                "Expected Float, found Int @ 1",
+                "Unknown type `Pixels` @ Pixels",
+                "`x` is used before it is assigned a value @ x",
+                "`z` is used before it is assigned a value @ z",
+            ]
+        );
+    }
 
-            func_locals.names.insert(
-                format!("test_struct.field_{}", index),
-                (Some(test_field_local), ChipType::Primitive(Primitive::I64)),
-            );
-        });
+    #[test]
+    fn typeck_mutability() {
+        let errors = typeck_compilation_unit(
+            "
+            tag Counter {
+                prop step: Int
+                state count: Int = 0
+                view() -> Tag {
+                    count = 1;
+                    let mut label = count;
+                    label = 2;
+                    let size = total();
+                    let root = (<Text value=label size=size/>);
+                    root
+                }
+                total() -> Int { reset(); count }
+                reset() { count = 0; total() }
+                increment(by: Int) {
+                    count = count + by;
+                    by = 2;
+                    step = 3;
+                    let total = count;
+                    total = 4;
+                    let once;
+                    once = 5;
+                    let twice;
+                    if true { twice = 6 };
+                    twice = 7;
+                    let mut again;
+                    again = 8;
+                    again = 9
+                }
+            }
+            ",
+        );
+        assert_eq!(
+            errors,
+            vec![
+                "`twice` might already be assigned, declare it with `let mut` to assign it again @ twice",
+                "Cannot assign to state `count` inside of view() @ count",
+                "Cannot assign to state `count` inside of reset(), which is called by view() @ count",
+                "Cannot assign to argument `by` @ by",
+                "Cannot assign to prop `step`, props are set by the parent tag @ step",
+                "Cannot assign twice to immutable binding `total`, declare it with `let mut` @ total",
+            ]
+        );
+    }
 
-        func_locals.names.insert(
-            "test_struct".into(),
-            (None, ChipType::Struct(Rc::new(test_struct_def))),
+    #[test]
+    fn typeck_casts() {
+        let errors = typeck_compilation_unit(
+            "
+            f(x: Float, flag: Bool) -> U8 {
+                let a: I32 = 3i32;
+                let b: U64 = a as U64 + 1u64;
+                let c = 1.5f32 + 2;
+                let d = flag as Int;
+                let e = x as Pixels;
+                let sum = b as Float + x;
+                sum as U8
+            }
+            ",
         );
+        assert_eq!(
+            errors,
+            vec![
+                "Cannot apply `+` to F32 and Int @ 1.5f32 + 2",
+                "Cannot cast Bool as Int @ flag as Int",
+                "Unknown type `Pixels` @ Pixels",
+            ]
+        );
+    }
 
-        compile_statement_wasm(
-            &mut func.func_body(),
-            &mut func_locals,
-            module_locals,
-            &statement.unwrap(),
+    const CST_SOURCE: &str = "
+        // Adds things
+        add(a: Int, b: Int,) -> Int {
+            let mut sum = (a + b) ; /* inline */ sum = sum * 2 ;
+            if sum > 10 { sum - 10 } else { sum as Int }
+        }
+
+        tag Counter {
+            prop step: Int = 1
+            state count = 0
+            view() -> Tag {
+                let root = ( <Row gap=4>Count: <Text size=12/></Row> );
+                root
+            }
+        }
+
+        export Home { <Counter step=2/> }
+    ";
+
+    #[test]
+    fn cst_round_trip() {
+        let sources = [
+            CST_SOURCE,
+            "",
+            "  // only a comment\n",
+            "f( { let = ; } tag {",
+            "x() { 1 + # 2 } /* never closed",
+            "export E { <Row>text</Column> <Text",
+        ];
+        for source in sources {
+            let parse = cst::parse(source);
+            assert_eq!(parse.syntax().to_string(), source);
+        }
+    }
+
+    #[test]
+    fn cst_lowering() {
+        let parse = cst::parse(CST_SOURCE);
+        assert!(parse.errors.is_empty(), "{:?}", parse.errors);
+        let (unit, errors) = cst::lower(CST_SOURCE, &parse.syntax());
+        assert!(errors.is_empty());
+
+        let expected = parse_compilation_unit(CST_SOURCE);
+        assert_eq!(format!("{:?}", unit), format!("{:?}", expected));
+    }
+
+    #[test]
+    fn cst_tree() {
+        let parse = cst::parse("f() { -1 + x } // done");
+        assert_eq!(
+            format!("{:?}", parse.syntax()),
+            r#"CompilationUnit@0..22
+  FunctionDef@0..14
+    Ident@0..1 "f"
+    ArgDefList@1..3
+      LParen@1..2 "("
+      RParen@2..3 ")"
+    Whitespace@3..4 " "
+    Block@4..14
+      LBrace@4..5 "{"
+      Whitespace@5..6 " "
+      BinaryExpr@6..12
+        Literal@6..8
+          Num@6..8 "-1"
+        Whitespace@8..9 " "
+        Plus@9..10 "+"
+        Whitespace@10..11 " "
+        NameRef@11..12
+          Ident@11..12 "x"
+      Whitespace@12..13 " "
+      RBrace@13..14 "}"
+  Whitespace@14..15 " "
+  Comment@15..22 "// done"
+"#
         );
+    }
 
-        dbg!(
-            "{:?}",
-            func.func_body()
-                .instrs()
-                .iter()
-                .map(|t| &t.0)
-                .collect::<Vec<_>>()
+    #[test]
+    fn cst_errors() {
+        let source = "f() { let = 1 } export E { <Row></Column> } g() { 256u8 }";
+        let parse = cst::parse(source);
+        let (_, lower_errors) = cst::lower(source, &parse.syntax());
+        let errors = parse
+            .errors
+            .iter()
+            .chain(&lower_errors)
+            .map(|err| {
+                let span = err.span();
+                format!("{err} @ {}", &source[span.start..span.end])
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                "Expected a name, found `=` @ =",
+                "Tag was closed incorrectly: Row != Column @ Column",
+                "Integer literal was too large (couldn't fit into u8): 256u8 @ 256u8",
+            ]
+        );
+    }
+
+    #[test]
+    fn fmt_layout() {
+        assert_eq!(
+            format_source(CST_SOURCE).unwrap(),
+            "// Adds things
+add(a: Int, b: Int) -> Int {
+    let mut sum = (a + b); /* inline */
+    sum = sum * 2;
+    if sum > 10 {
+        sum - 10
+    } else {
+        sum as Int
+    }
+}
+
+tag Counter {
+    prop step: Int = 1
+    state count = 0
+
+    view() -> Tag {
+        let root = (<Row gap=4>Count: <Text size=12/></Row>);
+        root
+    }
+}
+
+export Home {
+    <Counter step=2/>
+}
+"
         );
     }
+
+    #[test]
+    fn fmt_long_tags() {
+        let source = "export Home { <Column spacing=10>Welcome back to the application, this line is long! \
+            <Row><Text size=12/><Image width=100 height=200/></Row> </Column> }
+            f() { let x = (<Image first_property=1000000 second_property=2000000 third_property=3000000 fourth_property=4000000/>) }";
+        assert_eq!(
+            format_source(source).unwrap(),
+            "export Home {
+    <Column spacing=10>
+        Welcome back to the application, this line is long!
+        <Row><Text size=12/> <Image width=100 height=200/></Row>
+    </Column>
+}
+
+f() {
+    let x = (
+        <Image
+            first_property=1000000
+            second_property=2000000
+            third_property=3000000
+            fourth_property=4000000
+        />
+    )
+}
+"
+        );
+    }
+
+    #[test]
+    fn fmt_idempotent() {
+        let sources = [
+            CST_SOURCE,
+            "",
+            "  // only a comment\n",
+            "f(){1;2}tag T{}export E{<A/>}",
+            "f() { // start\n  let a = /* a */ 1 // one\n\n\n  a }\n// end",
+            "export E { <Row // gap\n gap=1>text <Text/>\n\n more</Row> }",
+        ];
+        for source in sources {
+            let formatted = format_source(source).unwrap();
+            assert_eq!(format_source(&formatted).unwrap(), formatted, "{}", source);
+
+            // Only the layout changes
+            let lower =
+                |source| format!("{:?}", cst::lower(source, &cst::parse(source).syntax()).0);
+            assert_eq!(lower(&formatted), lower(source));
+            let comments = |source: &str| {
+                cst::parse(source)
+                    .syntax()
+                    .to_string()
+                    .matches("//")
+                    .count()
+            };
+            assert_eq!(comments(&formatted), comments(source));
+        }
+    }
+
+    #[test]
+    fn fmt_syntax_errors() {
+        let errors = format_source("f() { let = 1 }").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "Expected a name, found `=`");
+    }
+
+    #[test]
+    fn debug_empty_lists() {
+        let unit = ast::CompilationUnit::default();
+        assert_eq!(format!("{:?}", unit.with(&ast::IdentList(vec![]))), "");
+        assert_eq!(format!("{:?}", unit.with(&ast::TagList(vec![]))), " ");
+    }
+
+    #[test]
+    fn display_statements() {
+        let cases = [
+            ("a.field_1 = 1 + 2 * 3", "a.field_1 = 1 + 2 * 3"),
+            (
+                "let mut x: U8 = (<Row gap=4>Count: <Text/></Row>)",
+                "let mut x: U8 = (<Row gap=4>Count: <Text/></Row>)",
+            ),
+            ("if a > b + c {1;} else {}", "if a > b + c { 1 } else {}"),
+            (
+                "f(1.0, 2e300f64, 0x10u64, -1 as U8)",
+                "f(1.0, 2e300f64, 16u64, -1 as U8)",
+            ),
+            ("a - b - c * d / e as Int", "a - b - c * d / e as Int"),
+        ];
+        for (source, expected) in cases {
+            let (unit, statement) = parse_statement(source);
+            assert_eq!(unit.with(&statement).to_string(), expected);
+        }
+        let mut unit = ast::CompilationUnit::default();
+        let mut a = || {
+            let ident = unit.alloc_ident("a", ast::Span::default());
+            unit.alloc(ast::Statement::Ident(ident))
+        };
+        let (l, m, r) = (a(), a(), a());
+        let sum = unit.alloc(ast::Statement::Op(m, ast::Opcode::Add, r));
+        let right_nested = unit.alloc(ast::Statement::Op(l, ast::Opcode::Mul, sum));
+        assert_eq!(unit.with(&right_nested).to_string(), "a * (a + a)");
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for seed in 1..=500 {
+            let unit = AstGen::new(seed).compilation_unit();
+            let source = unit.to_string();
+            let mut e = vec![];
+            let mut parsed = ast::CompilationUnit::default();
+            let result = main_parser::CompilationUnitParser::new().parse(
+                &mut e,
+                &mut parsed,
+                Lexer::new(&source),
+            );
+            assert!(e.is_empty(), "{:?} in {}", e, source);
+            assert!(result.is_ok(), "{:?} in {}", result, source);
+            assert_eq!(parsed, unit, "{}", source);
+
+            let (lowered, errors) = cst::lower(&source, &cst::parse(&source).syntax());
+            assert!(errors.is_empty(), "{:?} in {}", errors, source);
+            assert_eq!(lowered, unit, "{}", source);
+        }
+    }
+
+    fn parse_compilation_unit(l: &str) -> ast::CompilationUnit {
+        let mut e = vec![];
+        let mut unit = ast::CompilationUnit::default();
+        main_parser::CompilationUnitParser::new()
+            .parse(&mut e, &mut unit, Lexer::new(l))
+            .unwrap();
+        assert!(e.is_empty());
+        unit
+    }
+
+    fn parse_statement(l: &str) -> (ast::CompilationUnit, ast::StmtId) {
+        let mut e = vec![];
+        let mut unit = ast::CompilationUnit::default();
+        let statement = main_parser::StatementParser::new()
+            .parse(&mut e, &mut unit, Lexer::new(l))
+            .unwrap();
+        (unit, statement)
+    }
+
+    #[test]
+    fn visitor_walks_everything() {
+        /// Collects every call and tag name in the order they are visited
+        struct Names<'u>(Vec<&'u str>);
+
+        impl<'u> visit::Visitor<'u> for Names<'u> {
+            fn visit_statement(&mut self, unit: &'u ast::CompilationUnit, id: ast::StmtId) {
+                if let ast::Statement::FunctionCall(call) = &unit[id] {
+                    self.0.push(&unit[call.ident].0);
+                }
+                visit::walk_statement(self, unit, id);
+            }
+
+            fn visit_tag(&mut self, unit: &'u ast::CompilationUnit, tag: &'u ast::Tag) {
+                self.0.push(&unit[tag.ident].0);
+                visit::walk_tag(self, unit, tag);
+            }
+        }
+
+        let unit = parse_compilation_unit(
+            "
+            tag Card {
+                state count = f(1)
+                view() -> Tag { if g(count) > 0 { let root = (<Row><Text value=count/></Row>); root } else { h() } }
+            }
+            export Main { <Card/> }
+            main() { let x = k(a as Int); x = j(i()) }
+            ",
+        );
+        let mut names = Names(vec![]);
+        visit::Visitor::visit_compilation_unit(&mut names, &unit);
+        assert_eq!(
+            names.0,
+            vec!["f", "g", "Row", "Text", "h", "Card", "k", "j", "i"]
+        );
+    }
+
+    #[test]
+    fn visitor_mut_rewrites() {
+        /// Folds additions and multiplications of integer literals, innermost first
+        struct Fold;
+
+        impl visit::VisitorMut for Fold {
+            fn visit_statement_mut(&mut self, unit: &mut ast::CompilationUnit, id: ast::StmtId) {
+                visit::walk_statement_mut(self, unit, id);
+                if let ast::Statement::Op(l, op, r) = &unit[id] {
+                    let value = match (&unit[*l], op, &unit[*r]) {
+                        (
+                            ast::Statement::Number(ast::Number::Int(l, None), _),
+                            ast::Opcode::Add,
+                            ast::Statement::Number(ast::Number::Int(r, None), _),
+                        ) => l + r,
+                        (
+                            ast::Statement::Number(ast::Number::Int(l, None), _),
+                            ast::Opcode::Mul,
+                            ast::Statement::Number(ast::Number::Int(r, None), _),
+                        ) => l * r,
+                        _ => return,
+                    };
+                    let span = unit.span(id).unwrap_or_default();
+                    unit[id] = ast::Statement::Number(ast::Number::Int(value, None), span);
+                }
+            }
+        }
+
+        let mut unit = parse_compilation_unit(
+            "f() -> Int { g(1 + 2 * 3, x + 4) } export Main { <Text value=x/> }",
+        );
+        visit::VisitorMut::visit_compilation_unit_mut(&mut Fold, &mut unit);
+        assert_eq!(
+            unit,
+            parse_compilation_unit("f() -> Int { g(7, x + 4) } export Main { <Text value=x/> }")
+        );
+    }
+
+    #[test]
+    fn ast_json() {
+        let source = "f(a: U8) -> U64 { a; 18446744073709551615u64 }";
+        let unit = parse_compilation_unit(source);
+        let (resolution, types, errors) = compile::analyze(&unit);
+        let dump = json::unit_json(&unit, &resolution, &types, &errors).to_string();
+        let defs = &dump[..dump.find(r#","definitions""#).unwrap()];
+        assert_eq!(
+            defs,
+            concat!(
+                r#"{"kind":"CompilationUnit","defs":[{"kind":"FunctionDef","#,
+                r#""name":{"kind":"Ident","name":"f","span":{"start":0,"end":1},"def":2},"#,
+                r#""args":[{"kind":"ArgDef","#,
+                r#""name":{"kind":"Ident","name":"a","span":{"start":2,"end":3},"def":3},"#,
+                r#""annotation":{"kind":"Ident","name":"U8","span":{"start":5,"end":7},"def":null}}],"#,
+                r#""return_type":{"kind":"Ident","name":"U64","span":{"start":12,"end":15},"def":null},"#,
+                r#""block":[{"kind":"Ident","id":0,"span":{"start":18,"end":19},"type":"U8","#,
+                r#""name":{"kind":"Ident","name":"a","span":{"start":18,"end":19},"def":3}},"#,
+                r#"{"kind":"Number","id":1,"span":{"start":21,"end":44},"type":"U64","#,
+                r#""value":18446744073709551615,"suffix":"u64"}]}]"#,
+            )
+        );
+        assert!(dump.ends_with(r#""errors":[]}"#), "{dump}");
+
+        let escaped = json::Json::String("<Text>\"a\\b\"\n\u{1}</Text>".into());
+        assert_eq!(escaped.to_string(), r#""<Text>\"a\\b\"\n\u0001</Text>""#);
+        let nested = json::Json::Object(vec![
+            ("empty", json::Json::Array(vec![])),
+            (
+                "list",
+                json::Json::Array(vec![json::Json::Null, json::Json::Bool(true)]),
+            ),
+        ]);
+        assert_eq!(
+            format!("{nested:#}"),
+            "{\n  \"empty\": [],\n  \"list\": [\n    null,\n    true\n  ]\n}"
+        );
+    }
+
+    #[test]
+    fn compile_in_memory() {
+        let source = "
+            tag Card { view() -> Tag { let root = (<Text/>); root } }
+            export Main { <Card/> }
+            add(a: Int, b: Int) -> Int { a + b }
+            ";
+        let output = compile_source("card.chip", source, &CompileOptions::default());
+        assert_eq!(output.diagnostics, vec![]);
+        let wasm = output.wasm.unwrap();
+        assert!(wasm.starts_with(b"\0asm"));
+        assert!(walrus::Module::from_buffer(&wasm).is_ok());
+        assert_eq!(
+            output.metadata,
+            Metadata {
+                name: "card.chip".into(),
+                functions: vec!["add".into()],
+                tags: vec!["Card".into()],
+                exports: vec!["Main".into()],
+            }
+        );
+
+        let options = CompileOptions { check_only: true };
+        assert_eq!(compile_source("card.chip", source, &options).wasm, None);
+    }
+
+    #[test]
+    fn compile_unit_to_module() {
+        let unit = parse_compilation_unit(
+            "
+            tag Card { view() -> Tag { let t: Tag; t = (<Text/>); t } }
+            export Main { <Card/> }
+            add(a: Int, b: Int) -> Int { let c = a; c + b }
+            ",
+        );
+        let mut module = compile_checked(&unit).unwrap();
+        let mut names = module
+            .funcs
+            .iter()
+            .filter_map(|fun| fun.name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "Card.view",
+                "Card::drop",
+                "Card::new",
+                "Main",
+                "add",
+                "alloc"
+            ]
+        );
+        let exports = module
+            .exports
+            .iter()
+            .map(|export| export.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            exports,
+            vec!["memory", "Card.view", "Card::new", "Card::drop", "Main"]
+        );
+        assert!(walrus::Module::from_buffer(&module.emit_wasm()).is_ok());
+
+        // Without tags nothing is imported
+        let unit = parse_compilation_unit("add(a: Int, b: Int) -> Int { a + b }");
+        let module = compile_checked(&unit).unwrap();
+        assert_eq!(module.imports.iter().count(), 0);
+        assert_eq!(module.exports.iter().count(), 0);
+    }
+
+    #[test]
+    fn compile_exports() {
+        let unit = parse_compilation_unit(
+            "
+            tag Counter {
+                state count: Int = 0
+                view() -> Tag { let root = (<Text value=count/>); root }
+                increment() { count = double(count) }
+            }
+            export Main { <Counter/> }
+            double(x: Int) -> Int { x * 2 }
+            ",
+        );
+        let module = compile_checked(&unit).unwrap();
+        let mut exports = module
+            .exports
+            .iter()
+            .map(|export| export.name.as_str())
+            .collect::<Vec<_>>();
+        exports.sort();
+        // Not `double`, `alloc` or `Counter::update_count`
+        assert_eq!(
+            exports,
+            vec![
+                "Counter.increment",
+                "Counter.view",
+                "Counter::drop",
+                "Counter::new",
+                "Main",
+                "memory"
+            ]
+        );
+        assert!(module.funcs.by_name("double").is_some());
+        assert!(module.funcs.by_name("Counter::update_count").is_some());
+    }
+
+    #[test]
+    fn compile_in_memory_diagnostics() {
+        let describe = |source: &str| {
+            let output = compile_source("bad.chip", source, &CompileOptions::default());
+            assert_eq!(output.wasm, None);
+            output
+                .diagnostics
+                .iter()
+                .map(|diagnostic| {
+                    format!(
+                        "{}:{} {}",
+                        diagnostic.line, diagnostic.column, diagnostic.message
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            describe("f() {\n    let x: Bool = 1\n}"),
+            vec!["1:18 Expected Bool, found Int"]
+        );
+        assert_eq!(
+            describe("f() { 1 + }\ng() { }"),
+            vec![
+                "0:10 Unrecognized token `}` found at 10:11\nExpected one of \"ident\" or \"num\""
+            ]
+        );
+        assert_eq!(
+            describe("f("),
+            vec!["0:2 Unrecognized EOF found at 2\nExpected one of \")\" or \"ident\""]
+        );
+        for source in [
+            "f() -> U8 { 1.5u8 }",
+            "f() -> U8 { 1e2u8 }",
+            "f() -> Int { 0x1p3i64 }",
+        ] {
+            assert_eq!(describe(source).len(), 1, "{source}");
+        }
+
+        let output = compile_source("bad.chip", "f() { x }", &CompileOptions::default());
+        assert_eq!(
+            output.diagnostics[0].to_string(),
+            "\"bad.chip\":0:6: Cannot find `x` in this scope\n    f() { x }"
+        );
+    }
+
+    #[test]
+    fn compile_function_signatures() {
+        let unit = parse_compilation_unit(
+            "
+            mix(a: Int, b: Float, c: Bool) -> F32 { let d = b; d as F32 }
+            nothing() { 1 }
+            tag Card {
+                count() -> U8 { 1u8 }
+                view() -> Tag { let root = (<Text/>); root }
+            }
+            ",
+        );
+        let mut module = compile_checked(&unit).unwrap();
+        let signature = |name: &str| {
+            let ty = module.funcs.get(module.funcs.by_name(name).unwrap()).ty();
+            let ty = module.types.get(ty);
+            (ty.params().to_vec(), ty.results().to_vec())
+        };
+        assert_eq!(
+            signature("mix"),
+            (
+                vec![ValType::I64, ValType::F64, ValType::I32],
+                vec![ValType::F32]
+            )
+        );
+        assert_eq!(signature("nothing"), (vec![], vec![]));
+        assert_eq!(
+            signature("Card.count"),
+            (vec![ValType::I32], vec![ValType::I32])
+        );
+        assert!(walrus::Module::from_buffer(&module.emit_wasm()).is_ok());
+    }
+
+    #[test]
+    fn compile_calls() {
+        let unit = parse_compilation_unit(
+            "
+            main() -> Int { add(1, 2) }
+            add(a: Int, b: Int) -> Int { a + b }
+            countdown(n: Int) -> Int { countdown(n - 1) }
+            tag Card {
+                add(a: Int, b: Int) -> Int { a }
+                double(x: Int) -> Int { add(x, x) }
+                view() -> Tag { let root = (<Text/>); root }
+            }
+            ",
+        );
+        let mut module = compile_checked(&unit).unwrap();
+        let callees = |name: &str| {
+            let fun = module.funcs.get(module.funcs.by_name(name).unwrap());
+            let fun = fun.kind.unwrap_local();
+            fun.block(fun.entry_block())
+                .instrs
+                .iter()
+                .filter_map(|(instr, _)| match instr {
+                    walrus::ir::Instr::Call(call) => module.funcs.get(call.func).name.clone(),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(callees("main"), vec!["add"]);
+        assert_eq!(callees("countdown"), vec!["countdown"]);
+        assert_eq!(callees("Card.double"), vec!["Card.add"]);
+        assert!(walrus::Module::from_buffer(&module.emit_wasm()).is_ok());
+    }
+
+    #[test]
+    fn compile_if_else() {
+        let source = "
+            max(a: Int, b: Int) -> Int { if a > b { a } else { b } }
+            abs(a: Int) -> Int { let mut r = a; if a < 0 { r = 0 - a }; r }
+            not(b: Bool) -> Bool { b == false }
+            on() -> Bool { true }
+            ";
+        let output = compile_source("if.chip", source, &CompileOptions::default());
+        assert_eq!(output.diagnostics, vec![]);
+        let module = walrus::Module::from_buffer(&output.wasm.unwrap()).unwrap();
+        // `true` and `false` are constants
+        let consts = |name: &str| {
+            let fun = module.funcs.get(module.funcs.by_name(name).unwrap());
+            let fun = fun.kind.unwrap_local();
+            fun.block(fun.entry_block())
+                .instrs
+                .iter()
+                .filter_map(|(instr, _)| match instr {
+                    walrus::ir::Instr::Const(c) => Some(format!("{:?}", c.value)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(consts("not"), vec!["I32(0)"]);
+        assert_eq!(consts("on"), vec!["I32(1)"]);
+
+        // Only one branch has a value, so the `if` has none
+        let unit = parse_compilation_unit("f(a: Int) { if a > 0 { 1 } else { } }");
+        let mut module = compile_checked(&unit).unwrap();
+        assert!(walrus::Module::from_buffer(&module.emit_wasm()).is_ok());
+
+        let source = "f(a: Int) -> Int { if a > 0 { 1 } else { 1.0 } }";
+        let output = compile_source("if.chip", source, &CompileOptions::default());
+        let messages = output
+            .diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec!["`if` and `else` have different types: Int and Float"]
+        );
+    }
+
+    #[test]
+    fn wasm_codegen_errors() {
+        let error = |source: &str| {
+            let unit = parse_compilation_unit(source);
+            let err = compile_checked(&unit).unwrap_err();
+            (
+                err.to_string(),
+                source[err.span().start..err.span().end].to_string(),
+            )
+        };
+        assert_eq!(
+            error("f() { let x = g(); } g() { }"),
+            ("This statement must have a value".into(), "g".into())
+        );
+
+        assert_eq!(
+            error(
+                "tag Card { view() -> Tag { let t = (<Text/>); t } } export Main { <Card><Text/></Card> }"
+            ),
+            (
+                "Children of custom tags can't be compiled to wasm yet".into(),
+                "Card".into()
+            )
+        );
+        // There is no instance to keep it in
+        assert_eq!(
+            error(
+                "tag Card { view() -> Tag { let t = (<Text/>); t } } f() -> Tag { let t = (<Card/>); t }"
+            ),
+            (
+                "Custom tags outside of tags and exports can't be compiled to wasm yet".into(),
+                "Card".into()
+            )
+        );
+    }
+
+    #[test]
+    fn compile_tags_to_host_calls() {
+        let source = "
+            tag Card {
+                view() -> Tag {
+                    let size = 2;
+                    let root = (<Row gap=size><Text>Hello</Text>and<Text>Hello</Text></Row>);
+                    root
+                }
+            }
+            export Main { <Card/> <Text/> }
+            ";
+        let output = compile_source("card.chip", source, &CompileOptions::default());
+        assert_eq!(output.diagnostics, vec![]);
+        let module = walrus::Module::from_buffer(&output.wasm.unwrap()).unwrap();
+
+        let imports = module
+            .imports
+            .iter()
+            .map(|import| format!("{}.{}", import.module, import.name))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            imports,
+            vec![
+                "chirp.create_element",
+                "chirp.create_text",
+                "chirp.set_property",
+                "chirp.append_child",
+                "chirp.replace_element"
+            ]
+        );
+        // Every string is stored once
+        let data = module
+            .data
+            .iter()
+            .map(|data| &data.value)
+            .collect::<Vec<_>>();
+        assert_eq!(data, vec![b"RowgapandTextHello"]);
+
+        let calls = |name: &str| {
+            let fun = module.funcs.get(module.funcs.by_name(name).unwrap());
+            let fun = fun.kind.unwrap_local();
+            fun.block(fun.entry_block())
+                .instrs
+                .iter()
+                .filter_map(|(instr, _)| match instr {
+                    walrus::ir::Instr::Call(call) => match &module.funcs.get(call.func).kind {
+                        walrus::FunctionKind::Import(import) => {
+                            Some(module.imports.get(import.import).name.clone())
+                        }
+                        _ => module.funcs.get(call.func).name.clone(),
+                    },
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            calls("Card.view"),
+            vec![
+                "create_element",
+                "set_property",
+                "create_element",
+                "create_text",
+                "append_child",
+                "append_child",
+                "create_text",
+                "append_child",
+                "create_element",
+                "create_text",
+                "append_child",
+                "append_child"
+            ]
+        );
+        assert_eq!(
+            calls("Main"),
+            vec![
+                "Card::new",
+                "Card.view",
+                "append_child",
+                "create_element",
+                "append_child"
+            ]
+        );
+        // The instance of `<Card/>` is returned to the host
+        let main = module.funcs.get(module.funcs.by_name("Main").unwrap());
+        assert_eq!(module.types.params(main.ty()), &[ValType::I32]);
+        assert_eq!(module.types.results(main.ty()), &[ValType::I32]);
+    }
+
+    #[test]
+    fn compile_tag_instances() {
+        let unit = parse_compilation_unit(
+            "
+            tag Counter {
+                state count: Int = 0
+                state step = count + 2
+                view() -> Tag { let root = (<Text value=count/>); root }
+                increment() { count = count + step; reset() }
+                reset() { let mut count = 5; count = count + 1 }
+            }
+            export Main { <Counter/> <Counter/> }
+            ",
+        );
+        let mut module = compile_checked(&unit).unwrap();
+        assert!(walrus::Module::from_buffer(&module.emit_wasm()).is_ok());
+
+        let instrs = |name: &str| {
+            let fun = module.funcs.get(module.funcs.by_name(name).unwrap());
+            let fun = fun.kind.unwrap_local();
+            fun.block(fun.entry_block())
+                .instrs
+                .iter()
+                .map(|(instr, _)| instr.clone())
+                .collect::<Vec<_>>()
+        };
+        let count = |name: &str, pred: fn(&walrus::ir::Instr) -> bool| {
+            instrs(name).iter().filter(|instr| pred(instr)).count()
+        };
+        let is_load = |instr: &walrus::ir::Instr| matches!(instr, walrus::ir::Instr::Load(_));
+        let is_store = |instr: &walrus::ir::Instr| matches!(instr, walrus::ir::Instr::Store(_));
+        // Both states are read from and `count` is written to the instance
+        assert_eq!(count("Counter.increment", is_load), 2);
+        assert_eq!(count("Counter.increment", is_store), 1);
+        // The local `count` shadows the state
+        assert_eq!(count("Counter.reset", is_load), 0);
+        assert_eq!(count("Counter.reset", is_store), 0);
+        // `new` stores every default and clears the rendered flag
+        assert_eq!(count("Counter::new", is_store), 3);
+        assert_eq!(count("Counter::drop", is_store), 1);
+
+        // Every `<Counter/>` gets its own instance
+        let calls = instrs("Main")
+            .iter()
+            .filter_map(|instr| match instr {
+                walrus::ir::Instr::Call(call) => module.funcs.get(call.func).name.clone(),
+                _ => None,
+            })
+            .filter(|name| name.starts_with("Counter"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            calls,
+            vec![
+                "Counter::new",
+                "Counter.view",
+                "Counter::new",
+                "Counter.view"
+            ]
+        );
+        let main = module.funcs.get(module.funcs.by_name("Main").unwrap());
+        assert_eq!(module.types.results(main.ty()).len(), 2);
+
+        // The functions of a tag can have the names of the generated ones
+        let unit = parse_compilation_unit(
+            "
+            tag C {
+                state x: Int = 0
+                new() { x = 1 }
+                drop() { }
+                update_x() { }
+                view() -> Tag { let root = (<Text value=x/>); root }
+            }
+            export Main { <C/> }
+            ",
+        );
+        let module = compile_checked(&unit).unwrap();
+        assert!(module.exports.iter().any(|export| export.name == "C.new"));
+        assert!(module.exports.iter().any(|export| export.name == "C::new"));
+    }
+
+    #[test]
+    fn compile_child_instances() {
+        let unit = parse_compilation_unit(
+            "
+            tag Counter {
+                state count: Int = 0
+                view() -> Tag { let root = (<Text value=count/>); root }
+            }
+            tag Page {
+                view() -> Tag { let root = (<Column><Counter/><Counter/></Column>); root }
+            }
+            export Main { <Page/> }
+            ",
+        );
+        let mut module = compile_checked(&unit).unwrap();
+        assert!(walrus::Module::from_buffer(&module.emit_wasm()).is_ok());
+
+        // Every call of a defined function, including the ones in nested blocks
+        fn nested_calls(
+            fun: &walrus::LocalFunction,
+            seq: walrus::ir::InstrSeqId,
+            calls: &mut Vec<walrus::FunctionId>,
+        ) {
+            for (instr, _) in &fun.block(seq).instrs {
+                match instr {
+                    walrus::ir::Instr::Call(call) => calls.push(call.func),
+                    walrus::ir::Instr::IfElse(if_else) => {
+                        nested_calls(fun, if_else.consequent, calls);
+                        nested_calls(fun, if_else.alternative, calls);
+                    }
+                    walrus::ir::Instr::Block(block) => nested_calls(fun, block.seq, calls),
+                    _ => {}
+                }
+            }
+        }
+        let calls = |name: &str| {
+            let fun = module.funcs.get(module.funcs.by_name(name).unwrap());
+            let fun = fun.kind.unwrap_local();
+            let mut ids = vec![];
+            nested_calls(fun, fun.entry_block(), &mut ids);
+            let mut calls = ids
+                .into_iter()
+                .filter_map(|id| module.funcs.get(id).name.clone())
+                .collect::<Vec<_>>();
+            calls.sort();
+            calls
+        };
+        // The instances of `<Counter/>` are kept in the one of `Page`, rendering it
+        // again drops the previous ones
+        assert_eq!(
+            calls("Page.view"),
+            vec![
+                "Counter.view",
+                "Counter.view",
+                "Counter::drop",
+                "Counter::drop",
+                "Counter::new",
+                "Counter::new",
+            ]
+        );
+        assert_eq!(calls("Page::drop"), vec!["Counter::drop", "Counter::drop"]);
+        // Only the instance of `<Page/>` is returned
+        let main = module.funcs.get(module.funcs.by_name("Main").unwrap());
+        assert_eq!(module.types.results(main.ty()).len(), 1);
+    }
+
+    #[test]
+    fn compile_tag_props() {
+        let unit = parse_compilation_unit(
+            "
+            tag Card {
+                prop size: Int
+                prop gap: Float = size as Float * 2.0
+                state doubled = size * 2
+                view() -> Tag { let root = (<Row gap=gap><Text size=size/></Row>); root }
+            }
+            export Main { <Card size=1/> <Card size=2 gap=0.5/> }
+            ",
+        );
+        let mut module = compile_checked(&unit).unwrap();
+        assert!(walrus::Module::from_buffer(&module.emit_wasm()).is_ok());
+
+        // Each prop is a param, the optional one is followed by whether it was given
+        let new = module.funcs.get(module.funcs.by_name("Card::new").unwrap());
+        assert_eq!(
+            module.types.get(new.ty()).params(),
+            &[
+                walrus::ValType::I64,
+                walrus::ValType::F64,
+                walrus::ValType::I32
+            ]
+        );
+        // The props are read from the instance
+        let view = module.funcs.get(module.funcs.by_name("Card.view").unwrap());
+        let view = view.kind.unwrap_local();
+        let loads = view
+            .block(view.entry_block())
+            .instrs
+            .iter()
+            .filter(|(instr, _)| matches!(instr, walrus::ir::Instr::Load(_)))
+            .count();
+        assert_eq!(loads, 2);
+        // Props never change, so there is nothing to update
+        assert!(module.funcs.by_name("Card::update_size").is_none());
+    }
+
+    #[test]
+    fn compile_state_updates() {
+        let unit = parse_compilation_unit(
+            "
+            tag Counter {
+                state count: Int = 0
+                state label: Int = 7
+                state hidden: Int = 0
+                view() -> Tag {
+                    let root = (<Row gap=2><Text value=count/><Text value=label/><Text value=1/></Row>);
+                    root
+                }
+                increment() { count = count + 1; hidden = hidden + 1 }
+                relabel() { label = count }
+            }
+            export Main { <Counter/> }
+            ",
+        );
+        let mut module = compile_checked(&unit).unwrap();
+        assert!(walrus::Module::from_buffer(&module.emit_wasm()).is_ok());
+
+        let calls = |name: &str| {
+            let fun = module.funcs.get(module.funcs.by_name(name).unwrap());
+            let fun = fun.kind.unwrap_local();
+            fun.block(fun.entry_block())
+                .instrs
+                .iter()
+                .filter_map(|(instr, _)| match instr {
+                    walrus::ir::Instr::Call(call) => match &module.funcs.get(call.func).kind {
+                        walrus::FunctionKind::Import(import) => {
+                            Some(module.imports.get(import.import).name.clone())
+                        }
+                        _ => module.funcs.get(call.func).name.clone(),
+                    },
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        // Only the property that reads the state is set again
+        assert_eq!(calls("Counter::update_count"), vec!["set_property"]);
+        assert_eq!(calls("Counter::update_label"), vec!["set_property"]);
+        assert_eq!(module.funcs.by_name("Counter::update_hidden"), None);
+        assert_eq!(calls("Counter.increment"), vec!["Counter::update_count"]);
+        assert_eq!(calls("Counter.relabel"), vec!["Counter::update_label"]);
+
+        // Locals are evaluated again, branches and custom tags are built again on their own
+        let update = |source: &str| {
+            let mut module = compile_checked(&parse_compilation_unit(source)).unwrap();
+            assert!(walrus::Module::from_buffer(&module.emit_wasm()).is_ok());
+            let update = module.funcs.by_name("Counter::update_count").unwrap();
+            let fun = module.funcs.get(update).kind.unwrap_local();
+            let mut calls = vec![];
+            nested_calls(&module, fun, fun.entry_block(), &mut calls);
+            calls
+        };
+        assert_eq!(
+            update(
+                "tag Counter { state count: Int = 0\n view() -> Tag { let c = count; let d = c * 2; let root = (<Text value=d/>); root } }"
+            ),
+            vec!["set_property"]
+        );
+        assert_eq!(
+            update(
+                "tag Counter { state count: Int = 0\n view() -> Tag { if count > 1 { let row = (<Row/>); row } else { let text = (<Text/>); text } } }"
+            ),
+            vec!["create_element", "create_element", "replace_element"]
+        );
+        // Properties in such a branch are set when building it
+        assert_eq!(
+            update(
+                "tag Counter { state count: Int = 0\n state on: Bool = true\n view() -> Tag { let gap = count + 1; if on { let row = (<Row gap=gap/>); row } else { let text = (<Text/>); text } } }"
+            ),
+            vec![
+                "create_element",
+                "set_property",
+                "create_element",
+                "replace_element"
+            ]
+        );
+        // A custom tag gets a new instance with the new props
+        assert_eq!(
+            update(
+                "tag Label { prop value: Int\n view() -> Tag { let t = (<Text value=value/>); t } }\n tag Counter { state count: Int = 0\n view() -> Tag { let root = (<Row><Label value=count/></Row>); root } }"
+            ),
+            vec!["Label::new", "Label::drop", "Label.view", "replace_element"]
+        );
+
+        // Anything else that reads a state renders the view again and replaces its root
+        let replaced = vec!["Counter.view", "replace_element"];
+        assert_eq!(
+            update(
+                "tag Counter { state count: Int = 0\n view() -> Tag { let mut c = 0; c = count; let root = (<Text value=c/>); root } }"
+            ),
+            replaced
+        );
+        assert_eq!(
+            update(
+                "tag Counter { state count: Int = 0\n view() -> Tag { let mut root = (<Text/>); if count > 1 { root = (<Row/>) }; root } }"
+            ),
+            replaced
+        );
+    }
+
+    /// The functions that `seq` calls, including the ones in nested blocks
+    fn nested_calls(
+        module: &walrus::Module,
+        fun: &walrus::LocalFunction,
+        seq: walrus::ir::InstrSeqId,
+        calls: &mut Vec<String>,
+    ) {
+        for (instr, _) in &fun.block(seq).instrs {
+            match instr {
+                walrus::ir::Instr::Call(call) => {
+                    let name = match &module.funcs.get(call.func).kind {
+                        walrus::FunctionKind::Import(import) => {
+                            Some(module.imports.get(import.import).name.clone())
+                        }
+                        _ => module.funcs.get(call.func).name.clone(),
+                    };
+                    calls.extend(name);
+                }
+                walrus::ir::Instr::Block(block) => nested_calls(module, fun, block.seq, calls),
+                walrus::ir::Instr::IfElse(if_else) => {
+                    nested_calls(module, fun, if_else.consequent, calls);
+                    nested_calls(module, fun, if_else.alternative, calls);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn output_paths() {
+        let mut outputs = OutputPaths::new("project");
+        let output = |outputs: &OutputPaths, source: &str| {
+            outputs
+                .output_path(Path::new(source))
+                .map_err(|err| err.to_string())
+        };
+        assert_eq!(
+            output(&outputs, "project/ui/card.chip"),
+            Ok(PathBuf::from("project/out/ui/card.wasm"))
+        );
+        outputs.out_dir = "build".into();
+        assert_eq!(
+            output(&outputs, "project/main.chip"),
+            Ok(PathBuf::from("build/main.wasm"))
+        );
+        assert_eq!(
+            output(&outputs, "elsewhere/main.chip"),
+            Err(r#""elsewhere/main.chip" is not inside of "project""#.into())
+        );
+        outputs.out_dir = "project".into();
+        assert_eq!(
+            output(&outputs, "project/lib.wasm"),
+            Err(
+                r#"Output "project/lib.wasm" would overwrite the source file "project/lib.wasm""#
+                    .into()
+            )
+        );
+        assert_eq!(Target::from_extension("wasm"), Some(Target::Wasm));
+        assert_eq!(Target::from_extension("wat"), Some(Target::Wat));
+        assert_eq!(Target::from_extension("rs"), Some(Target::Rust));
+        assert_eq!(Target::from_extension("chip"), None);
+    }
+
+    #[test]
+    fn compile_folder_keeps_sources() {
+        let root = std::env::temp_dir().join(format!("chip-compile-{}", std::process::id()));
+        let source = "add(a: Int) -> Int { a + 1 }\n";
+        fs::create_dir_all(root.join("ui")).unwrap();
+        fs::write(root.join("ui/add.chip"), source).unwrap();
+
+        let mut outputs = OutputPaths::new(&root);
+        outputs.out_dir = root.join("build");
+        let result = compile_folder(&root, &outputs);
+        let written = fs::read(root.join("build/ui/add.wasm"));
+        let kept = fs::read_to_string(root.join("ui/add.chip"));
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(result.unwrap(), vec![root.join("build/ui/add.wasm")]);
+        assert!(written.unwrap().starts_with(b"\0asm"));
+        assert_eq!(kept.unwrap(), source);
+    }
+
+    #[test]
+    fn render_targets() {
+        let source = "
+            tag Card { view() -> Tag { let root = (<Text/>); root } }
+            export Main { <Card/> }
+            add(a: Int, b: Int) -> Int { if a > b { a } else { b + 1 } }
+            ";
+        let wasm = compile_source("card.chip", source, &CompileOptions::default())
+            .wasm
+            .unwrap();
+        assert_eq!(
+            Target::Wasm.render("card.chip", wasm.clone()).unwrap(),
+            wasm
+        );
+
+        let wat = String::from_utf8(Target::Wat.render("card.chip", wasm.clone()).unwrap());
+        let wat = wat.unwrap();
+        assert!(wat.starts_with("(module\n"), "{wat}");
+        assert!(wat.ends_with(")\n"), "{wat}");
+        for line in [
+            r#"  (import "chirp" "create_element" (func $chirp.create_element (param i32 i32) (result i32)))"#,
+            r#"  (export "Main" (func $Main))"#,
+            ") (result i64)\n    (local $l",
+            "    i64.gt_s\n    if $b1\n",
+            "    call $Card.view\n    call $chirp.append_child\n",
+            r#"  (data (i32.const 0) "Text")"#,
+        ] {
+            assert!(wat.contains(line), "{line} in {wat}");
+        }
+
+        let rust = String::from_utf8(Target::Rust.render("card.chip", wasm.clone()).unwrap());
+        let rust = rust.unwrap();
+        assert!(rust.starts_with("//! Compiled from card.chip by chip, don't edit\n"));
+        assert!(
+            rust.contains(
+                "pub const EXPORTS: &[&str] = &[\n    \"Card.view\",\n    \"Card::new\",\n    \"Card::drop\",\n    \"Main\",\n];"
+            ),
+            "{rust}"
+        );
+        assert!(rust.contains("pub const WASM: &[u8] = &[\n    0x00, 0x61, 0x73, 0x6d,"));
+        let bytes = rust.matches("0x").count();
+        assert_eq!(bytes, wasm.len());
+    }
+
+    fn typeck_compilation_unit(l: &str) -> Vec<String> {
+        let unit = parse_compilation_unit(l);
+        let (resolution, errors) = resolve::resolve(&unit);
+        assert_eq!(errors, vec![]);
+        typeck::typeck(&unit, &resolution)
+            .1
+            .iter()
+            .map(|err| {
+                let span = err.span();
+                format!("{err} @ {}", &l[span.start..span.end])
+            })
+            .collect()
+    }
+
+    fn resolve_compilation_unit(l: &str) -> (resolve::Resolution, Vec<resolve::ResolveError>) {
+        resolve::resolve(&parse_compilation_unit(l))
+    }
+
+    fn validate_compilation_unit(l: &str) -> Vec<validate::ValidationError> {
+        validate::validate(&parse_compilation_unit(l))
+    }
+
+    /// `parse_statement_expect(l, l);`
+    fn parse_compilation_unit_expect_same(l: &str) {
+        parse_compilation_unit_expect(l, l);
+    }
+
+    fn parse_compilation_unit_expect(l: &str, r: &str) {
+        println!("Testing that parsed \"{}\" == \"{}\"", l, r);
+        let mut e = vec![];
+        let mut unit = ast::CompilationUnit::default();
+        let expr =
+            main_parser::CompilationUnitParser::new().parse(&mut e, &mut unit, Lexer::new(l));
+        println!("{:?} with error vec {:?}", unit, e);
+        assert!(expr.is_ok());
+        assert_eq!(&format!("{:?}", unit), r);
+    }
+
+    /// `parse_statement_expect(l, l);`
+    fn parse_statement_expect_same(l: &str) {
+        parse_statement_expect(l, l);
+    }
+
+    /// Convenience func, this parses l and makes sure it's string representation equals r
+    fn parse_statement_expect(l: &str, r: &str) {
+        println!("Testing that parsed \"{}\" == \"{}\"", l, r);
+        let mut e = vec![];
+        let mut unit = ast::CompilationUnit::default();
+        let expr = main_parser::StatementParser::new().parse(&mut e, &mut unit, Lexer::new(l));
+        println!(
+            "{:?} with error vec {:?}",
+            expr.as_ref().map(|id| unit.with(id)),
+            e
+        );
+        assert!(expr.is_ok());
+        assert_eq!(&format!("{:?}", unit.with(&expr.unwrap())), r);
+    }
+
+    /// Compiles a unit that passed every check, like [`compile_source`] does
+    fn compile_checked(unit: &ast::CompilationUnit) -> Result<walrus::Module, WasmCodegenError> {
+        let (resolution, types, errors) = compile::analyze(unit);
+        assert_eq!(errors, vec![]);
+        wasm::compile_unit(unit, &resolution, &types)
+    }
+
+    /// Compiles a statement outside of any unit, so every identifier is looked up as a local
+    fn compile_unresolved(
+        builder: &mut walrus::InstrSeqBuilder,
+        func_locals: &mut LocalMap,
+        module_locals: &mut walrus::ModuleLocals,
+        unit: &ast::CompilationUnit,
+        statement: ast::StmtId,
+    ) -> Result<Option<ChipType>, WasmCodegenError> {
+        let resolution = resolve::Resolution::default();
+        let types = typeck::TypeckResults::default();
+        let functions = FunctionTable::new(Analysis {
+            resolution: &resolution,
+            types: &types,
+        });
+        compile_statement_wasm(
+            builder,
+            func_locals,
+            module_locals,
+            functions.callees(None),
+            unit,
+            statement,
+        )
+    }
+
+    #[test]
+    fn wasm_block_scope() {
+        let (unit, statement) = parse_statement("{ let a = 1; { let a = 2.5; a }; a + 1 }");
+
+        let mut module = walrus::Module::default();
+        let mut func = FunctionBuilder::new(&mut module.types, &[], &[ValType::I64]);
+        let mut func_locals = LocalMap::default();
+
+        let chip_type = compile_unresolved(
+            &mut func.func_body(),
+            &mut func_locals,
+            &mut module.locals,
+            &unit,
+            statement,
+        )
+        .unwrap();
+        assert_eq!(chip_type, Some(ChipType::Primitive(Primitive::I64)));
+        assert!(func_locals.names.is_empty());
+    }
+
+    #[test]
+    fn wasm_casts() {
+        let unit = parse_compilation_unit("f() -> U8 { let a = 2.5 * 2.0; a as Int as U8 + 1u8 }");
+        let module = compile_checked(&unit).unwrap();
+        let fun = module.funcs.get(module.funcs.by_name("f").unwrap());
+        assert_eq!(module.types.results(fun.ty()), &[ValType::I32]);
+
+        let fun = fun.kind.unwrap_local();
+        let ops = fun
+            .block(fun.entry_block())
+            .instrs
+            .iter()
+            .filter_map(|(instr, _)| match instr {
+                walrus::ir::Instr::Unop(unop) => Some(format!("{:?}", unop.op)),
+                walrus::ir::Instr::Binop(binop) => Some(format!("{:?}", binop.op)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ops,
+            vec![
+                "F64Mul",
+                "I64TruncSF64",
+                "I32WrapI64",
+                "I32And",
+                "I32Add",
+                "I32And"
+            ]
+        );
+    }
+
+    #[test]
+    fn wasm_uninitialised_let() {
+        // The type of `y` is inferred from its first assignment during type checking
+        let unit = parse_compilation_unit("f() -> Int { let x: Int; let y; y = 2.5; x = 3; x }");
+        let module = compile_checked(&unit).unwrap();
+        let local_types = module
+            .locals
+            .iter()
+            .map(|local| local.ty())
+            .collect::<Vec<_>>();
+        assert_eq!(local_types, vec![ValType::I64, ValType::F64]);
+    }
+
+    #[test]
+    fn wasm_let_binding() {
+        let (unit, statement) = parse_statement(
+            r#"
+            {   let a = test_struct;
+                a.field_1 = test_struct;
+            }
+            "#,
+        );
+
+        let mut module = walrus::Module::default();
+
+        let module_locals = &mut module.locals;
+
+        let mut func = FunctionBuilder::new(&mut module.types, &[], &[ValType::I64]);
+
+        let mut func_locals = LocalMap::default();
+
+        let mut test_struct_def = LinkedHashMap::new();
+
+        (0..5).for_each(|index| {
+            test_struct_def.insert(
+                format!("field_{}", index),
+                ChipType::Primitive(Primitive::I64),
+            );
+
+            let test_field_local = module_locals.add(ValType::I64);
+
+            func_locals.names.insert(
+                format!("test_struct.field_{}", index),
+                (Some(test_field_local), ChipType::Primitive(Primitive::I64)),
+            );
+        });
+
+        func_locals.names.insert(
+            "test_struct".into(),
+            (None, ChipType::Struct(Rc::new(test_struct_def))),
+        );
+
+        // Assigning the whole struct to one of its fields is ill-typed, only the absence of a panic
+        // is checked here, like in the original test
+        let _ = compile_unresolved(
+            &mut func.func_body(),
+            &mut func_locals,
+            module_locals,
+            &unit,
+            statement,
+        );
+
+        dbg!(
+            "{:?}",
+            func.func_body()
+                .instrs()
+                .iter()
+                .map(|t| &t.0)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn wasm_struct_field_assign() {
+        let (unit, statement) = parse_statement("{ let a = test_struct; a.field_1 = 5; }");
+
+        let mut module = walrus::Module::default();
+        let mut func = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let mut func_locals = LocalMap::default();
+        let mut test_struct_def = LinkedHashMap::new();
+        for index in 0..5 {
+            test_struct_def.insert(
+                format!("field_{}", index),
+                ChipType::Primitive(Primitive::I64),
+            );
+            let test_field_local = module.locals.add(ValType::I64);
+            func_locals.names.insert(
+                format!("test_struct.field_{}", index),
+                (Some(test_field_local), ChipType::Primitive(Primitive::I64)),
+            );
+        }
+        func_locals.names.insert(
+            "test_struct".into(),
+            (None, ChipType::Struct(Rc::new(test_struct_def))),
+        );
+
+        compile_unresolved(
+            &mut func.func_body(),
+            &mut func_locals,
+            &mut module.locals,
+            &unit,
+            statement,
+        )
+        .unwrap();
+
+        // `a` copies all 5 fields into its own locals, last field first since they are popped
+        // off the stack, then `5` is stored into the local of `a.field_1`
+        let instrs = func.func_body().instrs().to_vec();
+        let sets = instrs
+            .iter()
+            .filter_map(|(instr, _)| match instr {
+                walrus::ir::Instr::LocalSet(set) => Some(set.local),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sets.len(), 6);
+        assert_eq!(sets[5], sets[3]);
+        assert!(matches!(
+            instrs[instrs.len() - 2].0,
+            walrus::ir::Instr::Const(_)
+        ));
+    }
+
+    /// Generates random ASTs following `main_parser.lalrpop`, one method per rule
+    struct AstGen {
+        state: u64,
+        depth: usize,
+        unit: ast::CompilationUnit,
+    }
+
+    impl AstGen {
+        const NAMES: [&'static str; 5] = ["a", "b", "count", "x_1", "_tmp"];
+        const TYPES: [&'static str; 5] = ["Int", "Float", "U8", "Bool", "Tag"];
+        const TAGS: [&'static str; 3] = ["Row", "Text", "Column"];
+        const TEXTS: [&'static str; 3] = ["hello", "Count: 1", "a, b & c!"];
+        const MAX_DEPTH: usize = 5;
+
+        fn new(seed: u64) -> AstGen {
+            AstGen {
+                state: seed,
+                depth: 0,
+                unit: ast::CompilationUnit::default(),
+            }
+        }
+
+        /// xorshift64*
+        fn next(&mut self) -> u64 {
+            self.state ^= self.state >> 12;
+            self.state ^= self.state << 25;
+            self.state ^= self.state >> 27;
+            self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        /// One of `n` alternatives, always the first one, which doesn't recurse, once the tree is deep
+        fn choice(&mut self, n: usize) -> usize {
+            if self.depth >= Self::MAX_DEPTH {
+                0
+            } else {
+                self.below(n)
+            }
+        }
+
+        fn deeper<T>(&mut self, rule: impl FnOnce(&mut Self) -> T) -> T {
+            self.depth += 1;
+            let node = rule(self);
+            self.depth -= 1;
+            node
+        }
+
+        fn many<T>(&mut self, max: usize, mut rule: impl FnMut(&mut Self) -> T) -> Vec<T> {
+            let count = if self.depth >= Self::MAX_DEPTH {
+                0
+            } else {
+                self.below(max + 1)
+            };
+            self.deeper(|gen| (0..count).map(|_| rule(gen)).collect())
+        }
+
+        fn ident(&mut self, names: &[&'static str]) -> ast::IdentId {
+            let name = names[self.below(names.len())];
+            self.unit.alloc_ident(name, ast::Span::default())
+        }
+
+        fn alloc(&mut self, statement: ast::Statement) -> ast::StmtId {
+            self.unit.alloc(statement)
+        }
+
+        fn compilation_unit(mut self) -> ast::CompilationUnit {
+            self.unit.defs = self.many(4, Self::top_level_def);
+            self.unit
+        }
+
+        fn top_level_def(&mut self) -> ast::TopLevelDef {
+            match self.below(3) {
+                0 => ast::TopLevelDef::Func(self.function_def()),
+                1 => {
+                    let ident = self.ident(&Self::TAGS);
+                    let mut tags = vec![self.deeper(Self::tag)];
+                    tags.extend(self.many(2, Self::tag));
+                    ast::TopLevelDef::Export(ident, ast::TagList(tags))
+                }
+                _ => ast::TopLevelDef::TagDef(ast::TagDef {
+                    ident: self.ident(&Self::TAGS),
+                    props: ast::PropList(self.many(2, |gen| ast::Prop {
+                        ident: gen.ident(&Self::NAMES),
+                        chip_type: gen.ident(&Self::TYPES),
+                        default: (gen.below(2) == 0).then(|| gen.expr(false)),
+                    })),
+                    states: ast::StateList(self.many(2, |gen| {
+                        let (chip_type, default) = match gen.below(3) {
+                            0 => (Some(gen.ident(&Self::TYPES)), None),
+                            1 => (None, Some(gen.expr(false))),
+                            _ => (Some(gen.ident(&Self::TYPES)), Some(gen.expr(false))),
+                        };
+                        ast::State {
+                            ident: gen.ident(&Self::NAMES),
+                            chip_type,
+                            default,
+                        }
+                    })),
+                    fns: ast::FunctionDefList(self.many(2, Self::function_def)),
+                }),
+            }
+        }
+
+        fn function_def(&mut self) -> ast::FunctionDef {
+            ast::FunctionDef {
+                ident: self.ident(&Self::NAMES),
+                args: ast::ArgDefList(self.many(3, |gen| ast::ArgDef {
+                    name: gen.ident(&Self::NAMES),
+                    chip_type: gen.ident(&Self::TYPES),
+                })),
+                return_type: (self.below(2) == 0).then(|| self.ident(&Self::TYPES)),
+                block: self.statement_list(),
+            }
+        }
+
+        fn statement_list(&mut self) -> ast::StatementList {
+            ast::StatementList(self.many(3, Self::statement))
+        }
+
+        fn statement(&mut self) -> ast::StmtId {
+            let mutability = if self.below(2) == 0 {
+                ast::Mutability::Mutable
+            } else {
+                ast::Mutability::Immutable
+            };
+            let annotation = (self.below(2) == 0).then(|| self.ident(&Self::TYPES));
+            let statement = match self.choice(4) {
+                0 => return self.comp(),
+                1 => ast::Statement::Let(mutability, self.ident(&Self::NAMES), annotation),
+                2 => ast::Statement::LetAssign(
+                    mutability,
+                    self.ident(&Self::NAMES),
+                    annotation,
+                    self.deeper(|gen| gen.expr(false)),
+                ),
+                _ => {
+                    let mut idents = vec![self.ident(&Self::NAMES)];
+                    idents.extend(self.many(2, |gen| gen.ident(&Self::NAMES)));
+                    ast::Statement::Assign(
+                        ast::IdentList(idents),
+                        self.deeper(|gen| gen.expr(false)),
+                    )
+                }
+            };
+            self.alloc(statement)
+        }
+
+        fn comp(&mut self) -> ast::StmtId {
+            let statement = match self.choice(5) {
+                0 => return self.expr(true),
+                1 => {
+                    let ops = [
+                        ast::Opcode::Gt,
+                        ast::Opcode::Ge,
+                        ast::Opcode::Lt,
+                        ast::Opcode::Le,
+                        ast::Opcode::Eq,
+                        ast::Opcode::Ne,
+                    ];
+                    let l = self.deeper(Self::comp);
+                    let op = ops.into_iter().nth(self.below(6)).unwrap();
+                    ast::Statement::Op(l, op, self.deeper(|gen| gen.expr(false)))
+                }
+                2 => ast::Statement::If(self.deeper(Self::comp), self.statement_list()),
+                3 => ast::Statement::IfElse(
+                    self.deeper(Self::comp),
+                    self.statement_list(),
+                    self.statement_list(),
+                ),
+                _ => ast::Statement::Block(self.statement_list()),
+            };
+            self.alloc(statement)
+        }
+
+        /// `Expr`, or `ExprNoParens` which can't start with a parenthesis
+        fn expr(&mut self, no_parens: bool) -> ast::StmtId {
+            let statement = match self.choice(if no_parens { 3 } else { 4 }) {
+                0 => return self.factor(),
+                1 => {
+                    let l = self.deeper(|gen| gen.expr(no_parens));
+                    let op = if self.below(2) == 0 {
+                        ast::Opcode::Add
+                    } else {
+                        ast::Opcode::Sub
+                    };
+                    ast::Statement::Op(l, op, self.deeper(Self::factor))
+                }
+                2 => ast::Statement::FunctionCall(ast::FunctionCall {
+                    ident: self.ident(&Self::NAMES),
+                    args: ast::ArgList(self.many(3, Self::statement)),
+                }),
+                _ => ast::Statement::Tag(self.deeper(Self::tag)),
+            };
+            self.alloc(statement)
+        }
+
+        fn factor(&mut self) -> ast::StmtId {
+            match self.choice(2) {
+                0 => self.cast(),
+                _ => {
+                    let l = self.deeper(Self::factor);
+                    let op = if self.below(2) == 0 {
+                        ast::Opcode::Mul
+                    } else {
+                        ast::Opcode::Div
+                    };
+                    let r = self.deeper(Self::cast);
+                    self.alloc(ast::Statement::Op(l, op, r))
+                }
+            }
+        }
+
+        fn cast(&mut self) -> ast::StmtId {
+            match self.choice(2) {
+                0 => self.term(),
+                _ => {
+                    let value = self.deeper(Self::cast);
+                    let chip_type = self.ident(&Self::TYPES);
+                    self.alloc(ast::Statement::Cast(value, chip_type))
+                }
+            }
+        }
+
+        /// Also `PropertyValue`
+        fn term(&mut self) -> ast::StmtId {
+            let statement = if self.below(2) == 0 {
+                ast::Statement::Ident(self.ident(&Self::NAMES))
+            } else {
+                ast::Statement::Number(self.number(), ast::Span::default())
+            };
+            self.alloc(statement)
+        }
+
+        fn number(&mut self) -> ast::Number {
+            let num_type = ast::NumType::ALL[self.below(ast::NumType::ALL.len())];
+            let defaults = matches!(num_type, ast::NumType::I64 | ast::NumType::F64);
+            let suffix = if defaults && self.below(2) == 0 {
+                None
+            } else {
+                Some(num_type)
+            };
+            let bits = self.next();
+            if num_type.is_float() {
+                let value = (bits % 2_000_001) as f64 / 1000.0 - 1000.0;
+                let exponent = self.below(41) as i32 - 20;
+                ast::Number::Float(value * 10f64.powi(exponent), suffix)
+            } else {
+                let value = match num_type {
+                    ast::NumType::I32 => bits as i32 as i64,
+                    ast::NumType::U8 => (bits % 256) as i64,
+                    ast::NumType::U32 => bits as u32 as i64,
+                    _ => bits as i64,
+                };
+                ast::Number::Int(value, suffix)
+            }
+        }
+
+        fn tag(&mut self) -> ast::Tag {
+            let properties = self.many(3, |gen| (gen.ident(&Self::NAMES), gen.term()));
+            let count = self.many(3, |_| ()).len();
+            let mut children = vec![];
+            for _ in 0..count {
+                // Text always runs until the next tag
+                let after_text = matches!(children.last(), Some(ast::TagChild::Text(..)));
+                children.push(if !after_text && self.below(2) == 0 {
+                    ast::TagChild::Text(Self::TEXTS[self.below(3)].into(), ast::Span::default())
+                } else {
+                    ast::TagChild::Tag(self.deeper(Self::tag))
+                });
+            }
+            ast::Tag {
+                ident: self.ident(&Self::TAGS),
+                properties: ast::PropertyList(properties),
+                children: ast::TagChildList(children),
+            }
+        }
+    }
 }
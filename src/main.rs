@@ -0,0 +1,101 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage:
+    chip [--out-dir=DIR] [--target=wasm|wat|rs]
+                                Compiles every .chip file in the current directory,
+                                into DIR (default `out`) with the same layout
+    chip fmt [--check] [PATH]...  Formats .chip files, `--check` only lists files that would change
+    chip --emit=ast-json [PATH]   Prints the checked AST of .chip files as JSON, for tools";
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let result = match args.first().map(String::as_str) {
+        None => chip::compile_root().map(|written| {
+            print_written(&written);
+            ExitCode::SUCCESS
+        }),
+        Some("fmt") => fmt(&args[1..]),
+        Some(flag) if flag.starts_with("--emit=") => emit(&flag["--emit=".len()..], &args[1..]),
+        Some(flag) if flag.starts_with("--out-dir=") || flag.starts_with("--target=") => {
+            compile(&args)
+        }
+        Some(_) => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    result.unwrap_or_else(|err| {
+        eprintln!("{err:?}");
+        ExitCode::FAILURE
+    })
+}
+
+fn compile(args: &[String]) -> anyhow::Result<ExitCode> {
+    let root = std::env::current_dir()?;
+    let mut outputs = chip::OutputPaths::new(&root);
+    for arg in args {
+        if let Some(dir) = arg.strip_prefix("--out-dir=") {
+            outputs.out_dir = root.join(dir);
+        } else if let Some(extension) = arg.strip_prefix("--target=") {
+            outputs.target = chip::Target::from_extension(extension)
+                .ok_or_else(|| anyhow::anyhow!("Unknown target `{extension}`\n{USAGE}"))?;
+        } else {
+            anyhow::bail!("Unknown argument `{arg}`\n{USAGE}");
+        }
+    }
+    print_written(&chip::compile_folder(&root, &outputs)?);
+    Ok(ExitCode::SUCCESS)
+}
+
+fn print_written(written: &[PathBuf]) {
+    for file in written {
+        println!("Wrote {}", file.display());
+    }
+}
+
+fn emit(kind: &str, args: &[String]) -> anyhow::Result<ExitCode> {
+    let path = match args {
+        [] => ".",
+        [path] => path.as_str(),
+        _ => anyhow::bail!("Expected at most one path\n{USAGE}"),
+    };
+    match kind {
+        "ast-json" => println!("{}", chip::emit_ast_json(path)?),
+        _ => anyhow::bail!("Unknown output `{kind}`, expected `ast-json`\n{USAGE}"),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn fmt(args: &[String]) -> anyhow::Result<ExitCode> {
+    let mut check = false;
+    let mut paths = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "--check" => check = true,
+            flag if flag.starts_with("--") => anyhow::bail!("Unknown flag `{flag}`\n{USAGE}"),
+            path => paths.push(path),
+        }
+    }
+    if paths.is_empty() {
+        paths.push(".");
+    }
+
+    let mut changed = vec![];
+    for path in paths {
+        changed.extend(chip::format_path(path, check)?);
+    }
+    for file in &changed {
+        if check {
+            println!("Would reformat {}", file.display());
+        } else {
+            println!("Formatted {}", file.display());
+        }
+    }
+    Ok(if check && !changed.is_empty() {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
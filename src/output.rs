@@ -0,0 +1,106 @@
+//! Where compiled files are written, see [`OutputPaths`]
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// What a source file is compiled to: the module itself, its text format, or a Rust
+/// file that embeds the module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Wasm,
+    Wat,
+    Rust,
+}
+
+impl Target {
+    pub const ALL: [Target; 3] = [Target::Wasm, Target::Wat, Target::Rust];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Target::Wasm => "wasm",
+            Target::Wat => "wat",
+            Target::Rust => "rs",
+        }
+    }
+
+    /// The target for `--target=<extension>`
+    pub fn from_extension(extension: &str) -> Option<Target> {
+        Target::ALL
+            .into_iter()
+            .find(|target| target.extension() == extension)
+    }
+
+    /// The contents of the output file for the compiled `wasm` of `source`
+    pub fn render(self, source: &str, wasm: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Target::Wasm => Ok(wasm),
+            Target::Wat => Ok(crate::wasm::print_wat(&wasm)?.into_bytes()),
+            Target::Rust => Ok(rust_module(source, &wasm)?.into_bytes()),
+        }
+    }
+}
+
+/// A Rust file with the module as `WASM` and the functions it exports as `EXPORTS`
+fn rust_module(source: &str, wasm: &[u8]) -> anyhow::Result<String> {
+    let module = walrus::Module::from_buffer(wasm)?;
+    let mut out = format!("//! Compiled from {source} by chip, don't edit\n\n");
+    out.push_str("pub const EXPORTS: &[&str] = &[\n");
+    let functions = module
+        .exports
+        .iter()
+        .filter(|export| matches!(export.item, walrus::ExportItem::Function(_)));
+    for export in functions {
+        writeln!(out, "    {:?},", export.name)?;
+    }
+    out.push_str("];\n\npub const WASM: &[u8] = &[\n");
+    for line in wasm.chunks(16) {
+        let bytes = line
+            .iter()
+            .map(|byte| format!("0x{byte:02x},"))
+            .collect::<Vec<_>>();
+        writeln!(out, "    {}", bytes.join(" "))?;
+    }
+    out.push_str("];\n");
+    Ok(out)
+}
+
+/// Maps source files to output files: `out_dir` mirrors the layout of `src_dir`,
+/// with the extension of `target` in place of `.chip`
+#[derive(Debug, Clone)]
+pub struct OutputPaths {
+    pub src_dir: PathBuf,
+    pub out_dir: PathBuf,
+    pub target: Target,
+}
+
+impl OutputPaths {
+    /// Wasm files in `out` inside of `src_dir`
+    pub fn new(src_dir: impl Into<PathBuf>) -> OutputPaths {
+        let src_dir = src_dir.into();
+        OutputPaths {
+            out_dir: src_dir.join("out"),
+            src_dir,
+            target: Target::Wasm,
+        }
+    }
+
+    /// Fails instead of returning a path that would write over `source`
+    pub fn output_path(&self, source: &Path) -> anyhow::Result<PathBuf> {
+        let relative = source
+            .strip_prefix(&self.src_dir)
+            .map_err(|_| anyhow::anyhow!("{source:?} is not inside of {:?}", self.src_dir))?;
+        let output = self
+            .out_dir
+            .join(relative)
+            .with_extension(self.target.extension());
+        let is_source = output == source
+            || matches!(
+                (output.canonicalize(), source.canonicalize()),
+                (Ok(output), Ok(source)) if output == source
+            );
+        if is_source {
+            anyhow::bail!("Output {output:?} would overwrite the source file {source:?}");
+        }
+        Ok(output)
+    }
+}
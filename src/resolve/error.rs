@@ -0,0 +1,25 @@
+use thiserror::Error;
+
+use crate::ast::Span;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ResolveError {
+    #[error("Cannot find `{0}` in this scope")]
+    UndefinedName(String, Span),
+    #[error("Cannot find function `{0}` in this scope")]
+    UndefinedFunction(String, Span),
+    #[error("`{0}` is defined more than once in the same scope")]
+    DuplicateDefinition(String, Span),
+}
+
+impl ResolveError {
+    /// Where in the source this error should be reported
+    pub fn span(&self) -> Span {
+        use self::ResolveError::*;
+        match self {
+            UndefinedName(_, span) | UndefinedFunction(_, span) | DuplicateDefinition(_, span) => {
+                *span
+            }
+        }
+    }
+}
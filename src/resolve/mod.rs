@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+
+use crate::ast::{
+    CompilationUnit, FunctionDef, IdentId, Mutability, Span, Statement, StatementList, StmtId, Tag,
+    TagDef, TagList, TopLevelDef,
+};
+
+pub use self::error::ResolveError;
+
+mod error;
+
+/// Values that are always in scope
+pub const BUILTIN_VALUES: &[&str] = &["true", "false"];
+
+/// Index into [`Resolution::defs`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DefId(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefKind {
+    Builtin,
+    Function,
+    Tag,
+    Prop,
+    State,
+    Arg,
+    Local,
+}
+
+#[derive(Debug)]
+pub struct Def {
+    pub name: String,
+    pub kind: DefKind,
+    pub span: Span,
+    /// Only `state` and `let mut` can be assigned to after they're initialised
+    pub mutable: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Resolution {
+    pub defs: Vec<Def>,
+    /// What each identifier that refers to a definition resolved to
+    pub refs: HashMap<IdentId, DefId>,
+    /// The definition each declaring identifier introduced
+    pub decls: HashMap<IdentId, DefId>,
+}
+
+impl Resolution {
+    pub fn def(&self, id: DefId) -> &Def {
+        &self.defs[id.0]
+    }
+}
+
+/// Resolves every identifier in `unit` to its definition, following lexical scoping.
+/// Names that can't be resolved are left out of the [`Resolution`] and reported as errors.
+pub fn resolve(unit: &CompilationUnit) -> (Resolution, Vec<ResolveError>) {
+    let mut resolver = Resolver {
+        unit,
+        resolution: Resolution::default(),
+        scopes: vec![HashMap::new()],
+        tags: HashMap::new(),
+        errors: vec![],
+    };
+
+    for name in BUILTIN_VALUES {
+        resolver.define(name, DefKind::Builtin, None, false);
+    }
+
+    // Top level items are visible everywhere, regardless of the order they're defined in
+    for def in &unit.defs {
+        match def {
+            TopLevelDef::Func(fun) => resolver.declare(fun.ident, DefKind::Function, false),
+            TopLevelDef::TagDef(tag_def) => {
+                let ident = &unit[tag_def.ident];
+                // Duplicate tag definitions are reported by validation
+                if !resolver.tags.contains_key(ident.0.as_str()) {
+                    let id = resolver.new_def(&ident.0, DefKind::Tag, ident.1, false);
+                    resolver.resolution.decls.insert(tag_def.ident, id);
+                    resolver.tags.insert(&ident.0, id);
+                }
+            }
+            TopLevelDef::Export(_, _) => {}
+        }
+    }
+
+    for def in &unit.defs {
+        match def {
+            TopLevelDef::Func(fun) => resolver.function(fun),
+            TopLevelDef::Export(_, tags) => resolver.tag_list(tags),
+            TopLevelDef::TagDef(tag_def) => resolver.tag_def(tag_def),
+        }
+    }
+
+    (resolver.resolution, resolver.errors)
+}
+
+struct Resolver<'a> {
+    unit: &'a CompilationUnit,
+    resolution: Resolution,
+    /// Innermost scope last
+    scopes: Vec<HashMap<&'a str, DefId>>,
+    tags: HashMap<&'a str, DefId>,
+    errors: Vec<ResolveError>,
+}
+
+impl<'a> Resolver<'a> {
+    fn new_def(&mut self, name: &str, kind: DefKind, span: Span, mutable: bool) -> DefId {
+        let id = DefId(self.resolution.defs.len());
+        self.resolution.defs.push(Def {
+            name: name.into(),
+            kind,
+            span,
+            mutable,
+        });
+        id
+    }
+
+    /// Builtins are the only definitions without a declaring identifier
+    fn define(&mut self, name: &'a str, kind: DefKind, ident: Option<IdentId>, mutable: bool) {
+        let span = ident.map_or_else(Span::default, |ident| self.unit[ident].1);
+        let id = self.new_def(name, kind, span, mutable);
+        if let Some(ident) = ident {
+            self.resolution.decls.insert(ident, id);
+        }
+        self.scopes
+            .last_mut()
+            .expect("There is always a global scope")
+            .insert(name, id);
+    }
+
+    /// Adds `ident` to the innermost scope. Only `let` may shadow a name in the same scope.
+    fn declare(&mut self, id: IdentId, kind: DefKind, mutable: bool) {
+        let unit = self.unit;
+        let ident = &unit[id];
+        let scope = self.scopes.last().expect("There is always a global scope");
+        if let Some(existing) = scope.get(ident.0.as_str()) {
+            if kind != DefKind::Local && self.resolution.def(*existing).kind != DefKind::Local {
+                self.errors
+                    .push(ResolveError::DuplicateDefinition(ident.0.clone(), ident.1));
+                return;
+            }
+        }
+        self.define(&ident.0, kind, Some(id), mutable);
+    }
+
+    fn lookup(&self, name: &str) -> Option<DefId> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied())
+    }
+
+    fn reference(&mut self, id: IdentId) {
+        let unit = self.unit;
+        let ident = &unit[id];
+        match self.lookup(&ident.0) {
+            Some(def) => {
+                self.resolution.refs.insert(id, def);
+            }
+            None => self
+                .errors
+                .push(ResolveError::UndefinedName(ident.0.clone(), ident.1)),
+        }
+    }
+
+    fn scoped(&mut self, f: impl FnOnce(&mut Self)) {
+        self.scopes.push(HashMap::new());
+        f(self);
+        self.scopes.pop();
+    }
+
+    /// Defaults are evaluated in order while the instance is created, so a default only sees
+    /// the props and states before it. The functions of the tag need an instance, so they
+    /// are only visible in each other.
+    fn tag_def(&mut self, tag_def: &TagDef) {
+        self.scoped(|this| {
+            for prop in &tag_def.props.0 {
+                if let Some(default) = prop.default {
+                    this.statement(default);
+                }
+                this.declare(prop.ident, DefKind::Prop, false);
+            }
+            for state in &tag_def.states.0 {
+                if let Some(default) = state.default {
+                    this.statement(default);
+                }
+                this.declare(state.ident, DefKind::State, true);
+            }
+            for fun in &tag_def.fns.0 {
+                this.declare(fun.ident, DefKind::Function, false);
+            }
+            for fun in &tag_def.fns.0 {
+                this.function(fun);
+            }
+        });
+    }
+
+    fn function(&mut self, fun: &FunctionDef) {
+        self.scoped(|this| {
+            for arg in &fun.args.0 {
+                this.declare(arg.name, DefKind::Arg, false);
+            }
+            this.scoped(|this| this.statement_list(&fun.block));
+        });
+    }
+
+    /// Statements are resolved in order, so a `let` is only visible to the statements after it
+    fn statement_list(&mut self, list: &StatementList) {
+        for statement in &list.0 {
+            self.statement(*statement);
+        }
+    }
+
+    fn statement(&mut self, id: StmtId) {
+        let unit = self.unit;
+        match &unit[id] {
+            Statement::Op(l, _, r) => {
+                self.statement(*l);
+                self.statement(*r);
+            }
+            Statement::FunctionCall(call) => {
+                let ident = &unit[call.ident];
+                match self.lookup(&ident.0) {
+                    Some(def) => {
+                        self.resolution.refs.insert(call.ident, def);
+                    }
+                    None => self
+                        .errors
+                        .push(ResolveError::UndefinedFunction(ident.0.clone(), ident.1)),
+                }
+                for arg in &call.args.0 {
+                    self.statement(*arg);
+                }
+            }
+            Statement::If(cond, block) => {
+                self.statement(*cond);
+                self.scoped(|this| this.statement_list(block));
+            }
+            Statement::IfElse(cond, if_block, else_block) => {
+                self.statement(*cond);
+                self.scoped(|this| this.statement_list(if_block));
+                self.scoped(|this| this.statement_list(else_block));
+            }
+            Statement::Let(mutability, ident, _) => {
+                self.declare(*ident, DefKind::Local, *mutability == Mutability::Mutable)
+            }
+            Statement::LetAssign(mutability, ident, _, value) => {
+                // The new binding isn't visible on its own right hand side
+                self.statement(*value);
+                self.declare(*ident, DefKind::Local, *mutability == Mutability::Mutable);
+            }
+            Statement::Assign(idents, value) => {
+                self.statement(*value);
+                // The rest of the list are field names, only the variable itself is resolved
+                if let Some(root) = idents.0.first() {
+                    self.reference(*root);
+                }
+            }
+            Statement::Tag(tag) => self.tag(tag),
+            Statement::Ident(ident) => self.reference(*ident),
+            Statement::Block(list) => self.scoped(|this| this.statement_list(list)),
+            Statement::Cast(value, _) => self.statement(*value),
+            Statement::Number(..) | Statement::Error => {}
+        }
+    }
+
+    fn tag_list(&mut self, tags: &TagList) {
+        for tag in &tags.0 {
+            self.tag(tag);
+        }
+    }
+
+    fn tag(&mut self, tag: &Tag) {
+        // Unknown tags are reported by validation, built-in tags have no definition
+        if let Some(id) = self.tags.get(self.unit[tag.ident].0.as_str()) {
+            self.resolution.refs.insert(tag.ident, *id);
+        }
+        for (_, value) in &tag.properties.0 {
+            self.statement(*value);
+        }
+        for child in tag.children.tags() {
+            self.tag(child);
+        }
+    }
+}
@@ -63,6 +63,16 @@ impl CodeText {
         TextLocation(line, col)
     }
 
+    /// Formats `message` with the location of `pos` and the line it's on
+    pub fn describe(&self, pos: usize, message: impl Display) -> String {
+        let location = self.line_col(pos);
+        format!(
+            "{:?}:{location}: {message}\n    {}",
+            self.path(),
+            self.line_text(location.0)
+        )
+    }
+
     pub fn line_text(&self, line_num: usize) -> &str {
         let start_offset = self.newlines[line_num];
         if line_num == self.newlines.len() - 1 {
@@ -0,0 +1,69 @@
+use thiserror::Error;
+
+use crate::ast::Span;
+
+use super::Ty;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum TypeError {
+    #[error("Expected {0}, found {1}")]
+    MismatchedTypes(Ty, Ty, Span),
+    #[error("Unknown type `{0}`")]
+    UnknownType(String, Span),
+    #[error("Cannot apply `{0}` to {1} and {2}")]
+    InvalidOperands(String, Ty, Ty, Span),
+    #[error("Cannot cast {0} as {1}")]
+    InvalidCast(Ty, Ty, Span),
+    #[error("`{0}` takes {1} argument(s) but {2} were given")]
+    WrongArgCount(String, usize, usize, Span),
+    #[error("`{0}` is not a function")]
+    NotAFunction(String, Span),
+    #[error("`{0}` is not a value")]
+    NotAValue(String, Span),
+    #[error("`{0}` cannot be assigned to")]
+    InvalidAssignTarget(String, Span),
+    #[error("`{0}` has no fields")]
+    NoFields(String, Span),
+    #[error("`{0}` is used before it is assigned a value")]
+    UseBeforeAssign(String, Span),
+    #[error("Cannot assign twice to immutable binding `{0}`, declare it with `let mut`")]
+    AssignToImmutable(String, Span),
+    #[error("`{0}` might already be assigned, declare it with `let mut` to assign it again")]
+    AssignTwice(String, Span),
+    #[error("Cannot assign to argument `{0}`")]
+    AssignToArg(String, Span),
+    #[error("Cannot assign to prop `{0}`, props are set by the parent tag")]
+    AssignToProp(String, Span),
+    #[error("Cannot assign to state `{0}` inside of view()")]
+    AssignStateInView(String, Span),
+    #[error("Cannot assign to state `{0}` inside of {1}(), which is called by view()")]
+    AssignStateCalledByView(String, String, Span),
+    #[error("`if` and `else` have different types: {0} and {1}")]
+    MismatchedBranches(Ty, Ty, Span),
+}
+
+impl TypeError {
+    /// Where in the source this error should be reported
+    pub fn span(&self) -> Span {
+        use self::TypeError::*;
+        match self {
+            MismatchedTypes(_, _, span)
+            | UnknownType(_, span)
+            | InvalidOperands(_, _, _, span)
+            | InvalidCast(_, _, span)
+            | MismatchedBranches(_, _, span)
+            | WrongArgCount(_, _, _, span)
+            | NotAFunction(_, span)
+            | NotAValue(_, span)
+            | InvalidAssignTarget(_, span)
+            | NoFields(_, span)
+            | UseBeforeAssign(_, span)
+            | AssignToImmutable(_, span)
+            | AssignTwice(_, span)
+            | AssignToArg(_, span)
+            | AssignToProp(_, span)
+            | AssignStateInView(_, span)
+            | AssignStateCalledByView(_, _, span) => *span,
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+
+use crate::ast::{
+    CompilationUnit, FunctionDef, Statement, StatementList, StmtId, Tag, TopLevelDef,
+};
+use crate::resolve::{DefId, Resolution};
+
+use super::TypeError;
+
+/// Definite assignment analysis: every read of a `let` declared without a value
+/// must come after an assignment on every path leading to it. Unless it's `let mut`,
+/// no path may assign it more than once.
+pub fn check_initialization(unit: &CompilationUnit, resolution: &Resolution) -> Vec<TypeError> {
+    let mut checker = InitChecker {
+        unit,
+        resolution,
+        uninit: HashSet::new(),
+        flow: Flow::default(),
+        errors: vec![],
+    };
+
+    for def in &unit.defs {
+        match def {
+            TopLevelDef::Func(fun) => checker.function(fun),
+            TopLevelDef::TagDef(tag_def) => {
+                for fun in &tag_def.fns.0 {
+                    checker.function(fun);
+                }
+            }
+            TopLevelDef::Export(_, _) => {}
+        }
+    }
+
+    checker.errors
+}
+
+/// What is known about the uninitialised `let`s at one point in a function
+#[derive(Clone, Default)]
+struct Flow {
+    /// Assigned on every path leading here
+    assigned: HashSet<DefId>,
+    /// Assigned on at least one path leading here
+    maybe_assigned: HashSet<DefId>,
+}
+
+struct InitChecker<'r> {
+    unit: &'r CompilationUnit,
+    resolution: &'r Resolution,
+    /// Every `let` declared without a value
+    uninit: HashSet<DefId>,
+    flow: Flow,
+    errors: Vec<TypeError>,
+}
+
+impl InitChecker<'_> {
+    fn function(&mut self, fun: &FunctionDef) {
+        self.flow = Flow::default();
+        self.statement_list(&fun.block);
+    }
+
+    fn statement_list(&mut self, list: &StatementList) {
+        for statement in &list.0 {
+            self.statement(*statement);
+        }
+    }
+
+    /// Checks a block that might not run, returns the flow at its end
+    fn branch(&mut self, list: &StatementList) -> Flow {
+        let before = self.flow.clone();
+        self.statement_list(list);
+        std::mem::replace(&mut self.flow, before)
+    }
+
+    fn statement(&mut self, id: StmtId) {
+        let unit = self.unit;
+        match &unit[id] {
+            Statement::Op(l, _, r) => {
+                self.statement(*l);
+                self.statement(*r);
+            }
+            Statement::FunctionCall(call) => {
+                for arg in &call.args.0 {
+                    self.statement(*arg);
+                }
+            }
+            Statement::If(cond, block) => {
+                self.statement(*cond);
+                let block_flow = self.branch(block);
+                self.flow.maybe_assigned = block_flow.maybe_assigned;
+            }
+            Statement::IfElse(cond, if_block, else_block) => {
+                self.statement(*cond);
+                let if_flow = self.branch(if_block);
+                let else_flow = self.branch(else_block);
+                self.flow = Flow {
+                    assigned: &if_flow.assigned & &else_flow.assigned,
+                    maybe_assigned: &if_flow.maybe_assigned | &else_flow.maybe_assigned,
+                };
+            }
+            Statement::Let(_, ident, _) => {
+                if let Some(id) = self.resolution.decls.get(ident) {
+                    self.uninit.insert(*id);
+                }
+            }
+            Statement::LetAssign(_, _, _, value) => self.statement(*value),
+            Statement::Assign(idents, value) => {
+                self.statement(*value);
+                let root = match idents.0.first() {
+                    Some(root) => *root,
+                    None => return,
+                };
+                let id = match self.resolution.refs.get(&root) {
+                    Some(id) if self.uninit.contains(id) => *id,
+                    _ => return,
+                };
+                let def = self.resolution.def(id);
+                if !def.mutable && self.flow.maybe_assigned.contains(&id) {
+                    self.errors
+                        .push(TypeError::AssignTwice(def.name.clone(), unit[root].1));
+                }
+                self.flow.assigned.insert(id);
+                self.flow.maybe_assigned.insert(id);
+            }
+            Statement::Tag(tag) => self.tag(tag),
+            Statement::Ident(ident) => {
+                if let Some(id) = self.resolution.refs.get(ident) {
+                    if self.uninit.contains(id) && !self.flow.assigned.contains(id) {
+                        let ident = &unit[*ident];
+                        self.errors
+                            .push(TypeError::UseBeforeAssign(ident.0.clone(), ident.1));
+                    }
+                }
+            }
+            Statement::Block(list) => self.statement_list(list),
+            Statement::Cast(value, _) => self.statement(*value),
+            Statement::Number(..) | Statement::Error => {}
+        }
+    }
+
+    fn tag(&mut self, tag: &Tag) {
+        for (_, value) in &tag.properties.0 {
+            self.statement(*value);
+        }
+        for child in tag.children.tags() {
+            self.tag(child);
+        }
+    }
+}
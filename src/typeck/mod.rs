@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Error, Formatter};
+
+use crate::ast::{
+    CompilationUnit, FunctionDef, IdentId, NumType, Opcode, Span, Statement, StatementList, StmtId,
+    Tag, TagDef, TagList, TopLevelDef,
+};
+use crate::resolve::{DefId, DefKind, Resolution};
+
+pub use self::error::TypeError;
+
+mod error;
+mod init;
+mod mutability;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ty {
+    Unit,
+    Num(NumType),
+    Bool,
+    Tag,
+    /// The type of anything that already caused an error, never reported again
+    Error,
+}
+
+impl Ty {
+    /// The type a type annotation like `: Int` refers to
+    pub fn from_name(name: &str) -> Option<Ty> {
+        match name {
+            "Bool" => Some(Ty::Bool),
+            "Tag" => Some(Ty::Tag),
+            _ => NumType::from_name(name).map(Ty::Num),
+        }
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        matches!(self, Ty::Num(_))
+    }
+}
+
+impl Display for Ty {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            Ty::Unit => write!(f, "()"),
+            Ty::Num(num_type) => write!(f, "{}", num_type),
+            Ty::Bool => write!(f, "Bool"),
+            Ty::Tag => write!(f, "Tag"),
+            Ty::Error => write!(f, "{{error}}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FnSig {
+    pub args: Vec<Ty>,
+    pub ret: Ty,
+}
+
+/// The types of a [`CompilationUnit`], later passes can assume these are all consistent
+#[derive(Debug, Default)]
+pub struct TypeckResults {
+    /// The type of every statement
+    pub types: HashMap<StmtId, Ty>,
+    /// The type of every value definition: locals, args, states and props
+    pub def_types: HashMap<DefId, Ty>,
+    pub fn_sigs: HashMap<DefId, FnSig>,
+}
+
+/// Infers and checks the types of everything in `unit`. Names must already be resolved,
+/// identifiers that aren't in `resolution` were reported there and are typed as [`Ty::Error`].
+pub fn typeck(unit: &CompilationUnit, resolution: &Resolution) -> (TypeckResults, Vec<TypeError>) {
+    let mut checker = Checker {
+        unit,
+        resolution,
+        tag_defs: HashMap::new(),
+        results: TypeckResults::default(),
+        errors: vec![],
+    };
+
+    // Signatures first, so functions and tags can be used before they're defined
+    for def in &unit.defs {
+        match def {
+            TopLevelDef::Func(fun) => checker.signature(fun),
+            TopLevelDef::TagDef(tag_def) => checker.tag_signature(tag_def),
+            TopLevelDef::Export(_, _) => {}
+        }
+    }
+
+    for def in &unit.defs {
+        match def {
+            TopLevelDef::Func(fun) => checker.function(fun),
+            TopLevelDef::Export(_, tags) => checker.tag_list(tags),
+            TopLevelDef::TagDef(tag_def) => checker.tag_def(tag_def),
+        }
+    }
+
+    checker
+        .errors
+        .extend(init::check_initialization(unit, resolution));
+    checker
+        .errors
+        .extend(mutability::check_mutability(unit, resolution));
+    (checker.results, checker.errors)
+}
+
+struct Checker<'r, 'u> {
+    unit: &'u CompilationUnit,
+    resolution: &'r Resolution,
+    tag_defs: HashMap<DefId, &'u TagDef>,
+    results: TypeckResults,
+    errors: Vec<TypeError>,
+}
+
+impl<'r, 'u> Checker<'r, 'u> {
+    fn named_type(&mut self, name: IdentId) -> Ty {
+        let name = &self.unit[name];
+        Ty::from_name(&name.0).unwrap_or_else(|| {
+            self.errors
+                .push(TypeError::UnknownType(name.0.clone(), name.1));
+            Ty::Error
+        })
+    }
+
+    /// Reports a mismatch unless either type already is an error
+    fn expect(&mut self, expected: &Ty, found: &Ty, span: Option<Span>) {
+        if expected != found && *expected != Ty::Error && *found != Ty::Error {
+            self.errors.push(TypeError::MismatchedTypes(
+                expected.clone(),
+                found.clone(),
+                span.unwrap_or_default(),
+            ));
+        }
+    }
+
+    /// The type of the definition introduced by `ident`
+    fn def_type(&self, ident: IdentId) -> Ty {
+        self.resolution
+            .decls
+            .get(&ident)
+            .and_then(|id| self.results.def_types.get(id))
+            .cloned()
+            .unwrap_or(Ty::Error)
+    }
+
+    /// Records the type of the definition introduced by `ident`
+    fn declare(&mut self, ident: IdentId, ty: Ty) {
+        // Duplicate definitions were never declared during resolution
+        if let Some(id) = self.resolution.decls.get(&ident) {
+            self.results.def_types.insert(*id, ty);
+        }
+    }
+
+    fn signature(&mut self, fun: &FunctionDef) {
+        let args = fun
+            .args
+            .0
+            .iter()
+            .map(|arg| self.named_type(arg.chip_type))
+            .collect();
+        let ret = match fun.return_type {
+            Some(name) => self.named_type(name),
+            None => Ty::Unit,
+        };
+        if let Some(id) = self.resolution.decls.get(&fun.ident) {
+            self.results.fn_sigs.insert(*id, FnSig { args, ret });
+        }
+    }
+
+    /// Declares everything about a tag that is visible from outside of it
+    fn tag_signature(&mut self, tag_def: &'u TagDef) {
+        if let Some(id) = self.resolution.decls.get(&tag_def.ident) {
+            self.tag_defs.insert(*id, tag_def);
+        }
+        for prop in &tag_def.props.0 {
+            let ty = self.named_type(prop.chip_type);
+            self.declare(prop.ident, ty);
+        }
+        for state in &tag_def.states.0 {
+            if let Some(name) = state.chip_type {
+                let ty = self.named_type(name);
+                self.declare(state.ident, ty);
+            }
+        }
+        for fun in &tag_def.fns.0 {
+            self.signature(fun);
+        }
+    }
+
+    fn tag_def(&mut self, tag_def: &TagDef) {
+        for prop in &tag_def.props.0 {
+            if let Some(default) = prop.default {
+                let expected = self.def_type(prop.ident);
+                let found = self.statement(default);
+                self.expect(&expected, &found, self.unit.span(default));
+            }
+        }
+
+        for state in &tag_def.states.0 {
+            if let Some(default) = state.default {
+                let found = self.statement(default);
+                if state.chip_type.is_some() {
+                    let expected = self.def_type(state.ident);
+                    self.expect(&expected, &found, self.unit.span(default));
+                } else {
+                    self.declare(state.ident, found);
+                }
+            }
+        }
+
+        for fun in &tag_def.fns.0 {
+            self.function(fun);
+        }
+    }
+
+    fn function(&mut self, fun: &FunctionDef) {
+        let sig = self
+            .resolution
+            .decls
+            .get(&fun.ident)
+            .and_then(|id| self.results.fn_sigs.get(id))
+            .cloned();
+
+        for (index, arg) in fun.args.0.iter().enumerate() {
+            let ty = match &sig {
+                Some(sig) => sig.args[index].clone(),
+                None => self.named_type(arg.chip_type),
+            };
+            self.declare(arg.name, ty);
+        }
+
+        let found = self.statement_list(&fun.block);
+        // Without a return type the value of the body is discarded
+        if let (Some(return_type), Some(sig)) = (fun.return_type, &sig) {
+            let span = fun
+                .block
+                .0
+                .last()
+                .and_then(|last| self.unit.span(*last))
+                .unwrap_or(self.unit[return_type].1);
+            self.expect(&sig.ret, &found, Some(span));
+        }
+    }
+
+    /// The type of a list is the type of its last statement
+    fn statement_list(&mut self, list: &StatementList) -> Ty {
+        let mut ty = Ty::Unit;
+        for statement in &list.0 {
+            ty = self.statement(*statement);
+        }
+        ty
+    }
+
+    fn statement(&mut self, id: StmtId) -> Ty {
+        let ty = self.statement_inner(id);
+        self.results.types.insert(id, ty.clone());
+        ty
+    }
+
+    fn statement_inner(&mut self, id: StmtId) -> Ty {
+        let unit = self.unit;
+        match &unit[id] {
+            Statement::Number(number, _) => Ty::Num(number.num_type()),
+            Statement::Op(l, op, r) => {
+                let l = self.statement(*l);
+                let r = self.statement(*r);
+                self.op(id, op, l, r)
+            }
+            Statement::FunctionCall(call) => {
+                let args = call
+                    .args
+                    .0
+                    .iter()
+                    .map(|arg| (self.statement(*arg), unit.span(*arg)))
+                    .collect::<Vec<_>>();
+
+                let ident = &unit[call.ident];
+                let id = match self.resolution.refs.get(&call.ident) {
+                    Some(id) => *id,
+                    None => return Ty::Error,
+                };
+                let sig = match self.results.fn_sigs.get(&id) {
+                    Some(sig) => sig.clone(),
+                    None => {
+                        if self.resolution.def(id).kind != DefKind::Function {
+                            self.errors
+                                .push(TypeError::NotAFunction(ident.0.clone(), ident.1));
+                        }
+                        return Ty::Error;
+                    }
+                };
+
+                if sig.args.len() != args.len() {
+                    self.errors.push(TypeError::WrongArgCount(
+                        ident.0.clone(),
+                        sig.args.len(),
+                        args.len(),
+                        ident.1,
+                    ));
+                } else {
+                    for (expected, (found, span)) in sig.args.iter().zip(args) {
+                        self.expect(expected, &found, span);
+                    }
+                }
+                sig.ret
+            }
+            Statement::If(cond, block) => {
+                let found = self.statement(*cond);
+                self.expect(&Ty::Bool, &found, unit.span(*cond));
+                self.statement_list(block);
+                Ty::Unit
+            }
+            Statement::IfElse(cond, if_block, else_block) => {
+                let found = self.statement(*cond);
+                self.expect(&Ty::Bool, &found, unit.span(*cond));
+                let if_ty = self.statement_list(if_block);
+                let else_ty = self.statement_list(else_block);
+                match (if_ty, else_ty) {
+                    (if_ty, else_ty) if if_ty == else_ty => if_ty,
+                    (Ty::Error, _) | (_, Ty::Error) => Ty::Error,
+                    // The value of a branch without one to match is dropped
+                    (Ty::Unit, _) | (_, Ty::Unit) => Ty::Unit,
+                    (if_ty, else_ty) => {
+                        self.errors.push(TypeError::MismatchedBranches(
+                            if_ty,
+                            else_ty,
+                            unit.span(id).unwrap_or_default(),
+                        ));
+                        Ty::Error
+                    }
+                }
+            }
+            Statement::Let(_, ident, annotation) => {
+                // Without an annotation the type is inferred from the first assignment
+                if let Some(name) = annotation {
+                    let ty = self.named_type(*name);
+                    self.declare(*ident, ty);
+                }
+                Ty::Unit
+            }
+            Statement::LetAssign(_, ident, annotation, value) => {
+                let found = self.statement(*value);
+                let ty = match annotation {
+                    Some(name) => {
+                        let expected = self.named_type(*name);
+                        self.expect(&expected, &found, unit.span(*value));
+                        expected
+                    }
+                    None => found,
+                };
+                self.declare(*ident, ty);
+                Ty::Unit
+            }
+            Statement::Assign(idents, value) => {
+                let found = self.statement(*value);
+                let (root_id, root) = match idents.0.first() {
+                    Some(root) => (*root, &unit[*root]),
+                    None => return Ty::Unit,
+                };
+                if idents.0.len() > 1 {
+                    self.errors
+                        .push(TypeError::NoFields(root.0.clone(), root.1));
+                    return Ty::Unit;
+                }
+
+                let id = match self.resolution.refs.get(&root_id) {
+                    Some(id) => *id,
+                    None => return Ty::Unit,
+                };
+                match self.resolution.def(id).kind {
+                    DefKind::Builtin | DefKind::Function | DefKind::Tag => {
+                        self.errors
+                            .push(TypeError::InvalidAssignTarget(root.0.clone(), root.1));
+                    }
+                    DefKind::Prop | DefKind::State | DefKind::Arg | DefKind::Local => {
+                        match self.results.def_types.get(&id).cloned() {
+                            Some(expected) => self.expect(&expected, &found, unit.span(*value)),
+                            None => {
+                                self.results.def_types.insert(id, found);
+                            }
+                        }
+                    }
+                }
+                Ty::Unit
+            }
+            Statement::Tag(tag) => {
+                self.tag(tag);
+                Ty::Tag
+            }
+            Statement::Ident(ident) => self.ident(*ident),
+            Statement::Block(list) => self.statement_list(list),
+            Statement::Cast(value, name) => {
+                let found = self.statement(*value);
+                let target = self.named_type(*name);
+                match (&found, &target) {
+                    (Ty::Error, _) | (_, Ty::Error) => Ty::Error,
+                    (Ty::Num(_), Ty::Num(_)) => target,
+                    _ => {
+                        self.errors.push(TypeError::InvalidCast(
+                            found,
+                            target,
+                            unit.span(id).unwrap_or_default(),
+                        ));
+                        Ty::Error
+                    }
+                }
+            }
+            Statement::Error => Ty::Error,
+        }
+    }
+
+    fn op(&mut self, id: StmtId, op: &Opcode, l: Ty, r: Ty) -> Ty {
+        if l == Ty::Error || r == Ty::Error {
+            return Ty::Error;
+        }
+
+        let valid = l == r
+            && match op {
+                Opcode::Mul | Opcode::Div | Opcode::Add | Opcode::Sub => l.is_numeric(),
+                Opcode::Gt | Opcode::Ge | Opcode::Lt | Opcode::Le => l.is_numeric(),
+                Opcode::Eq | Opcode::Ne => l.is_numeric() || l == Ty::Bool,
+            };
+        if !valid {
+            self.errors.push(TypeError::InvalidOperands(
+                format!("{op:?}"),
+                l,
+                r,
+                self.unit.span(id).unwrap_or_default(),
+            ));
+            return Ty::Error;
+        }
+
+        match op {
+            Opcode::Mul | Opcode::Div | Opcode::Add | Opcode::Sub => l,
+            _ => Ty::Bool,
+        }
+    }
+
+    fn ident(&mut self, ident: IdentId) -> Ty {
+        let id = match self.resolution.refs.get(&ident) {
+            Some(id) => *id,
+            None => return Ty::Error,
+        };
+        match self.resolution.def(id).kind {
+            DefKind::Builtin => Ty::Bool,
+            DefKind::Function | DefKind::Tag => {
+                let ident = &self.unit[ident];
+                self.errors
+                    .push(TypeError::NotAValue(ident.0.clone(), ident.1));
+                Ty::Error
+            }
+            DefKind::Prop | DefKind::State | DefKind::Arg | DefKind::Local => {
+                // Reads before the first assignment are reported by `check_initialization`
+                self.results
+                    .def_types
+                    .get(&id)
+                    .cloned()
+                    .unwrap_or(Ty::Error)
+            }
+        }
+    }
+
+    fn tag_list(&mut self, tags: &TagList) {
+        for tag in &tags.0 {
+            self.tag(tag);
+        }
+    }
+
+    fn tag(&mut self, tag: &Tag) {
+        let unit = self.unit;
+        let tag_def = self
+            .resolution
+            .refs
+            .get(&tag.ident)
+            .and_then(|id| self.tag_defs.get(id))
+            .copied();
+
+        for (key, value) in &tag.properties.0 {
+            let found = self.statement(*value);
+            // Literals were already checked during validation
+            let is_literal = match &unit[*value] {
+                Statement::Number(..) => true,
+                Statement::Ident(ident) => self
+                    .resolution
+                    .refs
+                    .get(ident)
+                    .is_some_and(|id| self.resolution.def(*id).kind == DefKind::Builtin),
+                _ => false,
+            };
+            let key = &unit[*key];
+            let prop =
+                tag_def.and_then(|tag_def| tag_def.props.0.iter().find(|p| unit[p.ident] == *key));
+            if let (false, Some(prop)) = (is_literal, prop) {
+                let expected = self.def_type(prop.ident);
+                self.expect(&expected, &found, Some(key.1));
+            }
+        }
+        for child in tag.children.tags() {
+            self.tag(child);
+        }
+    }
+}
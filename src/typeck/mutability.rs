@@ -0,0 +1,203 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{
+    CompilationUnit, FunctionDef, IdentId, Statement, StatementList, StmtId, Tag, TagDef,
+    TopLevelDef,
+};
+use crate::resolve::{DefId, DefKind, Resolution};
+use crate::visit::{walk_statement, Visitor};
+
+use super::TypeError;
+
+/// Only `state` and `let mut` bindings may be assigned to, and neither `view()` nor the functions
+/// it calls may change any `state`.
+/// `let`s declared without a value may be assigned once, that is checked by `check_initialization`.
+pub fn check_mutability(unit: &CompilationUnit, resolution: &Resolution) -> Vec<TypeError> {
+    let mut checker = MutabilityChecker {
+        unit,
+        resolution,
+        view: View::Outside,
+        deferred: HashSet::new(),
+        errors: vec![],
+    };
+
+    for def in &unit.defs {
+        match def {
+            TopLevelDef::Func(fun) => checker.function(fun, View::Outside),
+            TopLevelDef::TagDef(tag_def) => {
+                let called = called_by_view(unit, resolution, tag_def);
+                for fun in &tag_def.fns.0 {
+                    let name = &unit[fun.ident].0;
+                    let view = if name == "view" {
+                        View::Inside
+                    } else if called.contains(&fun.ident) {
+                        View::CalledBy(name)
+                    } else {
+                        View::Outside
+                    };
+                    checker.function(fun, view);
+                }
+            }
+            TopLevelDef::Export(_, _) => {}
+        }
+    }
+
+    checker.errors
+}
+
+/// The functions of `tag_def` that `view()` calls, directly or through other functions of the tag
+fn called_by_view(
+    unit: &CompilationUnit,
+    resolution: &Resolution,
+    tag_def: &TagDef,
+) -> HashSet<IdentId> {
+    let fns = tag_def
+        .fns
+        .0
+        .iter()
+        .filter_map(|fun| Some((*resolution.decls.get(&fun.ident)?, fun)))
+        .collect::<HashMap<_, _>>();
+    let mut called = HashSet::new();
+    let mut pending = tag_def
+        .fns
+        .0
+        .iter()
+        .filter(|fun| unit[fun.ident].0 == "view")
+        .collect::<Vec<_>>();
+    while let Some(fun) = pending.pop() {
+        let mut calls = Calls {
+            resolution,
+            calls: vec![],
+        };
+        for statement in &fun.block.0 {
+            calls.visit_statement(unit, *statement);
+        }
+        for id in calls.calls {
+            if let Some(callee) = fns.get(&id) {
+                if unit[callee.ident].0 != "view" && called.insert(callee.ident) {
+                    pending.push(callee);
+                }
+            }
+        }
+    }
+    called
+}
+
+/// The definitions of the functions called in the visited statements
+struct Calls<'r> {
+    resolution: &'r Resolution,
+    calls: Vec<DefId>,
+}
+
+impl<'u> Visitor<'u> for Calls<'_> {
+    fn visit_statement(&mut self, unit: &'u CompilationUnit, id: StmtId) {
+        if let Statement::FunctionCall(call) = &unit[id] {
+            self.calls
+                .extend(self.resolution.refs.get(&call.ident).copied());
+        }
+        walk_statement(self, unit, id);
+    }
+}
+
+/// Whether the checked function runs as part of `view()`
+#[derive(Clone, Copy)]
+enum View<'u> {
+    Outside,
+    Inside,
+    /// A function of the tag that `view()` calls
+    CalledBy(&'u str),
+}
+
+struct MutabilityChecker<'r> {
+    unit: &'r CompilationUnit,
+    resolution: &'r Resolution,
+    view: View<'r>,
+    /// `let`s declared without a value
+    deferred: HashSet<DefId>,
+    errors: Vec<TypeError>,
+}
+
+impl<'r> MutabilityChecker<'r> {
+    fn function(&mut self, fun: &FunctionDef, view: View<'r>) {
+        self.view = view;
+        self.statement_list(&fun.block);
+    }
+
+    fn statement_list(&mut self, list: &StatementList) {
+        for statement in &list.0 {
+            self.statement(*statement);
+        }
+    }
+
+    fn statement(&mut self, id: StmtId) {
+        let unit = self.unit;
+        match &unit[id] {
+            Statement::Op(l, _, r) => {
+                self.statement(*l);
+                self.statement(*r);
+            }
+            Statement::FunctionCall(call) => {
+                for arg in &call.args.0 {
+                    self.statement(*arg);
+                }
+            }
+            Statement::If(cond, block) => {
+                self.statement(*cond);
+                self.statement_list(block);
+            }
+            Statement::IfElse(cond, if_block, else_block) => {
+                self.statement(*cond);
+                self.statement_list(if_block);
+                self.statement_list(else_block);
+            }
+            Statement::Let(_, ident, _) => {
+                if let Some(id) = self.resolution.decls.get(ident) {
+                    self.deferred.insert(*id);
+                }
+            }
+            Statement::LetAssign(_, _, _, value) => self.statement(*value),
+            Statement::Assign(idents, value) => {
+                self.statement(*value);
+                let root = match idents.0.first() {
+                    Some(root) => *root,
+                    None => return,
+                };
+                let id = match self.resolution.refs.get(&root) {
+                    Some(id) => *id,
+                    None => return,
+                };
+                let def = self.resolution.def(id);
+                let span = unit[root].1;
+                let error = match (def.kind, self.view) {
+                    (DefKind::Local, _) if !def.mutable && !self.deferred.contains(&id) => {
+                        TypeError::AssignToImmutable(def.name.clone(), span)
+                    }
+                    (DefKind::Arg, _) => TypeError::AssignToArg(def.name.clone(), span),
+                    (DefKind::Prop, _) => TypeError::AssignToProp(def.name.clone(), span),
+                    (DefKind::State, View::Inside) => {
+                        TypeError::AssignStateInView(def.name.clone(), span)
+                    }
+                    (DefKind::State, View::CalledBy(fun)) => {
+                        TypeError::AssignStateCalledByView(def.name.clone(), fun.into(), span)
+                    }
+                    // Assigning to anything that isn't a value is a type error
+                    _ => return,
+                };
+                self.errors.push(error);
+            }
+            Statement::Tag(tag) => self.tag(tag),
+            Statement::Block(list) => self.statement_list(list),
+            Statement::Cast(value, _) => self.statement(*value),
+            Statement::Ident(_) | Statement::Number(..) | Statement::Error => {}
+        }
+    }
+
+    fn tag(&mut self, tag: &Tag) {
+        for (_, value) in &tag.properties.0 {
+            self.statement(*value);
+        }
+        for child in tag.children.tags() {
+            self.tag(child);
+        }
+    }
+}
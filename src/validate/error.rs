@@ -0,0 +1,52 @@
+use thiserror::Error;
+
+use crate::ast::Span;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ValidationError {
+    #[error("Tag `{0}` has no property `{1}`")]
+    UnknownProp(String, String, Span),
+    #[error("Tag `{0}` is missing required property `{1}`")]
+    MissingProp(String, String, Span),
+    #[error("Property `{1}` was passed to tag `{0}` more than once")]
+    DuplicateProp(String, String, Span),
+    #[error("Property `{1}` of tag `{0}` is declared as {2} but was given {3}")]
+    PropTypeMismatch(String, String, String, String, Span),
+    #[error("Tag `{0}` is neither defined nor a built-in tag")]
+    UnknownTag(String, Span),
+    #[error("Tag `{0}` is defined more than once")]
+    DuplicateTagDef(String, Span),
+    #[error("Tag `{0}` is already a built-in tag")]
+    BuiltinTagDef(String, Span),
+    #[error("`{0}` is exported more than once")]
+    DuplicateExport(String, Span),
+    #[error("`{0}` can't be exported, the module exports its memory under that name")]
+    ReservedExport(String, Span),
+    #[error("Tag `{0}` has no `view()` function")]
+    MissingView(String, Span),
+    #[error("`view()` of tag `{0}` must return Tag")]
+    ViewMustReturnTag(String, Span),
+    #[error("`view()` of tag `{0}` can't take arguments, use a `prop` instead")]
+    ViewArgs(String, Span),
+}
+
+impl ValidationError {
+    /// Where in the source this error should be reported
+    pub fn span(&self) -> Span {
+        use self::ValidationError::*;
+        match self {
+            UnknownProp(_, _, span)
+            | MissingProp(_, _, span)
+            | DuplicateProp(_, _, span)
+            | PropTypeMismatch(_, _, _, _, span)
+            | UnknownTag(_, span)
+            | DuplicateTagDef(_, span)
+            | BuiltinTagDef(_, span)
+            | DuplicateExport(_, span)
+            | ReservedExport(_, span)
+            | MissingView(_, span)
+            | ViewMustReturnTag(_, span)
+            | ViewArgs(_, span) => *span,
+        }
+    }
+}
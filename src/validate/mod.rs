@@ -0,0 +1,186 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{CompilationUnit, NumType, Statement, StmtId, Tag, TagDef, TopLevelDef};
+use crate::typeck::Ty;
+use crate::visit::{walk_tag, Visitor};
+
+pub use self::error::ValidationError;
+
+mod error;
+
+/// Tags provided by the host, these can be used without a `tag` definition
+pub const BUILTIN_TAGS: &[&str] = &["Text", "Image", "Row", "Column", "Stack", "Input", "Button"];
+
+/// Names the compiled module exports besides the `export`s, see `wasm::compile_unit`
+const RESERVED_EXPORTS: &[&str] = &["memory"];
+
+/// Checks everything the parser can't, returns every problem found instead of stopping at the first
+pub fn validate(unit: &CompilationUnit) -> Vec<ValidationError> {
+    let mut validator = Validator {
+        unit,
+        tag_defs: HashMap::new(),
+        errors: vec![],
+    };
+
+    let mut exports = HashSet::new();
+    for def in &unit.defs {
+        match def {
+            TopLevelDef::TagDef(tag_def) => {
+                let ident = &unit[tag_def.ident];
+                // Usage sites are checked against the first definition, or the built-in tag
+                if BUILTIN_TAGS.contains(&ident.0.as_str()) {
+                    validator
+                        .errors
+                        .push(ValidationError::BuiltinTagDef(ident.0.clone(), ident.1));
+                } else if validator.tag_defs.contains_key(ident.0.as_str()) {
+                    validator
+                        .errors
+                        .push(ValidationError::DuplicateTagDef(ident.0.clone(), ident.1));
+                } else {
+                    validator.tag_defs.insert(&ident.0, tag_def);
+                }
+                validator.check_view(tag_def);
+            }
+            TopLevelDef::Export(ident, _) => {
+                let ident = &unit[*ident];
+                if RESERVED_EXPORTS.contains(&ident.0.as_str()) {
+                    validator
+                        .errors
+                        .push(ValidationError::ReservedExport(ident.0.clone(), ident.1));
+                } else if !exports.insert(&ident.0) {
+                    validator
+                        .errors
+                        .push(ValidationError::DuplicateExport(ident.0.clone(), ident.1));
+                }
+            }
+            TopLevelDef::Func(_) => {}
+        }
+    }
+
+    validator.visit_compilation_unit(unit);
+    validator.errors
+}
+
+struct Validator<'u> {
+    unit: &'u CompilationUnit,
+    tag_defs: HashMap<&'u str, &'u TagDef>,
+    errors: Vec<ValidationError>,
+}
+
+impl<'u> Visitor<'u> for Validator<'u> {
+    fn visit_tag(&mut self, unit: &'u CompilationUnit, tag: &'u Tag) {
+        let ident = &unit[tag.ident];
+        if let Some(tag_def) = self.tag_defs.get(ident.0.as_str()) {
+            self.check_props(tag_def, tag);
+        } else if !BUILTIN_TAGS.contains(&ident.0.as_str()) {
+            self.errors
+                .push(ValidationError::UnknownTag(ident.0.clone(), ident.1));
+        }
+        walk_tag(self, unit, tag);
+    }
+}
+
+impl<'u> Validator<'u> {
+    /// Every tag definition needs a `view() -> Tag` to be rendered,
+    /// it only gets the instance it renders
+    fn check_view(&mut self, tag_def: &TagDef) {
+        let unit = self.unit;
+        let tag = &unit[tag_def.ident];
+        let tag_name = &tag.0;
+        let view = tag_def.fns.0.iter().find(|fun| unit[fun.ident].0 == "view");
+        if let Some(arg) = view.and_then(|view| view.args.0.first()) {
+            self.errors.push(ValidationError::ViewArgs(
+                tag_name.clone(),
+                unit[arg.name].1,
+            ));
+        }
+        match view {
+            None => self
+                .errors
+                .push(ValidationError::MissingView(tag_name.clone(), tag.1)),
+            Some(view) => match view.return_type.map(|id| &unit[id]) {
+                Some(return_type) if return_type.0 == "Tag" => {}
+                Some(return_type) => self.errors.push(ValidationError::ViewMustReturnTag(
+                    tag_name.clone(),
+                    return_type.1,
+                )),
+                None => self.errors.push(ValidationError::ViewMustReturnTag(
+                    tag_name.clone(),
+                    unit[view.ident].1,
+                )),
+            },
+        }
+    }
+
+    /// Compares the properties passed at a usage site against the `prop`s of the definition
+    fn check_props(&mut self, tag_def: &TagDef, tag: &Tag) {
+        let unit = self.unit;
+        let tag_name = &unit[tag.ident].0;
+        let mut passed = HashSet::new();
+
+        for (key, value) in &tag.properties.0 {
+            let key = &unit[*key];
+            if !passed.insert(key.0.as_str()) {
+                self.errors.push(ValidationError::DuplicateProp(
+                    tag_name.clone(),
+                    key.0.clone(),
+                    key.1,
+                ));
+                continue;
+            }
+
+            let prop = match tag_def.props.0.iter().find(|prop| unit[prop.ident] == *key) {
+                Some(prop) => prop,
+                None => {
+                    self.errors.push(ValidationError::UnknownProp(
+                        tag_name.clone(),
+                        key.0.clone(),
+                        key.1,
+                    ));
+                    continue;
+                }
+            };
+
+            let chip_type = unit[prop.chip_type].0.as_str();
+            // An unknown type is reported by type checking
+            if Ty::from_name(chip_type).is_none() {
+                continue;
+            }
+            if let Some(found) = literal_type(unit, *value) {
+                // `Int` and `I64` are the same type
+                let expected = NumType::from_name(chip_type).map_or(chip_type, |ty| ty.name());
+                if found != expected {
+                    self.errors.push(ValidationError::PropTypeMismatch(
+                        tag_name.clone(),
+                        key.0.clone(),
+                        chip_type.into(),
+                        found.into(),
+                        key.1,
+                    ));
+                }
+            }
+        }
+
+        for prop in &tag_def.props.0 {
+            let ident = &unit[prop.ident];
+            if prop.default.is_none() && !passed.contains(ident.0.as_str()) {
+                self.errors.push(ValidationError::MissingProp(
+                    tag_name.clone(),
+                    ident.0.clone(),
+                    unit[tag.ident].1,
+                ));
+            }
+        }
+    }
+}
+
+/// The type of a property value, if it can be known without resolving any names
+fn literal_type(unit: &CompilationUnit, value: StmtId) -> Option<&'static str> {
+    match &unit[value] {
+        Statement::Number(number, _) => Some(number.num_type().name()),
+        Statement::Ident(ident) if unit[*ident].0 == "true" || unit[*ident].0 == "false" => {
+            Some("Bool")
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,288 @@
+//! Traversal of the AST. Implement the `visit_*` methods for the nodes a pass cares about,
+//! and call the matching `walk_*` function from an override to keep descending into its children.
+
+use crate::ast::{
+    CompilationUnit, FunctionDef, Statement, StmtId, Tag, TagChild, TagDef, TopLevelDef,
+};
+
+pub trait Visitor<'u> {
+    fn visit_compilation_unit(&mut self, unit: &'u CompilationUnit) {
+        walk_compilation_unit(self, unit)
+    }
+
+    fn visit_top_level_def(&mut self, unit: &'u CompilationUnit, def: &'u TopLevelDef) {
+        walk_top_level_def(self, unit, def)
+    }
+
+    fn visit_tag_def(&mut self, unit: &'u CompilationUnit, tag_def: &'u TagDef) {
+        walk_tag_def(self, unit, tag_def)
+    }
+
+    fn visit_function_def(&mut self, unit: &'u CompilationUnit, fun: &'u FunctionDef) {
+        walk_function_def(self, unit, fun)
+    }
+
+    fn visit_statement(&mut self, unit: &'u CompilationUnit, id: StmtId) {
+        walk_statement(self, unit, id)
+    }
+
+    fn visit_tag(&mut self, unit: &'u CompilationUnit, tag: &'u Tag) {
+        walk_tag(self, unit, tag)
+    }
+}
+
+pub fn walk_compilation_unit<'u, V: Visitor<'u> + ?Sized>(
+    visitor: &mut V,
+    unit: &'u CompilationUnit,
+) {
+    for def in &unit.defs {
+        visitor.visit_top_level_def(unit, def);
+    }
+}
+
+pub fn walk_top_level_def<'u, V: Visitor<'u> + ?Sized>(
+    visitor: &mut V,
+    unit: &'u CompilationUnit,
+    def: &'u TopLevelDef,
+) {
+    match def {
+        TopLevelDef::Func(fun) => visitor.visit_function_def(unit, fun),
+        TopLevelDef::Export(_, tags) => {
+            for tag in &tags.0 {
+                visitor.visit_tag(unit, tag);
+            }
+        }
+        TopLevelDef::TagDef(tag_def) => visitor.visit_tag_def(unit, tag_def),
+    }
+}
+
+/// Prop defaults, then state defaults, then the functions
+pub fn walk_tag_def<'u, V: Visitor<'u> + ?Sized>(
+    visitor: &mut V,
+    unit: &'u CompilationUnit,
+    tag_def: &'u TagDef,
+) {
+    for prop in &tag_def.props.0 {
+        if let Some(default) = prop.default {
+            visitor.visit_statement(unit, default);
+        }
+    }
+    for state in &tag_def.states.0 {
+        if let Some(default) = state.default {
+            visitor.visit_statement(unit, default);
+        }
+    }
+    for fun in &tag_def.fns.0 {
+        visitor.visit_function_def(unit, fun);
+    }
+}
+
+pub fn walk_function_def<'u, V: Visitor<'u> + ?Sized>(
+    visitor: &mut V,
+    unit: &'u CompilationUnit,
+    fun: &'u FunctionDef,
+) {
+    for statement in &fun.block.0 {
+        visitor.visit_statement(unit, *statement);
+    }
+}
+
+/// The children of a statement in source order
+pub fn walk_statement<'u, V: Visitor<'u> + ?Sized>(
+    visitor: &mut V,
+    unit: &'u CompilationUnit,
+    id: StmtId,
+) {
+    match &unit[id] {
+        Statement::Op(l, _, r) => {
+            visitor.visit_statement(unit, *l);
+            visitor.visit_statement(unit, *r);
+        }
+        Statement::FunctionCall(call) => {
+            for arg in &call.args.0 {
+                visitor.visit_statement(unit, *arg);
+            }
+        }
+        Statement::If(cond, block) => {
+            visitor.visit_statement(unit, *cond);
+            for statement in &block.0 {
+                visitor.visit_statement(unit, *statement);
+            }
+        }
+        Statement::IfElse(cond, if_block, else_block) => {
+            visitor.visit_statement(unit, *cond);
+            for statement in if_block.0.iter().chain(&else_block.0) {
+                visitor.visit_statement(unit, *statement);
+            }
+        }
+        Statement::LetAssign(_, _, _, value)
+        | Statement::Assign(_, value)
+        | Statement::Cast(value, _) => visitor.visit_statement(unit, *value),
+        Statement::Tag(tag) => visitor.visit_tag(unit, tag),
+        Statement::Block(list) => {
+            for statement in &list.0 {
+                visitor.visit_statement(unit, *statement);
+            }
+        }
+        Statement::Number(..) | Statement::Let(..) | Statement::Ident(_) | Statement::Error => {}
+    }
+}
+
+/// Property values, then the child tags
+pub fn walk_tag<'u, V: Visitor<'u> + ?Sized>(
+    visitor: &mut V,
+    unit: &'u CompilationUnit,
+    tag: &'u Tag,
+) {
+    for (_, value) in &tag.properties.0 {
+        visitor.visit_statement(unit, *value);
+    }
+    for child in tag.children.tags() {
+        visitor.visit_tag(unit, child);
+    }
+}
+
+/// Like [`Visitor`], but nodes can be rewritten in place.
+/// Statements are visited by id, replace one with `unit[id] = ...`.
+pub trait VisitorMut {
+    fn visit_compilation_unit_mut(&mut self, unit: &mut CompilationUnit) {
+        walk_compilation_unit_mut(self, unit)
+    }
+
+    fn visit_top_level_def_mut(&mut self, unit: &mut CompilationUnit, def: &mut TopLevelDef) {
+        walk_top_level_def_mut(self, unit, def)
+    }
+
+    fn visit_tag_def_mut(&mut self, unit: &mut CompilationUnit, tag_def: &mut TagDef) {
+        walk_tag_def_mut(self, unit, tag_def)
+    }
+
+    fn visit_function_def_mut(&mut self, unit: &mut CompilationUnit, fun: &mut FunctionDef) {
+        walk_function_def_mut(self, unit, fun)
+    }
+
+    fn visit_statement_mut(&mut self, unit: &mut CompilationUnit, id: StmtId) {
+        walk_statement_mut(self, unit, id)
+    }
+
+    fn visit_tag_mut(&mut self, unit: &mut CompilationUnit, tag: &mut Tag) {
+        walk_tag_mut(self, unit, tag)
+    }
+}
+
+/// `unit.defs` is empty while they are being visited
+pub fn walk_compilation_unit_mut<V: VisitorMut + ?Sized>(
+    visitor: &mut V,
+    unit: &mut CompilationUnit,
+) {
+    let mut defs = std::mem::take(&mut unit.defs);
+    for def in &mut defs {
+        visitor.visit_top_level_def_mut(unit, def);
+    }
+    unit.defs = defs;
+}
+
+pub fn walk_top_level_def_mut<V: VisitorMut + ?Sized>(
+    visitor: &mut V,
+    unit: &mut CompilationUnit,
+    def: &mut TopLevelDef,
+) {
+    match def {
+        TopLevelDef::Func(fun) => visitor.visit_function_def_mut(unit, fun),
+        TopLevelDef::Export(_, tags) => {
+            for tag in &mut tags.0 {
+                visitor.visit_tag_mut(unit, tag);
+            }
+        }
+        TopLevelDef::TagDef(tag_def) => visitor.visit_tag_def_mut(unit, tag_def),
+    }
+}
+
+pub fn walk_tag_def_mut<V: VisitorMut + ?Sized>(
+    visitor: &mut V,
+    unit: &mut CompilationUnit,
+    tag_def: &mut TagDef,
+) {
+    for prop in &tag_def.props.0 {
+        if let Some(default) = prop.default {
+            visitor.visit_statement_mut(unit, default);
+        }
+    }
+    for state in &tag_def.states.0 {
+        if let Some(default) = state.default {
+            visitor.visit_statement_mut(unit, default);
+        }
+    }
+    for fun in &mut tag_def.fns.0 {
+        visitor.visit_function_def_mut(unit, fun);
+    }
+}
+
+pub fn walk_function_def_mut<V: VisitorMut + ?Sized>(
+    visitor: &mut V,
+    unit: &mut CompilationUnit,
+    fun: &mut FunctionDef,
+) {
+    for statement in &fun.block.0 {
+        visitor.visit_statement_mut(unit, *statement);
+    }
+}
+
+/// The statement is taken out of the unit while its children are visited,
+/// so it reads as [`Statement::Error`] until they are done
+pub fn walk_statement_mut<V: VisitorMut + ?Sized>(
+    visitor: &mut V,
+    unit: &mut CompilationUnit,
+    id: StmtId,
+) {
+    let mut statement = std::mem::replace(&mut unit[id], Statement::Error);
+    match &mut statement {
+        Statement::Op(l, _, r) => {
+            visitor.visit_statement_mut(unit, *l);
+            visitor.visit_statement_mut(unit, *r);
+        }
+        Statement::FunctionCall(call) => {
+            for arg in &call.args.0 {
+                visitor.visit_statement_mut(unit, *arg);
+            }
+        }
+        Statement::If(cond, block) => {
+            visitor.visit_statement_mut(unit, *cond);
+            for statement in &block.0 {
+                visitor.visit_statement_mut(unit, *statement);
+            }
+        }
+        Statement::IfElse(cond, if_block, else_block) => {
+            visitor.visit_statement_mut(unit, *cond);
+            for statement in if_block.0.iter().chain(&else_block.0) {
+                visitor.visit_statement_mut(unit, *statement);
+            }
+        }
+        Statement::LetAssign(_, _, _, value)
+        | Statement::Assign(_, value)
+        | Statement::Cast(value, _) => visitor.visit_statement_mut(unit, *value),
+        Statement::Tag(tag) => visitor.visit_tag_mut(unit, tag),
+        Statement::Block(list) => {
+            for statement in &list.0 {
+                visitor.visit_statement_mut(unit, *statement);
+            }
+        }
+        Statement::Number(..) | Statement::Let(..) | Statement::Ident(_) | Statement::Error => {}
+    }
+    unit[id] = statement;
+}
+
+pub fn walk_tag_mut<V: VisitorMut + ?Sized>(
+    visitor: &mut V,
+    unit: &mut CompilationUnit,
+    tag: &mut Tag,
+) {
+    for (_, value) in &tag.properties.0 {
+        visitor.visit_statement_mut(unit, *value);
+    }
+    for child in &mut tag.children.0 {
+        if let TagChild::Tag(child) = child {
+            visitor.visit_tag_mut(unit, child);
+        }
+    }
+}
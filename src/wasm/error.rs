@@ -1,15 +1,37 @@
 use thiserror::Error;
 
-use crate::ast::{Opcode, Statement};
-
-use super::Primitive;
+use crate::ast::Span;
 
 #[derive(Error, Debug)]
 pub enum WasmCodegenError {
-    #[error("Statement {0:?} must have a return value")]
-    MustHaveReturnValue(String),
-    #[error("Cannot use struct in operation: {0:?}")]
-    NoStructInOp(String),
-    #[error("Comparison must have the same type on both sides: {0:?} {1:?}")]
-    CompNonEqualType(Primitive, Primitive),
+    #[error("This statement must have a value")]
+    MustHaveReturnValue(Span),
+    #[error("Cannot use a struct in an operation")]
+    NoStructInOp(Span),
+    #[error("Unknown function `{0}`")]
+    UnknownFunction(String, Span),
+    #[error("Undeclared variable `{0}`")]
+    UndeclaredVariable(String, Span),
+    #[error("The type of `{0}` is unknown, the unit must be type checked first")]
+    Untyped(String, Span),
+    #[error("`{0}` wasn't placed in memory")]
+    UninternedString(String, Span),
+    #[error("{0} can't be compiled to wasm yet")]
+    Unsupported(&'static str, Span),
+}
+
+impl WasmCodegenError {
+    /// Where in the source this error should be reported
+    pub fn span(&self) -> Span {
+        use self::WasmCodegenError::*;
+        match self {
+            MustHaveReturnValue(span)
+            | NoStructInOp(span)
+            | UnknownFunction(_, span)
+            | UndeclaredVariable(_, span)
+            | Untyped(_, span)
+            | UninternedString(_, span)
+            | Unsupported(_, span) => *span,
+        }
+    }
 }
@@ -0,0 +1,295 @@
+use std::collections::{HashMap, HashSet};
+
+use walrus::{
+    ActiveData, ActiveDataLocation, DataKind, FunctionId, InstrSeqBuilder, MemoryId, Module,
+    ModuleLocals, ValType,
+};
+
+use crate::ast::{CompilationUnit, Ident, IdentId, Span, StmtId, Tag, TagChild, TopLevelDef};
+use crate::visit::{self, Visitor};
+
+use super::error::WasmCodegenError;
+use super::{compile_primitive, compile_value, Callees, ChipType, LocalMap, Primitive};
+
+/// The import module the host functions are taken from
+pub const HOST_MODULE: &str = "chirp";
+
+/// Wasm pages are 64 KiB
+const PAGE_SIZE: usize = 1 << 16;
+
+/// The functions the host provides to build the UI. Elements are `i32` handles,
+/// strings are an `i32` offset and an `i32` length into the exported `memory`.
+///
+/// - `create_element(name, name_len) -> element`
+/// - `create_text(text, text_len) -> element`, a text node
+/// - `set_property(element, key, key_len, value: f64)`
+/// - `append_child(parent, child)`
+/// - `replace_element(old, new)`, puts `new` where `old` is in its parent
+///
+/// Every property value is converted to an `f64`, so integers beyond 2^53 reach the host
+/// rounded to the nearest `f64`.
+pub struct Host {
+    pub memory: MemoryId,
+    pub create_element: FunctionId,
+    pub create_text: FunctionId,
+    pub set_property: FunctionId,
+    pub append_child: FunctionId,
+    pub replace_element: FunctionId,
+    /// The offset of every tag name, property key and text in memory
+    strings: HashMap<String, i32>,
+    /// Where the strings end, the rest of memory is free
+    pub data_len: u32,
+}
+
+impl Host {
+    /// Imports the host functions and places the strings of every tag in a data segment,
+    /// `None` if the unit doesn't have any tags
+    pub fn declare(module: &mut Module, unit: &CompilationUnit) -> Option<Host> {
+        let mut interner = Interner {
+            custom_tags: unit
+                .defs
+                .iter()
+                .filter_map(|def| match def {
+                    TopLevelDef::TagDef(tag_def) => Some(unit[tag_def.ident].0.as_str()),
+                    _ => None,
+                })
+                .collect(),
+            strings: HashMap::new(),
+            data: vec![],
+            has_tags: false,
+        };
+        interner.visit_compilation_unit(unit);
+        if !interner.has_tags {
+            return None;
+        }
+
+        let data_len = interner.data.len();
+        let pages = data_len.div_ceil(PAGE_SIZE).max(1);
+        let memory = module.memories.add_local(false, pages as u32, None);
+        module.exports.add("memory", memory);
+        module.data.add(
+            DataKind::Active(ActiveData {
+                memory,
+                location: ActiveDataLocation::Absolute(0),
+            }),
+            interner.data,
+        );
+
+        let mut import = |name: &str, params: &[ValType], results: &[ValType]| {
+            let ty = module.types.add(params, results);
+            module.add_import_func(HOST_MODULE, name, ty).0
+        };
+        use ValType::*;
+        Some(Host {
+            memory,
+            create_element: import("create_element", &[I32, I32], &[I32]),
+            create_text: import("create_text", &[I32, I32], &[I32]),
+            set_property: import("set_property", &[I32, I32, I32, F64], &[]),
+            append_child: import("append_child", &[I32, I32], &[]),
+            replace_element: import("replace_element", &[I32, I32], &[]),
+            strings: interner.strings,
+            data_len: data_len as u32,
+        })
+    }
+
+    /// Pushes the offset and length of an interned string
+    fn push_string(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        string: &str,
+        span: Span,
+    ) -> Result<(), WasmCodegenError> {
+        let offset = self
+            .strings
+            .get(string)
+            .ok_or_else(|| WasmCodegenError::UninternedString(string.into(), span))?;
+        builder.i32_const(*offset).i32_const(string.len() as i32);
+        Ok(())
+    }
+}
+
+/// Collects the strings of every built-in tag, each one is stored once
+struct Interner<'u> {
+    /// Rendered by calling their `view()`, so their names aren't needed
+    custom_tags: HashSet<&'u str>,
+    strings: HashMap<String, i32>,
+    data: Vec<u8>,
+    has_tags: bool,
+}
+
+impl Interner<'_> {
+    fn intern(&mut self, string: &str) {
+        if !self.strings.contains_key(string) {
+            self.strings.insert(string.into(), self.data.len() as i32);
+            self.data.extend_from_slice(string.as_bytes());
+        }
+    }
+}
+
+impl<'u> Visitor<'u> for Interner<'u> {
+    fn visit_tag(&mut self, unit: &'u CompilationUnit, tag: &'u Tag) {
+        self.has_tags = true;
+        let name = &unit[tag.ident].0;
+        if !self.custom_tags.contains(name.as_str()) {
+            self.intern(name);
+        }
+        // The properties of custom tags are passed to `new` instead
+        if !self.custom_tags.contains(name.as_str()) {
+            for (key, _) in &tag.properties.0 {
+                self.intern(&unit[*key].0);
+            }
+        }
+        for child in &tag.children.0 {
+            if let TagChild::Text(text, _) = child {
+                self.intern(text);
+            }
+        }
+        visit::walk_tag(self, unit, tag)
+    }
+}
+
+/// Sets the property `key` of the element on the stack to `value`, as an `f64`
+pub(super) fn compile_set_property(
+    builder: &mut InstrSeqBuilder,
+    func_locals: &mut LocalMap,
+    module_locals: &mut ModuleLocals,
+    functions: Callees,
+    unit: &CompilationUnit,
+    key: IdentId,
+    value: StmtId,
+) -> Result<(), WasmCodegenError> {
+    let Ident(key, key_span) = &unit[key];
+    let host = functions
+        .host()
+        .ok_or(WasmCodegenError::Unsupported("Tags", *key_span))?;
+    host.push_string(builder, key, *key_span)?;
+    let primitive = compile_primitive(builder, func_locals, module_locals, functions, unit, value)?;
+    if let Some(op) = primitive.conversion(Primitive::F64) {
+        builder.unop(op);
+    }
+    builder.call(host.set_property);
+    Ok(())
+}
+
+/// Leaves the handle of the element for `tag` on the stack. Built-in tags are created
+/// through the host along with their properties and children, custom tags call their `view()`
+/// on a new instance that gets the properties as its props. The instance is kept in the one
+/// the body runs on, or returned from the `export`, so that it can be dropped later.
+pub(super) fn compile_tag(
+    builder: &mut InstrSeqBuilder,
+    func_locals: &mut LocalMap,
+    module_locals: &mut ModuleLocals,
+    functions: Callees,
+    unit: &CompilationUnit,
+    tag: &Tag,
+) -> Result<ChipType, WasmCodegenError> {
+    let Ident(name, span) = &unit[tag.ident];
+    if let Some(view) = functions.view(name) {
+        if !tag.children.0.is_empty() {
+            return Err(WasmCodegenError::Unsupported(
+                "Children of custom tags",
+                *span,
+            ));
+        }
+        // Every occurrence of the tag gets its own instance
+        let instance_functions = functions
+            .instance_functions(name)
+            .ok_or_else(|| WasmCodegenError::UnknownFunction(format!("{name}::new"), *span))?;
+        for prop in &instance_functions.props {
+            let value = tag
+                .properties
+                .0
+                .iter()
+                .find(|(key, _)| unit[*key].0 == prop.name);
+            match value {
+                Some((_, value)) => {
+                    compile_value(builder, func_locals, module_locals, functions, unit, *value)?;
+                }
+                // Validation made sure that only props with a default are left out
+                None => {
+                    for (_, primitive) in prop.type_.flatten(&prop.name) {
+                        push_zero(builder, primitive.val_type());
+                    }
+                }
+            }
+            if prop.given.is_some() {
+                builder.i32_const(value.is_some() as i32);
+            }
+        }
+        builder.call(instance_functions.new);
+        let instance = match (functions.child(tag.ident), functions.handle(tag.ident)) {
+            (Some(slot), _) => {
+                // Running the function again replaces the instance of the last run
+                slot.drop_instance(builder, instance_functions.drop);
+                let instance = module_locals.add(ValType::I32);
+                builder.local_set(instance);
+                slot.store(builder, instance);
+                instance
+            }
+            (None, Some(handle)) => {
+                builder.local_set(handle);
+                handle
+            }
+            (None, None) => {
+                return Err(WasmCodegenError::Unsupported(
+                    "Custom tags outside of tags and exports",
+                    *span,
+                ))
+            }
+        };
+        builder.local_get(instance).call(view.id);
+        return Ok(ChipType::Primitive(Primitive::I32));
+    }
+
+    let host = functions
+        .host()
+        .ok_or(WasmCodegenError::Unsupported("Tags", *span))?;
+    let element = module_locals.add(ValType::I32);
+    host.push_string(builder, name, *span)?;
+    builder.call(host.create_element).local_set(element);
+    // Kept for the properties that are updated later
+    if let Some(slot) = functions.element(tag.ident) {
+        slot.store(builder, element);
+    }
+
+    for (key, value) in &tag.properties.0 {
+        builder.local_get(element);
+        compile_set_property(
+            builder,
+            func_locals,
+            module_locals,
+            functions,
+            unit,
+            *key,
+            *value,
+        )?;
+    }
+
+    // Texts are nodes of their own, so they keep their place between the tags
+    for child in &tag.children.0 {
+        builder.local_get(element);
+        match child {
+            TagChild::Tag(child) => {
+                compile_tag(builder, func_locals, module_locals, functions, unit, child)?;
+            }
+            TagChild::Text(text, text_span) => {
+                host.push_string(builder, text, *text_span)?;
+                builder.call(host.create_text);
+            }
+        }
+        builder.call(host.append_child);
+    }
+
+    builder.local_get(element);
+    Ok(ChipType::Primitive(Primitive::I32))
+}
+
+/// A placeholder for a param that is ignored
+fn push_zero(builder: &mut InstrSeqBuilder, val_type: ValType) {
+    match val_type {
+        ValType::I64 => builder.i64_const(0),
+        ValType::F32 => builder.f32_const(0.0),
+        ValType::F64 => builder.f64_const(0.0),
+        _ => builder.i32_const(0),
+    };
+}
@@ -0,0 +1,620 @@
+use std::collections::{HashMap, HashSet};
+
+use walrus::ir::{BinaryOp, IfElse, LoadKind, MemArg, StoreKind, Value};
+use walrus::{
+    FunctionBuilder, FunctionId, GlobalId, InitExpr, InstrSeqBuilder, LocalId, MemoryId, Module,
+    ModuleLocals, ValType,
+};
+
+use crate::ast::{CompilationUnit, Ident, IdentId, StmtId, Tag, TagDef};
+use crate::visit::{self, Visitor};
+
+use super::error::WasmCodegenError;
+use super::host::Host;
+use super::module::{Analysis, FunctionTable};
+use super::reactive::{analyze_view, compile_update_functions, SubtreeRoot, ViewBindings};
+use super::{compile_value, ChipType, LocalMap, Primitive};
+
+/// Where the instances of tags are allocated, after the data of the [`Host`]
+pub struct Heap {
+    pub memory: MemoryId,
+    /// `alloc(size) -> address`, grows the memory when it runs out
+    alloc: FunctionId,
+}
+
+impl Heap {
+    /// Uses the memory of the host, or exports a new one if there is none
+    pub fn declare(module: &mut Module, host: Option<&Host>) -> Heap {
+        let (memory, data_len) = match host {
+            Some(host) => (host.memory, host.data_len),
+            None => {
+                let memory = module.memories.add_local(false, 1, None);
+                module.exports.add("memory", memory);
+                (memory, 0)
+            }
+        };
+        // Address 0 marks an empty free list, so nothing may be allocated there
+        let start = align(data_len.max(8), 8);
+        let top = module.globals.add_local(
+            ValType::I32,
+            true,
+            InitExpr::Value(Value::I32(start as i32)),
+        );
+
+        let size = module.locals.add(ValType::I32);
+        let address = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        let mut body = builder.func_body();
+        body.global_get(top)
+            .local_tee(address)
+            .local_get(size)
+            .binop(BinaryOp::I32Add)
+            .global_set(top);
+        // Grow by as many pages as the new top is past the end
+        body.global_get(top)
+            .memory_size(memory)
+            .i32_const(16)
+            .binop(BinaryOp::I32Shl)
+            .binop(BinaryOp::I32GtU)
+            .if_else(
+                None,
+                |grow| {
+                    grow.global_get(top)
+                        .i32_const(0xFFFF)
+                        .binop(BinaryOp::I32Add)
+                        .i32_const(16)
+                        .binop(BinaryOp::I32ShrU)
+                        .memory_size(memory)
+                        .binop(BinaryOp::I32Sub)
+                        .memory_grow(memory)
+                        .drop();
+                },
+                |_| {},
+            );
+        body.local_get(address);
+        builder.name("alloc".into());
+        let alloc = builder.finish(vec![size], &mut module.funcs);
+
+        Heap { memory, alloc }
+    }
+}
+
+/// Where every tag with a `view()` keeps the element it returned, so that the tags that
+/// render it can find it. Dropped instances only need the first slot for the free list.
+pub const ROOT_OFFSET: u32 = 0;
+
+/// Where the `prop` and `state` fields of a tag are stored in an instance, followed by
+/// the elements of `view()` that are updated when a state changes and the instances
+/// of the custom tags its functions create
+#[derive(Default)]
+pub struct StateLayout {
+    fields: HashMap<String, StateField>,
+    /// The fields set once by `new`, they never change afterwards
+    props: HashSet<String>,
+    /// The offset of the element created for each tag
+    elements: HashMap<IdentId, u32>,
+    /// The offset of the element each branch in [`ViewBindings::subtrees`] resulted in
+    branches: HashMap<StmtId, u32>,
+    /// The offset of a flag that `view()` sets once the elements exist
+    rendered: Option<u32>,
+    /// [`ROOT_OFFSET`] if the tag has a `view()`
+    root: Option<u32>,
+    /// The offset of the instance created for each custom tag, zero until it exists
+    children: HashMap<IdentId, u32>,
+    size: u32,
+}
+
+pub struct StateField {
+    pub type_: ChipType,
+    /// The offset of every primitive, in the order [`ChipType::flatten`] produces them
+    slots: Vec<(u32, Primitive)>,
+    /// Sets the properties that read the field, see [`compile_update_functions`]
+    update: Option<FunctionId>,
+}
+
+impl StateLayout {
+    pub fn get(&self, name: &str) -> Option<&StateField> {
+        self.fields.get(name)
+    }
+
+    pub fn state_names(&self) -> impl Iterator<Item = &str> {
+        self.fields
+            .keys()
+            .map(String::as_str)
+            .filter(|name| !self.props.contains(*name))
+    }
+
+    pub fn is_state(&self, name: &str) -> bool {
+        self.fields.contains_key(name) && !self.props.contains(name)
+    }
+
+    pub fn element(&self, tag: IdentId) -> Option<u32> {
+        self.elements.get(&tag).copied()
+    }
+
+    pub fn branch(&self, id: StmtId) -> Option<u32> {
+        self.branches.get(&id).copied()
+    }
+
+    pub fn rendered(&self) -> Option<u32> {
+        self.rendered
+    }
+
+    pub fn root(&self) -> Option<u32> {
+        self.root
+    }
+
+    pub fn child(&self, tag: IdentId) -> Option<u32> {
+        self.children.get(&tag).copied()
+    }
+
+    /// Places the field after the previous ones, each primitive aligned to its size
+    fn add(&mut self, name: &str, type_: ChipType) {
+        let slots = type_
+            .flatten(name)
+            .into_iter()
+            .map(|(_, primitive)| {
+                let size = slot_size(primitive);
+                let offset = align(self.size, size);
+                self.size = offset + size;
+                (offset, primitive)
+            })
+            .collect();
+        self.fields.insert(
+            name.into(),
+            StateField {
+                type_,
+                slots,
+                update: None,
+            },
+        );
+    }
+
+    /// Places the flag, the elements and the branches of `bindings` after the fields
+    fn add_view_slots(&mut self, bindings: &ViewBindings) {
+        if bindings.elements.is_empty()
+            && bindings.subtrees.is_empty()
+            && bindings.rerender.is_empty()
+        {
+            return;
+        }
+        self.rendered = Some(self.add_slot());
+        for tag in &bindings.elements {
+            let offset = self.add_slot();
+            self.elements.insert(*tag, offset);
+        }
+        for subtree in &bindings.subtrees {
+            if let SubtreeRoot::Branch(id) = subtree.root {
+                let offset = self.add_slot();
+                self.branches.insert(id, offset);
+            }
+        }
+    }
+
+    /// Places the instances of `children` after the elements
+    fn add_children(&mut self, children: &[(IdentId, FunctionId)]) {
+        for (tag, _) in children {
+            let offset = self.add_slot();
+            self.children.insert(*tag, offset);
+        }
+    }
+
+    fn add_slot(&mut self) -> u32 {
+        let offset = align(self.size, 4);
+        self.size = offset + 4;
+        offset
+    }
+
+    /// A dropped instance stores the next free one in its first 4 bytes
+    fn instance_size(&self) -> u32 {
+        align(self.size.max(4), 8)
+    }
+}
+
+/// A state field of the instance a function runs on
+#[derive(Clone, Copy)]
+pub struct StateAccess<'t> {
+    pub memory: MemoryId,
+    pub instance: LocalId,
+    pub field: &'t StateField,
+}
+
+impl StateAccess<'_> {
+    /// Pushes the value of the field
+    pub fn load(&self, builder: &mut InstrSeqBuilder) {
+        for (offset, primitive) in &self.field.slots {
+            builder.local_get(self.instance).load(
+                self.memory,
+                load_kind(*primitive),
+                mem_arg(*offset, *primitive),
+            );
+        }
+    }
+
+    /// Pops a value of the field's type and stores it
+    pub fn store(&self, builder: &mut InstrSeqBuilder, module_locals: &mut ModuleLocals) {
+        let values = self
+            .field
+            .slots
+            .iter()
+            .map(|(_, primitive)| module_locals.add(primitive.val_type()))
+            .collect::<Vec<_>>();
+        for value in values.iter().rev() {
+            builder.local_set(*value);
+        }
+        for ((offset, primitive), value) in self.field.slots.iter().zip(values) {
+            builder.local_get(self.instance).local_get(value).store(
+                self.memory,
+                store_kind(*primitive),
+                mem_arg(*offset, *primitive),
+            );
+        }
+        if let Some(update) = self.field.update {
+            builder.local_get(self.instance).call(update);
+        }
+    }
+}
+
+/// An `i32` in the instance a function runs on, like the handle of an element
+#[derive(Clone, Copy)]
+pub struct InstanceSlot {
+    pub memory: MemoryId,
+    pub instance: LocalId,
+    pub offset: u32,
+}
+
+impl InstanceSlot {
+    /// Calls `drop` on the instance in the slot, unless it is empty
+    pub fn drop_instance(&self, builder: &mut InstrSeqBuilder, drop: FunctionId) {
+        self.load(builder);
+        builder.if_else(
+            None,
+            |then| {
+                self.load(then);
+                then.call(drop);
+            },
+            |_| {},
+        );
+    }
+
+    pub fn load(&self, builder: &mut InstrSeqBuilder) {
+        builder.local_get(self.instance).load(
+            self.memory,
+            LoadKind::I32 { atomic: false },
+            mem_arg(self.offset, Primitive::I32),
+        );
+    }
+
+    pub fn store(&self, builder: &mut InstrSeqBuilder, value: LocalId) {
+        builder.local_get(self.instance).local_get(value).store(
+            self.memory,
+            StoreKind::I32 { atomic: false },
+            mem_arg(self.offset, Primitive::I32),
+        );
+    }
+
+    pub fn set(&self, builder: &mut InstrSeqBuilder, value: i32) {
+        builder.local_get(self.instance).i32_const(value).store(
+            self.memory,
+            StoreKind::I32 { atomic: false },
+            mem_arg(self.offset, Primitive::I32),
+        );
+    }
+}
+
+/// The generated functions that manage the instances of a tag, exported as `Tag::new`
+/// and `Tag::drop`. Functions of the tag are named `Tag.function`, so these can't collide
+/// with them. Dropped instances are kept in a free list and reused by `new`.
+#[derive(Debug, Clone)]
+pub struct InstanceFunctions {
+    /// `new(props...) -> instance`, with every state set to its default
+    pub new: FunctionId,
+    /// `drop(instance)`, drops the instances of its custom tags as well
+    pub drop: FunctionId,
+    free: GlobalId,
+    /// The params of `new`, in the order the props are declared
+    pub props: Vec<PropParam>,
+}
+
+/// The params of `new` for one prop: its primitives, followed by a flag that is zero
+/// when the caller left out a prop with a default
+#[derive(Debug, Clone)]
+pub struct PropParam {
+    pub name: String,
+    pub type_: ChipType,
+    values: Vec<LocalId>,
+    pub given: Option<LocalId>,
+}
+
+impl InstanceFunctions {
+    /// Adds `new` and `drop` with empty bodies, see [`compile_instance_functions`]
+    pub fn declare(
+        module: &mut Module,
+        analysis: Analysis,
+        unit: &CompilationUnit,
+        tag_def: &TagDef,
+    ) -> Result<InstanceFunctions, WasmCodegenError> {
+        let tag_name = &unit[tag_def.ident].0;
+        let mut props = vec![];
+        for prop in &tag_def.props.0 {
+            let Ident(name, span) = &unit[prop.ident];
+            let type_ = analysis
+                .def_type(prop.ident)
+                .ok_or_else(|| WasmCodegenError::Untyped(name.clone(), *span))?;
+            let values = type_
+                .flatten(name)
+                .into_iter()
+                .map(|(_, primitive)| module.locals.add(primitive.val_type()))
+                .collect();
+            let given = prop.default.map(|_| module.locals.add(ValType::I32));
+            props.push(PropParam {
+                name: name.clone(),
+                type_,
+                values,
+                given,
+            });
+        }
+        let params = props
+            .iter()
+            .flat_map(|prop| prop.values.iter().chain(&prop.given))
+            .copied()
+            .collect::<Vec<_>>();
+        let instance = module.locals.add(ValType::I32);
+
+        let mut declare = |name: &str, args: Vec<LocalId>, results: &[ValType]| {
+            let params = args
+                .iter()
+                .map(|arg| module.locals.get(*arg).ty())
+                .collect::<Vec<_>>();
+            let mut builder = FunctionBuilder::new(&mut module.types, &params, results);
+            let name = format!("{tag_name}::{name}");
+            builder.name(name.clone());
+            let id = builder.finish(args, &mut module.funcs);
+            module.exports.add(&name, id);
+            id
+        };
+        let new = declare("new", params, &[ValType::I32]);
+        let drop = declare("drop", vec![instance], &[]);
+        Ok(InstanceFunctions {
+            new,
+            drop,
+            free: module
+                .globals
+                .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0))),
+            props,
+        })
+    }
+}
+
+/// Fills in `new` and `drop` of a tag and returns where its props and states are stored.
+/// `new` evaluates the defaults in order, so a default can read the props and states
+/// before it. A state without a default starts out as zero. Writing to a state that
+/// `view()` reads updates the affected properties, see [`analyze_view`].
+pub fn compile_instance_functions(
+    module: &mut Module,
+    heap: &Heap,
+    functions: &FunctionTable,
+    unit: &CompilationUnit,
+    tag_def: &TagDef,
+) -> Result<StateLayout, WasmCodegenError> {
+    let tag_name = &unit[tag_def.ident].0;
+    let Some(instance_functions) = functions.instance_functions(tag_name) else {
+        return Ok(StateLayout::default());
+    };
+    let callees = functions.callees(Some(tag_name));
+
+    let builder = module
+        .funcs
+        .get_mut(instance_functions.new)
+        .kind
+        .unwrap_local_mut()
+        .builder_mut();
+    let mut body = builder.func_body();
+    let mut locals = LocalMap::default();
+    let mut layout = StateLayout::default();
+    if tag_def.fns.0.iter().any(|fun| unit[fun.ident].0 == "view") {
+        layout.root = Some(ROOT_OFFSET);
+        layout.size = ROOT_OFFSET + 4;
+    }
+    for (prop, param) in tag_def.props.0.iter().zip(&instance_functions.props) {
+        let prop_locals = locals.declare(&mut module.locals, &param.name, param.type_.clone());
+        let use_param = |seq: &mut InstrSeqBuilder| {
+            for (value, local) in param.values.iter().zip(&prop_locals) {
+                seq.local_get(*value).local_set(*local);
+            }
+        };
+        match (prop.default, param.given) {
+            (Some(default), Some(given)) => {
+                let mut use_default = body.dangling_instr_seq(None);
+                compile_value(
+                    &mut use_default,
+                    &mut locals,
+                    &mut module.locals,
+                    callees,
+                    unit,
+                    default,
+                )?;
+                for local in prop_locals.iter().rev() {
+                    use_default.local_set(*local);
+                }
+                let alternative = use_default.id();
+                let mut consequent = body.dangling_instr_seq(None);
+                use_param(&mut consequent);
+                let consequent = consequent.id();
+                body.local_get(given).instr(IfElse {
+                    consequent,
+                    alternative,
+                });
+            }
+            _ => use_param(&mut body),
+        }
+        layout.add(&param.name, param.type_.clone());
+        layout.props.insert(param.name.clone());
+    }
+    for state in &tag_def.states.0 {
+        let Ident(name, span) = &unit[state.ident];
+        let type_ = callees
+            .analysis()
+            .def_type(state.ident)
+            .ok_or_else(|| WasmCodegenError::Untyped(name.clone(), *span))?;
+        if let Some(default) = state.default {
+            compile_value(
+                &mut body,
+                &mut locals,
+                &mut module.locals,
+                callees,
+                unit,
+                default,
+            )?;
+        }
+        // Without a default the state starts out as zero, like a new local
+        let state_locals = locals.declare(&mut module.locals, name, type_.clone());
+        if state.default.is_some() {
+            for local in state_locals.iter().rev() {
+                body.local_set(*local);
+            }
+        }
+        layout.add(name, type_);
+    }
+
+    let bindings = analyze_view(unit, tag_def, &layout, callees);
+    layout.add_view_slots(&bindings);
+    let mut children = CustomTags {
+        functions,
+        tags: vec![],
+    };
+    for fun in &tag_def.fns.0 {
+        children.visit_function_def(unit, fun);
+    }
+    layout.add_children(&children.tags);
+
+    let instance = module.locals.add(ValType::I32);
+    let free = instance_functions.free;
+    let first_word = mem_arg(0, Primitive::I32);
+    body.global_get(free).if_else(
+        None,
+        |reuse| {
+            reuse
+                .global_get(free)
+                .local_tee(instance)
+                .load(heap.memory, LoadKind::I32 { atomic: false }, first_word)
+                .global_set(free);
+        },
+        |allocate| {
+            allocate
+                .i32_const(layout.instance_size() as i32)
+                .call(heap.alloc)
+                .local_set(instance);
+        },
+    );
+    let fields = tag_def.props.0.iter().map(|prop| prop.ident);
+    for ident in fields.chain(tag_def.states.0.iter().map(|state| state.ident)) {
+        let Ident(name, span) = &unit[ident];
+        let Some(field) = layout.get(name) else {
+            continue;
+        };
+        let values = locals.primitive_locals(name, *span)?;
+        for ((offset, primitive), value) in field.slots.iter().zip(values) {
+            body.local_get(instance).local_get(value).store(
+                heap.memory,
+                store_kind(*primitive),
+                mem_arg(*offset, *primitive),
+            );
+        }
+    }
+    let slot = |offset| InstanceSlot {
+        memory: heap.memory,
+        instance,
+        offset,
+    };
+    if let Some(offset) = layout.rendered {
+        slot(offset).set(&mut body, 0);
+    }
+    // A reused instance still holds the children it had before it was dropped
+    for (tag, _) in &children.tags {
+        slot(layout.children[tag]).set(&mut body, 0);
+    }
+    body.local_get(instance);
+
+    let drop = module
+        .funcs
+        .get_mut(instance_functions.drop)
+        .kind
+        .unwrap_local_mut();
+    let instance = drop.args[0];
+    let mut body = drop.builder_mut().func_body();
+    // Before the link to the next free instance overwrites the first slot
+    for (tag, child_drop) in &children.tags {
+        let child = InstanceSlot {
+            memory: heap.memory,
+            instance,
+            offset: layout.children[tag],
+        };
+        child.drop_instance(&mut body, *child_drop);
+    }
+    body.local_get(instance)
+        .global_get(free)
+        .store(heap.memory, StoreKind::I32 { atomic: false }, first_word)
+        .local_get(instance)
+        .global_set(free);
+
+    let updates = compile_update_functions(module, tag_def, callees, &layout, unit, &bindings)?;
+    for (state, update) in updates {
+        if let Some(field) = layout.fields.get_mut(&state) {
+            field.update = Some(update);
+        }
+    }
+    Ok(layout)
+}
+
+/// Every custom tag visited, with the `drop` of its instances
+pub struct CustomTags<'f> {
+    pub functions: &'f FunctionTable<'f>,
+    pub tags: Vec<(IdentId, FunctionId)>,
+}
+
+impl<'u> Visitor<'u> for CustomTags<'_> {
+    fn visit_tag(&mut self, unit: &'u CompilationUnit, tag: &'u Tag) {
+        if let Some(instance_functions) = self.functions.instance_functions(&unit[tag.ident].0) {
+            self.tags.push((tag.ident, instance_functions.drop));
+        }
+        visit::walk_tag(self, unit, tag)
+    }
+}
+
+fn align(offset: u32, alignment: u32) -> u32 {
+    offset.div_ceil(alignment) * alignment
+}
+
+/// `U8`s are stored in a full `i32`, like on the stack
+fn slot_size(primitive: Primitive) -> u32 {
+    match primitive.val_type() {
+        ValType::I64 | ValType::F64 => 8,
+        _ => 4,
+    }
+}
+
+fn mem_arg(offset: u32, primitive: Primitive) -> MemArg {
+    MemArg {
+        align: slot_size(primitive),
+        offset,
+    }
+}
+
+fn load_kind(primitive: Primitive) -> LoadKind {
+    match primitive.val_type() {
+        ValType::I64 => LoadKind::I64 { atomic: false },
+        ValType::F32 => LoadKind::F32,
+        ValType::F64 => LoadKind::F64,
+        _ => LoadKind::I32 { atomic: false },
+    }
+}
+
+fn store_kind(primitive: Primitive) -> StoreKind {
+    match primitive.val_type() {
+        ValType::I64 => StoreKind::I64 { atomic: false },
+        ValType::F32 => StoreKind::F32,
+        ValType::F64 => StoreKind::F64,
+        _ => StoreKind::I32 { atomic: false },
+    }
+}
@@ -1,37 +1,269 @@
-// FIXME: As soon as this isn't in heavy development anymore, REMOVE THIS
-#![allow(unused_imports, dead_code, unused_variables)]
-use crate::ast::{Ident, Number, Opcode, Statement, StatementList, Type};
+use crate::ast::{
+    CompilationUnit, Ident, IdentId, NumType, Number, Opcode, Span, Statement, StatementList,
+    StmtId,
+};
+use crate::resolve::DefKind;
+use crate::typeck::Ty;
 use linked_hash_map::LinkedHashMap;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::HashMap;
 use std::rc::Rc;
-use walrus::ir::{BinaryOp, Instr, InstrSeq, Value};
-use walrus::{InstrSeqBuilder, LocalId, ModuleLocals, ModuleTypes, ValType};
+use walrus::ir::{BinaryOp, IfElse, UnaryOp};
+use walrus::{InstrSeqBuilder, LocalId, ModuleLocals, ValType};
 
-use self::error::WasmCodegenError;
+pub use self::error::WasmCodegenError;
+pub use self::module::compile_unit;
+use self::module::Callees;
+/// For compiling single statements in tests
+#[cfg(test)]
+pub use self::module::{Analysis, FunctionTable};
+pub use self::wat::print_wat;
 
 mod error;
+mod host;
+mod instance;
+mod module;
+mod reactive;
+mod wat;
 
+type Bindings = HashMap<String, (Option<LocalId>, ChipType)>;
+
+#[derive(Default)]
 pub struct LocalMap {
-    pub names: HashMap<String, (Option<LocalId>, ChipType)>,
+    pub names: Bindings,
+    /// The bindings of each enclosing block, restored once the inner block ends
+    outer_scopes: Vec<Bindings>,
+}
+
+impl LocalMap {
+    pub fn enter_scope(&mut self) {
+        self.outer_scopes.push(self.names.clone());
+    }
+
+    /// Forgets every binding made since the matching [`LocalMap::enter_scope`]
+    pub fn exit_scope(&mut self) {
+        if let Some(names) = self.outer_scopes.pop() {
+            self.names = names;
+        }
+    }
+
+    /// Removes `name` and all of its fields
+    pub fn forget(&mut self, name: &str) {
+        self.names.retain(|k, _| {
+            k != name
+                && !k
+                    .strip_prefix(name)
+                    .is_some_and(|rest| rest.starts_with('.'))
+        });
+    }
+
+    /// The locals of every primitive in `name`, in the order [`ChipType::flatten`] produces them
+    pub fn primitive_locals(
+        &self,
+        name: &str,
+        span: Span,
+    ) -> Result<Vec<LocalId>, WasmCodegenError> {
+        let undeclared = |name: &str| WasmCodegenError::UndeclaredVariable(name.into(), span);
+        let type_ = &self.names.get(name).ok_or_else(|| undeclared(name))?.1;
+        type_
+            .flatten(name)
+            .keys()
+            .map(|k| {
+                self.names
+                    .get(k)
+                    .and_then(|(local, _)| *local)
+                    .ok_or_else(|| undeclared(k))
+            })
+            .collect()
+    }
+
+    /// Allocates a local for every primitive in `type_` and binds them to `name`,
+    /// returns the locals in the order [`ChipType::flatten`] produces them
+    pub fn declare(
+        &mut self,
+        module_locals: &mut ModuleLocals,
+        name: &str,
+        type_: ChipType,
+    ) -> Vec<LocalId> {
+        let primitives = type_.flatten(name);
+
+        self.forget(name);
+        self.names.insert(name.into(), (None, type_));
+
+        primitives
+            .iter()
+            .map(|(k, v)| {
+                let local = module_locals.add(v.val_type());
+
+                self.names
+                    .insert(k.into(), (Some(local), ChipType::Primitive(*v)));
+
+                local
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Primitive {
-    F64,
+    I32,
     I64,
+    U8,
+    U32,
+    U64,
+    F32,
+    F64,
+}
+
+impl Primitive {
+    pub fn val_type(&self) -> ValType {
+        match self {
+            Primitive::I32 | Primitive::U8 | Primitive::U32 => ValType::I32,
+            Primitive::I64 | Primitive::U64 => ValType::I64,
+            Primitive::F32 => ValType::F32,
+            Primitive::F64 => ValType::F64,
+        }
+    }
+
+    pub fn is_signed(&self) -> bool {
+        !matches!(self, Primitive::U8 | Primitive::U32 | Primitive::U64)
+    }
+
+    /// The instruction for `op` on two values of this type
+    fn binop(&self, op: &Opcode) -> BinaryOp {
+        use self::BinaryOp::*;
+        let signed = self.is_signed();
+        match (self.val_type(), op) {
+            (ValType::I32, Opcode::Mul) => I32Mul,
+            (ValType::I32, Opcode::Div) if signed => I32DivS,
+            (ValType::I32, Opcode::Div) => I32DivU,
+            (ValType::I32, Opcode::Add) => I32Add,
+            (ValType::I32, Opcode::Sub) => I32Sub,
+            (ValType::I32, Opcode::Gt) if signed => I32GtS,
+            (ValType::I32, Opcode::Gt) => I32GtU,
+            (ValType::I32, Opcode::Ge) if signed => I32GeS,
+            (ValType::I32, Opcode::Ge) => I32GeU,
+            (ValType::I32, Opcode::Lt) if signed => I32LtS,
+            (ValType::I32, Opcode::Lt) => I32LtU,
+            (ValType::I32, Opcode::Le) if signed => I32LeS,
+            (ValType::I32, Opcode::Le) => I32LeU,
+            (ValType::I32, Opcode::Eq) => I32Eq,
+            (ValType::I32, Opcode::Ne) => I32Ne,
+            (ValType::I64, Opcode::Mul) => I64Mul,
+            (ValType::I64, Opcode::Div) if signed => I64DivS,
+            (ValType::I64, Opcode::Div) => I64DivU,
+            (ValType::I64, Opcode::Add) => I64Add,
+            (ValType::I64, Opcode::Sub) => I64Sub,
+            (ValType::I64, Opcode::Gt) if signed => I64GtS,
+            (ValType::I64, Opcode::Gt) => I64GtU,
+            (ValType::I64, Opcode::Ge) if signed => I64GeS,
+            (ValType::I64, Opcode::Ge) => I64GeU,
+            (ValType::I64, Opcode::Lt) if signed => I64LtS,
+            (ValType::I64, Opcode::Lt) => I64LtU,
+            (ValType::I64, Opcode::Le) if signed => I64LeS,
+            (ValType::I64, Opcode::Le) => I64LeU,
+            (ValType::I64, Opcode::Eq) => I64Eq,
+            (ValType::I64, Opcode::Ne) => I64Ne,
+            (ValType::F32, Opcode::Mul) => F32Mul,
+            (ValType::F32, Opcode::Div) => F32Div,
+            (ValType::F32, Opcode::Add) => F32Add,
+            (ValType::F32, Opcode::Sub) => F32Sub,
+            (ValType::F32, Opcode::Gt) => F32Gt,
+            (ValType::F32, Opcode::Ge) => F32Ge,
+            (ValType::F32, Opcode::Lt) => F32Lt,
+            (ValType::F32, Opcode::Le) => F32Le,
+            (ValType::F32, Opcode::Eq) => F32Eq,
+            (ValType::F32, Opcode::Ne) => F32Ne,
+            (_, Opcode::Mul) => F64Mul,
+            (_, Opcode::Div) => F64Div,
+            (_, Opcode::Add) => F64Add,
+            (_, Opcode::Sub) => F64Sub,
+            (_, Opcode::Gt) => F64Gt,
+            (_, Opcode::Ge) => F64Ge,
+            (_, Opcode::Lt) => F64Lt,
+            (_, Opcode::Le) => F64Le,
+            (_, Opcode::Eq) => F64Eq,
+            (_, Opcode::Ne) => F64Ne,
+        }
+    }
+
+    /// The conversion from a value of this type to `to`, if they aren't represented the same way.
+    /// Narrowing to `U8` additionally needs [`mask_u8`].
+    fn conversion(&self, to: Primitive) -> Option<UnaryOp> {
+        use self::UnaryOp::*;
+        let from_signed = self.is_signed();
+        let to_signed = to.is_signed();
+        let op = match (self.val_type(), to.val_type()) {
+            (ValType::I64, ValType::I32) => I32WrapI64,
+            (ValType::I32, ValType::I64) if from_signed => I64ExtendSI32,
+            (ValType::I32, ValType::I64) => I64ExtendUI32,
+            (ValType::I32, ValType::F32) if from_signed => F32ConvertSI32,
+            (ValType::I32, ValType::F32) => F32ConvertUI32,
+            (ValType::I64, ValType::F32) if from_signed => F32ConvertSI64,
+            (ValType::I64, ValType::F32) => F32ConvertUI64,
+            (ValType::I32, ValType::F64) if from_signed => F64ConvertSI32,
+            (ValType::I32, ValType::F64) => F64ConvertUI32,
+            (ValType::I64, ValType::F64) if from_signed => F64ConvertSI64,
+            (ValType::I64, ValType::F64) => F64ConvertUI64,
+            (ValType::F32, ValType::I32) if to_signed => I32TruncSF32,
+            (ValType::F32, ValType::I32) => I32TruncUF32,
+            (ValType::F64, ValType::I32) if to_signed => I32TruncSF64,
+            (ValType::F64, ValType::I32) => I32TruncUF64,
+            (ValType::F32, ValType::I64) if to_signed => I64TruncSF32,
+            (ValType::F32, ValType::I64) => I64TruncUF32,
+            (ValType::F64, ValType::I64) if to_signed => I64TruncSF64,
+            (ValType::F64, ValType::I64) => I64TruncUF64,
+            (ValType::F32, ValType::F64) => F64PromoteF32,
+            (ValType::F64, ValType::F32) => F32DemoteF64,
+            _ => return None,
+        };
+        Some(op)
+    }
+}
+
+impl From<NumType> for Primitive {
+    fn from(num_type: NumType) -> Primitive {
+        match num_type {
+            NumType::I32 => Primitive::I32,
+            NumType::I64 => Primitive::I64,
+            NumType::U8 => Primitive::U8,
+            NumType::U32 => Primitive::U32,
+            NumType::U64 => Primitive::U64,
+            NumType::F32 => Primitive::F32,
+            NumType::F64 => Primitive::F64,
+        }
+    }
+}
+
+/// `U8`s live in an `i32`, so everything that can overflow has to cut them back to 8 bits
+fn mask_u8(builder: &mut InstrSeqBuilder) {
+    builder.i32_const(0xFF);
+    builder.binop(BinaryOp::I32And);
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ChipType {
+    /// Fields are kept in locals named `name.field`. Nothing in the language has a struct type
+    /// yet, so only tests create these.
+    #[allow(dead_code)]
     Struct(Rc<LinkedHashMap<String, ChipType>>),
     Primitive(Primitive),
 }
 
 impl ChipType {
+    /// How a value of a checked type is represented, `None` for `()`.
+    /// `Bool`s are wasm booleans and `Tag`s handles to host elements, both fit into an `i32`.
+    pub fn from_ty(ty: &Ty) -> Option<ChipType> {
+        match ty {
+            Ty::Num(num_type) => Some(ChipType::Primitive((*num_type).into())),
+            Ty::Bool | Ty::Tag => Some(ChipType::Primitive(Primitive::I32)),
+            Ty::Unit | Ty::Error => None,
+        }
+    }
+
     pub fn flatten(&self, name: &str) -> LinkedHashMap<String, Primitive> {
         let mut map = LinkedHashMap::new();
 
-        ChipType::flatten_inner(name, &self, &mut map);
+        ChipType::flatten_inner(name, self, &mut map);
 
         map
     }
@@ -40,7 +272,7 @@ impl ChipType {
         match type_ {
             ChipType::Struct(struct_) => struct_.iter().for_each(|(field_name, field_type)| {
                 let mut recurse_name = name.to_string();
-                recurse_name.push_str(".");
+                recurse_name.push('.');
                 recurse_name.push_str(field_name);
 
                 ChipType::flatten_inner(&recurse_name, field_type, map);
@@ -52,236 +284,417 @@ impl ChipType {
     }
 }
 
-pub fn compile_statement_wasm<'a>(
+/// Emits the instructions of a statement and returns the type of the value it leaves on the stack
+pub fn compile_statement_wasm(
     builder: &mut InstrSeqBuilder,
-    func_locals: &'a mut LocalMap,
+    func_locals: &mut LocalMap,
     module_locals: &mut ModuleLocals,
-    statement: &'a Statement<'a>,
-) -> anyhow::Result<Option<ChipType>> {
-    match statement {
-        Statement::Number(num) => {
-            return match num {
-                Number::Int(int) => {
-                    builder.i64_const(*int);
-
-                    Ok(Some(ChipType::Primitive(Primitive::I64)))
-                }
-                Number::Float(float) => {
-                    builder.f64_const(*float);
-
-                    Ok(Some(ChipType::Primitive(Primitive::F64)))
-                }
+    functions: Callees,
+    unit: &CompilationUnit,
+    statement: StmtId,
+) -> Result<Option<ChipType>, WasmCodegenError> {
+    let span = unit.span(statement).unwrap_or_default();
+    match &unit[statement] {
+        Statement::Number(num, _) => {
+            let primitive = Primitive::from(num.num_type());
+            match (num, primitive.val_type()) {
+                (Number::Int(int, _), ValType::I32) => builder.i32_const(*int as i32),
+                (Number::Int(int, _), _) => builder.i64_const(*int),
+                (Number::Float(float, _), ValType::F32) => builder.f32_const(*float as f32),
+                (Number::Float(float, _), _) => builder.f64_const(*float),
             };
+
+            return Ok(Some(ChipType::Primitive(primitive)));
         }
         Statement::Op(statement_1, comp, statement_2) => {
-            let type_1 = compile_statement_wasm(builder, func_locals, module_locals, statement_1)?
-                .ok_or(WasmCodegenError::MustHaveReturnValue(format!(
-                    "{statement_1:?}"
-                )))?;
-            let type_2 = compile_statement_wasm(builder, func_locals, module_locals, statement_2)?
-                .ok_or(WasmCodegenError::MustHaveReturnValue(format!(
-                    "{statement_2:?}"
-                )))?;
-
-            let primitive_1 = match type_1 {
-                ChipType::Struct(_) => {
-                    Err(WasmCodegenError::NoStructInOp(format!("{statement_1:?}")))?
+            let primitive_1 = compile_primitive(
+                builder,
+                func_locals,
+                module_locals,
+                functions,
+                unit,
+                *statement_1,
+            )?;
+            // Both sides have the same type after type checking
+            compile_primitive(
+                builder,
+                func_locals,
+                module_locals,
+                functions,
+                unit,
+                *statement_2,
+            )?;
+
+            builder.binop(primitive_1.binop(comp));
+
+            return Ok(Some(match comp {
+                Opcode::Mul | Opcode::Add | Opcode::Sub => {
+                    if primitive_1 == Primitive::U8 {
+                        mask_u8(builder);
+                    }
+                    ChipType::Primitive(primitive_1)
                 }
-                ChipType::Primitive(prim) => prim,
-            };
-
-            let primitive_2 = match type_2 {
-                ChipType::Struct(_) => {
-                    Err(WasmCodegenError::NoStructInOp(format!("{statement_2:?}")))?
-                }
-                ChipType::Primitive(prim) => prim,
-            };
-
-            if primitive_1 != primitive_2 {
-                Err(WasmCodegenError::CompNonEqualType(primitive_1, primitive_2))?;
+                Opcode::Div => ChipType::Primitive(primitive_1),
+                // Comparisons produce a wasm boolean
+                _ => ChipType::Primitive(Primitive::I32),
+            }));
+        }
+        Statement::FunctionCall(call) => {
+            let Ident(name, name_span) = &unit[call.ident];
+            let callee = functions
+                .get(name)
+                .ok_or_else(|| WasmCodegenError::UnknownFunction(name.clone(), *name_span))?;
+
+            // Functions of a tag run on the same instance as the caller
+            if callee.instance {
+                let instance = functions.instance().ok_or(WasmCodegenError::Unsupported(
+                    "Calling tag functions without an instance",
+                    *name_span,
+                ))?;
+                builder.local_get(instance);
             }
 
-            let int_or_float = primitive_1 == Primitive::I64;
-
-            match comp {
-                Opcode::Mul => builder.binop(if int_or_float {
-                    BinaryOp::I64Mul
-                } else {
-                    BinaryOp::F64Mul
-                }),
-                Opcode::Div => builder.binop(if int_or_float {
-                    BinaryOp::I64DivS
-                } else {
-                    BinaryOp::F64Div
-                }),
-                Opcode::Add => builder.binop(if int_or_float {
-                    BinaryOp::I64Add
-                } else {
-                    BinaryOp::F64Add
-                }),
-                Opcode::Sub => builder.binop(if int_or_float {
-                    BinaryOp::I64Sub
-                } else {
-                    BinaryOp::F64Sub
-                }),
-                Opcode::Gt => builder.binop(if int_or_float {
-                    BinaryOp::I64GtS
-                } else {
-                    BinaryOp::F64Gt
-                }),
-                Opcode::Ge => builder.binop(if int_or_float {
-                    BinaryOp::I64GeS
-                } else {
-                    BinaryOp::F64Ge
-                }),
-                Opcode::Lt => builder.binop(if int_or_float {
-                    BinaryOp::I64LtS
-                } else {
-                    BinaryOp::F64Lt
-                }),
-                Opcode::Le => builder.binop(if int_or_float {
-                    BinaryOp::I64LeS
-                } else {
-                    BinaryOp::F64Le
-                }),
-                Opcode::Eq => builder.binop(if int_or_float {
-                    BinaryOp::I64Eq
-                } else {
-                    BinaryOp::F64Eq
-                }),
-                Opcode::Ne => builder.binop(if int_or_float {
-                    BinaryOp::I64Ne
-                } else {
-                    BinaryOp::F64Ne
-                }),
-            };
+            // Each argument pushes its flattened primitives, in the order of the params
+            for arg in &call.args.0 {
+                compile_value(builder, func_locals, module_locals, functions, unit, *arg)?;
+            }
 
-            //Specifically make sure that there is a value here
-            return Ok(Some(type_2));
+            builder.call(callee.id);
+            return Ok(callee.result.clone());
         }
-        Statement::FunctionCall(_) => {}
         Statement::If(condition, block) => {
-            // let mut consequent = builder.dangling_instr_seq(None);
-            //
-            // let no_else = builder.dangling_instr_seq(None);
-            //
-            // parse_statement(builder, func_locals, module_locals, &condition);
-            //
-            // let block_return_type = block.0.iter().map(|statement| parse_statement(
-            //     &mut consequent,
-            //     func_locals,
-            //     module_locals,
-            //     &statement
-            // )).last().flatten();
-            //
-            // consequent.finish(vec![], )
-            //
-            // builder.instr(Instr::IfElse {
-            //     consequent,
-            //     alternative: ()
-            // })
-
-            // builder.if_else(match block_return_type {
-            //     None => None,
-            //     Some(type_) => match type_ {
-            //         ChipType::Struct(_) => panic!("Cannot return a non-bool value in if conditional"),
-            //         ChipType::Primitive(prim) => match prim {
-            //             Primitive::F64 => ValType::F64,
-            //             Primitive::I64 => ValType::I64
-            //         }
-            //     }
-            // }, );
+            compile_condition(
+                builder,
+                func_locals,
+                module_locals,
+                functions,
+                unit,
+                *condition,
+            )?;
+            return compile_branches(
+                builder,
+                func_locals,
+                module_locals,
+                functions,
+                unit,
+                (block, &StatementList(vec![])),
+            );
+        }
+        Statement::IfElse(condition, if_block, else_block) => {
+            compile_condition(
+                builder,
+                func_locals,
+                module_locals,
+                functions,
+                unit,
+                *condition,
+            )?;
+            let type_ = compile_branches(
+                builder,
+                func_locals,
+                module_locals,
+                functions,
+                unit,
+                (if_block, else_block),
+            )?;
+            // Kept for building the branch again when a state it reads changes
+            if let Some(slot) = functions.branch(statement) {
+                let element = module_locals.add(ValType::I32);
+                builder.local_tee(element);
+                slot.store(builder, element);
+            }
+            return Ok(type_);
         }
-        Statement::IfElse(_, _, _) => {}
         Statement::Assign(ident, assign_statement) => {
             //Push the right hand value onto the stack
-            let statement_type =
-                compile_statement_wasm(builder, func_locals, module_locals, &assign_statement)?
-                    .expect("Right-hand side of let assignment must have return type");
+            compile_value(
+                builder,
+                func_locals,
+                module_locals,
+                functions,
+                unit,
+                *assign_statement,
+            )?;
+
+            if let [root] = ident.0.as_slice() {
+                if let Some(state) = instance_state(functions, unit, *root) {
+                    state.store(builder, module_locals);
+                    return Ok(None);
+                }
+            }
 
             let left_name: String = ident
                 .0
                 .iter()
-                .rev()
-                .map(|ident| ident.0)
+                .map(|ident| unit[*ident].0.as_str())
                 .collect::<Vec<&str>>()
                 .join(".");
 
-            println!("{:?}\n{}", func_locals.names, left_name);
-            let ident_type = func_locals.names.get(&left_name).unwrap();
-
-            if statement_type != ident_type.1 {
-                panic!(
-                    "Assignment `{:?}` must have same type on left and right hand side",
-                    statement
-                );
-            }
-
-            let primitives = ident_type.1.flatten(&left_name);
-
             //Reverse the order because of how stacks are
-            primitives.iter().rev().for_each(|(k, v)| {
-                let local = func_locals.names.get(k).unwrap().0.unwrap();
-
-                builder.local_set(local);
-            });
+            for local in func_locals.primitive_locals(&left_name, span)?.iter().rev() {
+                builder.local_set(*local);
+            }
         }
         Statement::Ident(ident) => {
-            let local = func_locals
+            let Ident(name, name_span) = &unit[*ident];
+            // `true` and `false` are the only built-in values
+            if let Some(def) = functions.analysis().def(*ident) {
+                if def.kind == DefKind::Builtin {
+                    builder.i32_const((name == "true") as i32);
+                    return Ok(Some(ChipType::Primitive(Primitive::I32)));
+                }
+            }
+            if let Some(state) = instance_state(functions, unit, *ident) {
+                state.load(builder);
+                return Ok(Some(state.field.type_.clone()));
+            }
+            let type_ = func_locals
                 .names
-                .get(ident.0)
-                .expect(&format!("Undeclared local variable {}", ident.0));
-
-            let primitives = local.1.flatten(ident.0);
+                .get(name)
+                .ok_or_else(|| WasmCodegenError::UndeclaredVariable(name.clone(), *name_span))?
+                .1
+                .clone();
 
-            primitives.iter().for_each(|(k, _)| {
-                dbg!(k);
-                let local = func_locals.names.get(k).unwrap().0;
-
-                builder.local_get(local.unwrap());
-            });
+            for local in func_locals.primitive_locals(name, *name_span)? {
+                builder.local_get(local);
+            }
 
-            return Ok(Some(local.1.clone()));
+            return Ok(Some(type_));
+        }
+        Statement::Let(_, ident, _) => {
+            let name = &unit[*ident].0;
+            // Without an annotation the type comes from the first assignment,
+            // a `let` that is never assigned doesn't need any locals
+            match functions.analysis().def_type(*ident) {
+                Some(type_) => {
+                    func_locals.declare(module_locals, name, type_);
+                }
+                None => func_locals.forget(name),
+            }
         }
-        Statement::Let(_) => {}
-        Statement::LetAssign(ident, statement) => {
+        Statement::LetAssign(_, ident, _, statement) => {
             //Push the right hand value onto the stack
-            let type_ = compile_statement_wasm(builder, func_locals, module_locals, &statement)?
-                .expect("Right-hand side of let assignment must have return type");
-
-            let primitives = type_.flatten(ident.0);
+            let type_ = compile_value(
+                builder,
+                func_locals,
+                module_locals,
+                functions,
+                unit,
+                *statement,
+            )?;
 
-            func_locals.names.insert(ident.0.into(), (None, type_));
+            let locals = func_locals.declare(module_locals, &unit[*ident].0, type_);
 
             //Reverse the order because of how stacks are
-            primitives.iter().rev().for_each(|(k, v)| {
-                let local = module_locals.add(match v {
-                    Primitive::F64 => ValType::F64,
-                    Primitive::I64 => ValType::I64,
-                });
-
-                func_locals
-                    .names
-                    .insert(k.into(), (Some(local), ChipType::Primitive(*v)));
-
-                builder.local_set(local);
+            locals.iter().rev().for_each(|local| {
+                builder.local_set(*local);
             });
         }
         Statement::Block(statements) => {
-            let mut last = None;
-            for statement in &statements.0 {
-                last = Some(compile_statement_wasm(
-                    builder,
-                    func_locals,
-                    module_locals,
-                    &statement,
-                )?);
+            return compile_block(
+                builder,
+                func_locals,
+                module_locals,
+                functions,
+                unit,
+                statements,
+            );
+        }
+        Statement::Cast(value, chip_type) => {
+            let from =
+                compile_primitive(builder, func_locals, module_locals, functions, unit, *value)?;
+            let to = match functions.analysis().types.types.get(&statement) {
+                Some(Ty::Num(num_type)) => Primitive::from(*num_type),
+                _ => {
+                    let Ident(name, span) = &unit[*chip_type];
+                    return Err(WasmCodegenError::Untyped(name.clone(), *span));
+                }
+            };
+
+            if let Some(op) = from.conversion(to) {
+                builder.unop(op);
+            }
+            if to == Primitive::U8 && from != Primitive::U8 {
+                mask_u8(builder);
             }
-            return Ok(last.flatten());
+
+            return Ok(Some(ChipType::Primitive(to)));
         }
         Statement::Error => {}
-        Statement::Tag(_) => todo!(),
+        Statement::Tag(tag) => {
+            return host::compile_tag(builder, func_locals, module_locals, functions, unit, tag)
+                .map(Some)
+        }
     }
 
     Ok(None)
 }
+
+/// The prop or state `ident` refers to, if the body runs on an instance. The defaults in
+/// `new` read the fields before them from locals, since there is no instance yet.
+fn instance_state<'t>(
+    functions: Callees<'t>,
+    unit: &CompilationUnit,
+    ident: IdentId,
+) -> Option<instance::StateAccess<'t>> {
+    let def = functions.analysis().def(ident)?;
+    if !matches!(def.kind, DefKind::Prop | DefKind::State) {
+        return None;
+    }
+    functions.state(&unit[ident].0)
+}
+
+/// Like [`compile_statement_wasm`], for statements that have to leave a value on the stack
+fn compile_value(
+    builder: &mut InstrSeqBuilder,
+    func_locals: &mut LocalMap,
+    module_locals: &mut ModuleLocals,
+    functions: Callees,
+    unit: &CompilationUnit,
+    statement: StmtId,
+) -> Result<ChipType, WasmCodegenError> {
+    let span = unit.span(statement).unwrap_or_default();
+    compile_statement_wasm(
+        builder,
+        func_locals,
+        module_locals,
+        functions,
+        unit,
+        statement,
+    )?
+    .ok_or(WasmCodegenError::MustHaveReturnValue(span))
+}
+
+/// Like [`compile_value`], for operands that can't be structs
+fn compile_primitive(
+    builder: &mut InstrSeqBuilder,
+    func_locals: &mut LocalMap,
+    module_locals: &mut ModuleLocals,
+    functions: Callees,
+    unit: &CompilationUnit,
+    statement: StmtId,
+) -> Result<Primitive, WasmCodegenError> {
+    match compile_value(
+        builder,
+        func_locals,
+        module_locals,
+        functions,
+        unit,
+        statement,
+    )? {
+        ChipType::Primitive(primitive) => Ok(primitive),
+        ChipType::Struct(_) => Err(WasmCodegenError::NoStructInOp(
+            unit.span(statement).unwrap_or_default(),
+        )),
+    }
+}
+
+/// Compiles the statements in their own scope, only the value of the last one is kept
+fn compile_block(
+    builder: &mut InstrSeqBuilder,
+    func_locals: &mut LocalMap,
+    module_locals: &mut ModuleLocals,
+    functions: Callees,
+    unit: &CompilationUnit,
+    statements: &StatementList,
+) -> Result<Option<ChipType>, WasmCodegenError> {
+    func_locals.enter_scope();
+    let mut last = Ok(None);
+    for statement in &statements.0 {
+        if let Ok(type_) = last {
+            module::drop_value(builder, type_);
+        }
+        last = compile_statement_wasm(
+            builder,
+            func_locals,
+            module_locals,
+            functions,
+            unit,
+            *statement,
+        );
+        if last.is_err() {
+            break;
+        }
+    }
+    func_locals.exit_scope();
+    last
+}
+
+/// Pushes the condition of an `if`, a `Bool` is a wasm boolean
+fn compile_condition(
+    builder: &mut InstrSeqBuilder,
+    func_locals: &mut LocalMap,
+    module_locals: &mut ModuleLocals,
+    functions: Callees,
+    unit: &CompilationUnit,
+    condition: StmtId,
+) -> Result<(), WasmCodegenError> {
+    compile_value(
+        builder,
+        func_locals,
+        module_locals,
+        functions,
+        unit,
+        condition,
+    )
+    .map(|_| ())
+}
+
+/// Emits an `if` on the condition that was just pushed.
+/// The type of a branch is only known once it is compiled, so both branches store their value
+/// in the same locals, which are pushed after the `if`. This also works for structs.
+/// If only one branch has a value, it is dropped and the `if` has none.
+fn compile_branches(
+    builder: &mut InstrSeqBuilder,
+    func_locals: &mut LocalMap,
+    module_locals: &mut ModuleLocals,
+    functions: Callees,
+    unit: &CompilationUnit,
+    (consequent, alternative): (&StatementList, &StatementList),
+) -> Result<Option<ChipType>, WasmCodegenError> {
+    let mut compile_branch = |builder: &mut InstrSeqBuilder, statements| {
+        let id = builder.dangling_instr_seq(None).id();
+        compile_block(
+            &mut builder.instr_seq(id),
+            func_locals,
+            module_locals,
+            functions,
+            unit,
+            statements,
+        )
+        .map(|type_| (id, type_))
+    };
+    let (consequent, if_type) = compile_branch(builder, consequent)?;
+    let (alternative, else_type) = compile_branch(builder, alternative)?;
+
+    // Branches that both have a value have the same type after type checking
+    let type_ = match (if_type, else_type) {
+        (Some(if_type), Some(_)) => Some(if_type),
+        (if_type, else_type) => {
+            module::drop_value(&mut builder.instr_seq(consequent), if_type);
+            module::drop_value(&mut builder.instr_seq(alternative), else_type);
+            None
+        }
+    };
+
+    let results = type_
+        .iter()
+        .flat_map(|type_| type_.flatten("").into_iter())
+        .map(|(_, primitive)| module_locals.add(primitive.val_type()))
+        .collect::<Vec<_>>();
+    for branch in [consequent, alternative] {
+        let mut branch = builder.instr_seq(branch);
+        results.iter().rev().for_each(|local| {
+            branch.local_set(*local);
+        });
+    }
+
+    builder.instr(IfElse {
+        consequent,
+        alternative,
+    });
+    results.iter().for_each(|local| {
+        builder.local_get(*local);
+    });
+    Ok(type_)
+}
@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+
+use walrus::{FunctionBuilder, FunctionId, InstrSeqBuilder, LocalId, Module, ValType};
+
+use crate::ast::{CompilationUnit, FunctionDef, Ident, IdentId, StmtId, TopLevelDef};
+use crate::resolve::{Def, Resolution};
+use crate::typeck::TypeckResults;
+use crate::visit::Visitor;
+
+use super::error::WasmCodegenError;
+use super::host::{compile_tag, Host};
+use super::instance::{
+    compile_instance_functions, CustomTags, Heap, InstanceFunctions, InstanceSlot, StateAccess,
+    StateLayout,
+};
+use super::{compile_statement_wasm, ChipType, LocalMap};
+
+/// What name resolution and type checking found out about the unit being compiled
+#[derive(Clone, Copy)]
+pub struct Analysis<'a> {
+    pub resolution: &'a Resolution,
+    pub types: &'a TypeckResults,
+}
+
+impl<'a> Analysis<'a> {
+    /// The definition `ident` refers to, or introduces
+    pub fn def(&self, ident: IdentId) -> Option<&'a Def> {
+        let id = self
+            .resolution
+            .refs
+            .get(&ident)
+            .or_else(|| self.resolution.decls.get(&ident))?;
+        Some(self.resolution.def(*id))
+    }
+
+    /// The type of the value definition `ident` introduces, `None` for `()`
+    pub fn def_type(&self, ident: IdentId) -> Option<ChipType> {
+        let id = self.resolution.decls.get(&ident)?;
+        ChipType::from_ty(self.types.def_types.get(id)?)
+    }
+
+    /// The argument types and the result type of the function `ident` introduces
+    pub fn signature(&self, ident: IdentId) -> Option<(Vec<ChipType>, Option<ChipType>)> {
+        let id = self.resolution.decls.get(&ident)?;
+        let sig = self.types.fn_sigs.get(id)?;
+        let args = sig
+            .args
+            .iter()
+            .map(ChipType::from_ty)
+            .collect::<Option<_>>()?;
+        Some((args, ChipType::from_ty(&sig.ret)))
+    }
+}
+
+/// The signature of every function in a unit by name, tag functions are named `Tag.function`.
+/// All of them are declared before any body is compiled, so a call can refer to a function
+/// defined further down.
+pub struct FunctionTable<'a> {
+    analysis: Analysis<'a>,
+    functions: HashMap<String, FunctionSignature>,
+    /// Imported if the unit has tags
+    host: Option<Host>,
+    /// Allocates the instances if the unit defines tags
+    heap: Option<Heap>,
+    /// `new` and `drop` of every tag definition
+    instances: HashMap<String, InstanceFunctions>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionSignature {
+    pub id: FunctionId,
+    pub result: Option<ChipType>,
+    /// Functions of a tag take the instance they run on as an extra first param
+    pub instance: bool,
+}
+
+impl<'a> FunctionTable<'a> {
+    pub fn new(analysis: Analysis<'a>) -> FunctionTable<'a> {
+        FunctionTable {
+            analysis,
+            functions: HashMap::new(),
+            host: None,
+            heap: None,
+            instances: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&FunctionSignature> {
+        self.functions.get(name)
+    }
+
+    /// The functions a body in `tag`, or outside of any tag with `None`, can call
+    pub fn callees<'t>(&'t self, tag: Option<&'t str>) -> Callees<'t> {
+        Callees {
+            table: self,
+            tag,
+            instance: None,
+            handles: None,
+        }
+    }
+
+    pub fn instance_functions(&self, tag: &str) -> Option<&InstanceFunctions> {
+        self.instances.get(tag)
+    }
+}
+
+/// Functions of the enclosing tag shadow the top-level ones, like in name resolution.
+/// Inside of a tag's functions, its states are stored in the instance they run on.
+#[derive(Clone, Copy)]
+pub struct Callees<'t> {
+    table: &'t FunctionTable<'t>,
+    tag: Option<&'t str>,
+    instance: Option<(LocalId, &'t StateLayout)>,
+    /// Where an `export` keeps the instances of its custom tags, to return them
+    handles: Option<&'t HashMap<IdentId, LocalId>>,
+}
+
+impl<'t> Callees<'t> {
+    pub fn analysis(&self) -> Analysis<'t> {
+        self.table.analysis
+    }
+
+    pub fn get(&self, name: &str) -> Option<&'t FunctionSignature> {
+        self.tag
+            .and_then(|tag| self.table.get(&format!("{tag}.{name}")))
+            .or_else(|| self.table.get(name))
+    }
+
+    /// The `view()` of a custom tag
+    pub fn view(&self, tag: &str) -> Option<&'t FunctionSignature> {
+        self.table.get(&format!("{tag}.view"))
+    }
+
+    pub fn host(&self) -> Option<&'t Host> {
+        self.table.host.as_ref()
+    }
+
+    pub fn instance_functions(&self, tag: &str) -> Option<&'t InstanceFunctions> {
+        self.table.instance_functions(tag)
+    }
+
+    /// For the body of a function that runs on `instance`
+    pub fn with_instance(self, instance: LocalId, layout: &'t StateLayout) -> Callees<'t> {
+        Callees {
+            instance: Some((instance, layout)),
+            ..self
+        }
+    }
+
+    /// For the body of an `export`, which returns the instances in `handles`
+    pub fn with_handles(self, handles: &'t HashMap<IdentId, LocalId>) -> Callees<'t> {
+        Callees {
+            handles: Some(handles),
+            ..self
+        }
+    }
+
+    /// The local an `export` keeps the instance of the custom tag `tag` in
+    pub fn handle(&self, tag: IdentId) -> Option<LocalId> {
+        self.handles?.get(&tag).copied()
+    }
+
+    /// The local holding the instance the body runs on
+    pub fn instance(&self) -> Option<LocalId> {
+        self.instance.map(|(instance, _)| instance)
+    }
+
+    /// Where the element created for `tag` is kept, if a property of it reads a state
+    pub fn element(&self, tag: IdentId) -> Option<InstanceSlot> {
+        self.slot(|layout| layout.element(tag))
+    }
+
+    /// Where the instance created for the custom tag `tag` is kept
+    pub fn child(&self, tag: IdentId) -> Option<InstanceSlot> {
+        self.slot(|layout| layout.child(tag))
+    }
+
+    /// Where the element that the branch `id` of `view()` resulted in is kept,
+    /// if it is built again on updates
+    pub fn branch(&self, id: StmtId) -> Option<InstanceSlot> {
+        self.slot(|layout| layout.branch(id))
+    }
+
+    /// Where `view()` keeps the element it returned
+    pub fn root(&self) -> Option<InstanceSlot> {
+        self.slot(StateLayout::root)
+    }
+
+    /// Where `view()` marks the instance as rendered, if it has elements to update
+    pub fn rendered(&self) -> Option<InstanceSlot> {
+        self.slot(StateLayout::rendered)
+    }
+
+    fn slot(&self, offset: impl Fn(&StateLayout) -> Option<u32>) -> Option<InstanceSlot> {
+        let (instance, layout) = self.instance?;
+        Some(InstanceSlot {
+            memory: self.table.heap.as_ref()?.memory,
+            instance,
+            offset: offset(layout)?,
+        })
+    }
+
+    /// The prop or state `name` of the instance the body runs on
+    pub fn state(&self, name: &str) -> Option<StateAccess<'t>> {
+        let (instance, layout) = self.instance?;
+        Some(StateAccess {
+            memory: self.table.heap.as_ref()?.memory,
+            instance,
+            field: layout.get(name)?,
+        })
+    }
+}
+
+/// Lowers a unit without errors into a module, using the types and names found for it.
+/// Every `export` becomes an exported function that takes the handle of a host element
+/// and appends its tags to it. It returns the instances of its custom tags in the order
+/// they appear, for the host to pass to their functions and drop them. The functions of
+/// a tag are exported as well, see [`InstanceFunctions`]. Top-level functions and the
+/// update functions of states are only called from inside the module, so they aren't.
+pub fn compile_unit(
+    unit: &CompilationUnit,
+    resolution: &Resolution,
+    types: &TypeckResults,
+) -> Result<Module, WasmCodegenError> {
+    let mut module = Module::default();
+    let host = Host::declare(&mut module, unit);
+    let has_tag_defs = unit
+        .defs
+        .iter()
+        .any(|def| matches!(def, TopLevelDef::TagDef(_)));
+    let mut functions = FunctionTable::new(Analysis { resolution, types });
+    functions.heap = has_tag_defs.then(|| Heap::declare(&mut module, host.as_ref()));
+    functions.host = host;
+
+    let mut bodies = vec![];
+    for def in &unit.defs {
+        match def {
+            TopLevelDef::Func(fun) => {
+                let name = unit[fun.ident].0.clone();
+                let (locals, _) =
+                    declare_function(&mut module, &mut functions, unit, fun, &name, false)?;
+                bodies.push((name, None, fun, locals, None));
+            }
+            TopLevelDef::TagDef(tag_def) => {
+                let tag_name = &unit[tag_def.ident].0;
+                for fun in &tag_def.fns.0 {
+                    let fun_name = &unit[fun.ident].0;
+                    let name = format!("{tag_name}.{fun_name}");
+                    let (locals, instance) =
+                        declare_function(&mut module, &mut functions, unit, fun, &name, true)?;
+                    module.exports.add(&name, functions.functions[&name].id);
+                    bodies.push((name, Some(tag_name.as_str()), fun, locals, instance));
+                }
+                let instance_functions =
+                    InstanceFunctions::declare(&mut module, functions.analysis, unit, tag_def)?;
+                functions
+                    .instances
+                    .insert(tag_name.clone(), instance_functions);
+            }
+            TopLevelDef::Export(..) => {}
+        }
+    }
+
+    let mut layouts = HashMap::new();
+    if let Some(heap) = &functions.heap {
+        for def in &unit.defs {
+            if let TopLevelDef::TagDef(tag_def) = def {
+                let layout =
+                    compile_instance_functions(&mut module, heap, &functions, unit, tag_def)?;
+                layouts.insert(unit[tag_def.ident].0.as_str(), layout);
+            }
+        }
+    }
+
+    for (name, tag, fun, mut locals, instance) in bodies {
+        let signature = &functions.functions[&name];
+        let mut callees = functions.callees(tag);
+        if let (Some(instance), Some(layout)) = (instance, tag.and_then(|tag| layouts.get(tag))) {
+            callees = callees.with_instance(instance, layout);
+        }
+        compile_function_body(&mut module, signature, callees, &mut locals, unit, fun)?;
+    }
+
+    for def in &unit.defs {
+        if let TopLevelDef::Export(ident, tags) = def {
+            let parent = module.locals.add(ValType::I32);
+            let mut custom_tags = CustomTags {
+                functions: &functions,
+                tags: vec![],
+            };
+            for tag in &tags.0 {
+                custom_tags.visit_tag(unit, tag);
+            }
+            let handles = custom_tags
+                .tags
+                .iter()
+                .map(|(tag, _)| (*tag, module.locals.add(ValType::I32)))
+                .collect::<Vec<_>>();
+            let results = vec![ValType::I32; handles.len()];
+            let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &results);
+            let mut body = builder.func_body();
+            let mut locals = LocalMap::default();
+            let handle_map = handles.iter().copied().collect::<HashMap<_, _>>();
+            let callees = functions.callees(None).with_handles(&handle_map);
+            for tag in &tags.0 {
+                body.local_get(parent);
+                compile_tag(
+                    &mut body,
+                    &mut locals,
+                    &mut module.locals,
+                    callees,
+                    unit,
+                    tag,
+                )?;
+                if let Some(host) = &functions.host {
+                    body.call(host.append_child);
+                }
+            }
+            for (_, handle) in &handles {
+                body.local_get(*handle);
+            }
+            let name = &unit[*ident].0;
+            builder.name(name.clone());
+            let id = builder.finish(vec![parent], &mut module.funcs);
+            module.exports.add(name, id);
+        }
+    }
+
+    Ok(module)
+}
+
+/// Adds a function with an empty body to the module and `functions`.
+/// Arguments are flattened into params, struct results use multiple return values.
+/// Returns the locals the arguments are bound to, and the one of the instance with `instance`.
+fn declare_function(
+    module: &mut Module,
+    functions: &mut FunctionTable,
+    unit: &CompilationUnit,
+    fun: &FunctionDef,
+    name: &str,
+    instance: bool,
+) -> Result<(LocalMap, Option<LocalId>), WasmCodegenError> {
+    let mut locals = LocalMap::default();
+    let instance = instance.then(|| module.locals.add(ValType::I32));
+    let mut args = instance.into_iter().collect::<Vec<_>>();
+    let Ident(fun_name, span) = &unit[fun.ident];
+    let (arg_types, result) = functions
+        .analysis
+        .signature(fun.ident)
+        .ok_or_else(|| WasmCodegenError::Untyped(fun_name.clone(), *span))?;
+    for (arg, type_) in fun.args.0.iter().zip(&arg_types) {
+        args.extend(locals.declare(&mut module.locals, &unit[arg.name].0, type_.clone()));
+    }
+    let params = args
+        .iter()
+        .map(|local| module.locals.get(*local).ty())
+        .collect::<Vec<_>>();
+    let results = result
+        .iter()
+        .flat_map(|type_| type_.flatten("").into_iter())
+        .map(|(_, primitive)| primitive.val_type())
+        .collect::<Vec<_>>();
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &params, &results);
+    builder.name(name.into());
+    let id = builder.finish(args, &mut module.funcs);
+    functions.functions.insert(
+        name.into(),
+        FunctionSignature {
+            id,
+            result,
+            instance: instance.is_some(),
+        },
+    );
+    Ok((locals, instance))
+}
+
+/// Fills in the body of a declared function, the value of the last statement is returned
+fn compile_function_body(
+    module: &mut Module,
+    signature: &FunctionSignature,
+    callees: Callees,
+    locals: &mut LocalMap,
+    unit: &CompilationUnit,
+    fun: &FunctionDef,
+) -> Result<(), WasmCodegenError> {
+    let builder = module
+        .funcs
+        .get_mut(signature.id)
+        .kind
+        .unwrap_local_mut()
+        .builder_mut();
+    let mut body = builder.func_body();
+    let statements = &fun.block.0;
+    for (i, statement) in statements.iter().enumerate() {
+        let type_ = compile_statement_wasm(
+            &mut body,
+            locals,
+            &mut module.locals,
+            callees,
+            unit,
+            *statement,
+        )?;
+        if i + 1 < statements.len() || signature.result.is_none() {
+            drop_value(&mut body, type_);
+        }
+    }
+    // From now on state writes update the elements
+    if unit[fun.ident].0 == "view" {
+        if let Some(rendered) = callees.rendered() {
+            rendered.set(&mut body, 1);
+        }
+        if let Some(root) = callees.root() {
+            let element = module.locals.add(ValType::I32);
+            body.local_tee(element);
+            root.store(&mut body, element);
+        }
+    }
+    Ok(())
+}
+
+/// Pops a value that isn't used, which takes one `drop` per primitive
+pub(super) fn drop_value(builder: &mut InstrSeqBuilder, type_: Option<ChipType>) {
+    if let Some(type_) = type_ {
+        for _ in 0..type_.flatten("").len() {
+            builder.drop();
+        }
+    }
+}
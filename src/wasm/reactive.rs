@@ -0,0 +1,530 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::mem;
+
+use walrus::ir::{BinaryOp, UnaryOp};
+use walrus::{FunctionBuilder, FunctionId, InstrSeqBuilder, Module, ValType};
+
+use crate::ast::{
+    CompilationUnit, Ident, IdentId, Statement, StatementList, StmtId, Tag, TagChild, TagDef,
+};
+use crate::typeck::Ty;
+
+use super::error::WasmCodegenError;
+use super::host::{compile_set_property, compile_tag};
+use super::instance::{InstanceSlot, StateLayout, ROOT_OFFSET};
+use super::{compile_statement_wasm, Callees, LocalMap};
+
+/// The parts of a tag's `view()` that read its states. The properties of built-in tags
+/// are set again on their own, so the elements they belong to are kept in the instance.
+/// Branches and custom tags are built again as a whole, see [`Subtree`]. The `let`s a
+/// value reads are evaluated again before it. Everything else that reads a state can
+/// only be evaluated again by rendering it again.
+#[derive(Default)]
+pub struct ViewBindings<'u> {
+    /// The tags whose element is kept in the instance
+    pub elements: Vec<IdentId>,
+    /// The properties that read each state
+    pub properties: BTreeMap<String, Vec<PropertyBinding>>,
+    /// The parts that are built again when a state they read changes
+    pub subtrees: Vec<Subtree<'u>>,
+    /// The states that `view()` runs again for, see [`compile_update_functions`]
+    pub rerender: BTreeSet<String>,
+}
+
+/// `key=value` of the element created for `tag`
+#[derive(Clone)]
+pub struct PropertyBinding {
+    pub tag: IdentId,
+    pub key: IdentId,
+    pub value: StmtId,
+    /// The `let`s that `value` reads, see [`Reads::locals`]
+    pub locals: BTreeSet<StmtId>,
+}
+
+/// A part of `view()` that is built again and replaces the element it built before
+pub struct Subtree<'u> {
+    pub root: SubtreeRoot<'u>,
+    /// Every state read inside of it
+    pub states: BTreeSet<String>,
+    /// The `let`s before it that it reads, see [`Reads::locals`]
+    pub locals: BTreeSet<StmtId>,
+}
+
+#[derive(Clone, Copy)]
+pub enum SubtreeRoot<'u> {
+    /// An `if` with an `else` that results in a tag, its element is kept in the instance
+    Branch(StmtId),
+    /// A custom tag, its element is the root of the instance it gets
+    Tag(&'u Tag),
+}
+
+/// Finds the parts of the `view()` of `tag_def` that read a state in `layout`
+pub fn analyze_view<'u>(
+    unit: &'u CompilationUnit,
+    tag_def: &'u TagDef,
+    layout: &StateLayout,
+    callees: Callees,
+) -> ViewBindings<'u> {
+    let mut analysis = ViewAnalysis {
+        unit,
+        layout,
+        callees,
+        scopes: vec![HashMap::new()],
+        tags: 0,
+        branches: 0,
+        touched: Reads::default(),
+        pending: BTreeSet::new(),
+        declared: vec![],
+        once: HashSet::new(),
+        bindings: ViewBindings::default(),
+    };
+    if let Some(view) = tag_def.fns.0.iter().find(|fun| unit[fun.ident].0 == "view") {
+        for arg in &view.args.0 {
+            analysis.declare(&unit[arg.name].0, Reads::default(), None);
+        }
+        analysis.block(&view.block);
+    }
+    let mut bindings = analysis.bindings;
+    bindings.render_again_for(&analysis.once);
+    bindings
+}
+
+impl ViewBindings<'_> {
+    /// Leaves the parts that read a `let` in `once` to rendering again
+    fn render_again_for(&mut self, once: &HashSet<StmtId>) {
+        let repeatable = |locals: &BTreeSet<StmtId>| !locals.iter().any(|decl| once.contains(decl));
+        for (state, properties) in &mut self.properties {
+            let count = properties.len();
+            properties.retain(|property| repeatable(&property.locals));
+            if properties.len() != count {
+                self.rerender.insert(state.clone());
+            }
+        }
+        self.properties
+            .retain(|_, properties| !properties.is_empty());
+        let tags = self
+            .properties
+            .values()
+            .flatten()
+            .map(|property| property.tag)
+            .collect::<HashSet<_>>();
+        self.elements.retain(|tag| tags.contains(tag));
+        for subtree in mem::take(&mut self.subtrees) {
+            match repeatable(&subtree.locals) {
+                true => self.subtrees.push(subtree),
+                false => self.rerender.extend(subtree.states),
+            }
+        }
+    }
+}
+
+/// What a value reads
+#[derive(Default, Clone)]
+struct Reads {
+    states: BTreeSet<String>,
+    /// The `let`s of the locals it reads, and of the locals those read. Statements are
+    /// allocated in source order, so evaluating them in order gives the same values again.
+    locals: BTreeSet<StmtId>,
+}
+
+impl Reads {
+    fn extend(&mut self, other: Reads) {
+        self.states.extend(other.states);
+        self.locals.extend(other.locals);
+    }
+}
+
+/// A local of `view()` and what its value reads
+struct Local {
+    reads: Reads,
+    /// The `let` that declared it, `None` for an arg, which validation rejects
+    decl: Option<StmtId>,
+}
+
+struct ViewAnalysis<'u, 't> {
+    unit: &'u CompilationUnit,
+    layout: &'t StateLayout,
+    callees: Callees<'t>,
+    /// The locals of each block, innermost block last
+    scopes: Vec<HashMap<String, Local>>,
+    /// How many tags were visited, to find the ones inside of a branch
+    tags: usize,
+    /// How many of the enclosing branches depend on a state
+    branches: usize,
+    /// Everything read since the innermost subtree started, see [`ViewAnalysis::capture`]
+    touched: Reads,
+    /// The states read by the tags of the innermost branch that depends on a state.
+    /// They are updated along with the branch.
+    pending: BTreeSet<String>,
+    /// Every `let` so far
+    declared: Vec<StmtId>,
+    /// The `let`s that can't be evaluated again, since they don't have a value,
+    /// create tags or are assigned to later
+    once: HashSet<StmtId>,
+    bindings: ViewBindings<'u>,
+}
+
+impl<'u> ViewAnalysis<'u, '_> {
+    fn declare(&mut self, name: &str, reads: Reads, decl: Option<StmtId>) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.into(), Local { reads, decl });
+        }
+        self.declared.extend(decl);
+    }
+
+    fn local(&mut self, name: &str) -> Option<&mut Local> {
+        self.scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(name))
+    }
+
+    fn block(&mut self, block: &'u StatementList) -> Reads {
+        self.scopes.push(HashMap::new());
+        let mut reads = Reads::default();
+        for statement in &block.0 {
+            reads = self.statement(*statement);
+        }
+        self.scopes.pop();
+        reads
+    }
+
+    /// What the value of `id` reads
+    fn statement(&mut self, id: StmtId) -> Reads {
+        let unit = self.unit;
+        let mut reads = Reads::default();
+        match &unit[id] {
+            Statement::Number(..) | Statement::Error => {}
+            Statement::Op(left, _, right) => {
+                reads = self.statement(*left);
+                reads.extend(self.statement(*right));
+            }
+            Statement::Cast(value, _) => reads = self.statement(*value),
+            Statement::FunctionCall(call) => {
+                for arg in &call.args.0 {
+                    reads.extend(self.statement(*arg));
+                }
+                // Any state of the instance might be read by the callee
+                let name = &unit[call.ident].0;
+                if self.callees.get(name).is_some_and(|callee| callee.instance) {
+                    let states = self.layout.state_names().map(String::from);
+                    reads.states.extend(states);
+                    self.touched.states.extend(reads.states.clone());
+                }
+            }
+            Statement::If(condition, block) => {
+                reads = self.branches(id, *condition, &[block]);
+            }
+            Statement::IfElse(condition, if_block, else_block) => {
+                reads = self.branches(id, *condition, &[if_block, else_block]);
+            }
+            Statement::Let(_, ident, _) => {
+                self.once.insert(id);
+                self.declare(&unit[*ident].0, Reads::default(), Some(id));
+            }
+            Statement::LetAssign(_, ident, _, value) => {
+                let tags = self.tags;
+                let value = self.statement(*value);
+                if self.tags != tags {
+                    self.once.insert(id);
+                }
+                self.declare(&unit[*ident].0, value, Some(id));
+            }
+            Statement::Assign(idents, value) => {
+                let value = self.statement(*value);
+                if let Some(ident) = idents.0.first() {
+                    if let Some(local) = self.local(&unit[*ident].0) {
+                        local.reads.extend(value);
+                        let decl = local.decl;
+                        self.once.extend(decl);
+                    }
+                }
+            }
+            Statement::Ident(ident) => {
+                let name = &unit[*ident].0;
+                if let Some(local) = self.local(name) {
+                    reads = local.reads.clone();
+                    reads.locals.extend(local.decl);
+                } else if self.layout.is_state(name) {
+                    reads.states.insert(name.clone());
+                }
+                self.touched.extend(reads.clone());
+            }
+            Statement::Block(block) => reads = self.block(block),
+            Statement::Tag(tag) => self.tag(tag),
+        }
+        reads
+    }
+
+    /// Runs `visit` with nothing touched yet. Returns its result and what it read,
+    /// without the `let`s it declared itself.
+    fn capture<R>(&mut self, visit: impl FnOnce(&mut Self) -> R) -> (R, Reads) {
+        let outer = mem::take(&mut self.touched);
+        let declared = self.declared.len();
+        let result = visit(self);
+        let mut touched = mem::replace(&mut self.touched, outer);
+        self.touched.extend(touched.clone());
+        for decl in &self.declared[declared..] {
+            touched.locals.remove(decl);
+        }
+        (result, touched)
+    }
+
+    /// After a state in the condition changed, the other branch might run. A branch that
+    /// results in a tag is built again, along with the tags inside of it. Otherwise its
+    /// tags can only end up in the result of `view()` through a local, since a tag can't
+    /// take a local as its child, so `view()` runs again to replace them.
+    fn branches(&mut self, id: StmtId, condition: StmtId, blocks: &[&'u StatementList]) -> Reads {
+        let tags = self.tags;
+        let outer_pending = mem::take(&mut self.pending);
+        let ((reads, condition_states), touched) = self.capture(|this| {
+            let mut reads = this.statement(condition);
+            let condition_states = reads.states.clone();
+            let depends_on_state = !condition_states.is_empty() as usize;
+            this.branches += depends_on_state;
+            for block in blocks {
+                reads.extend(this.block(block));
+            }
+            this.branches -= depends_on_state;
+            (reads, condition_states)
+        });
+        let mut pending = mem::replace(&mut self.pending, outer_pending);
+        if self.tags != tags {
+            pending.extend(condition_states);
+        }
+        let types = &self.callees.analysis().types.types;
+        let is_tag = matches!(types.get(&id), Some(Ty::Tag));
+        if self.branches > 0 {
+            self.pending.extend(pending);
+        } else if !pending.is_empty() && is_tag {
+            self.bindings.subtrees.push(Subtree {
+                root: SubtreeRoot::Branch(id),
+                states: touched.states,
+                locals: touched.locals,
+            });
+        } else {
+            self.bindings.rerender.extend(pending);
+        }
+        reads
+    }
+
+    fn tag(&mut self, tag: &'u Tag) {
+        self.tags += 1;
+        let Ident(name, _) = &self.unit[tag.ident];
+        // A custom tag only gets its props in `new`, so it gets a new instance
+        if self.callees.view(name).is_some() && self.branches == 0 {
+            let ((), touched) = self.capture(|this| {
+                for (_, value) in &tag.properties.0 {
+                    this.statement(*value);
+                }
+            });
+            if !touched.states.is_empty() {
+                self.bindings.subtrees.push(Subtree {
+                    root: SubtreeRoot::Tag(tag),
+                    states: touched.states,
+                    locals: touched.locals,
+                });
+            }
+            return;
+        }
+        for (key, value) in &tag.properties.0 {
+            let ((), reads) = self.capture(|this| {
+                this.statement(*value);
+            });
+            if reads.states.is_empty() {
+                continue;
+            }
+            // The element of a tag in a branch might not exist
+            if self.branches > 0 {
+                self.pending.extend(reads.states);
+                continue;
+            }
+            if !self.bindings.elements.contains(&tag.ident) {
+                self.bindings.elements.push(tag.ident);
+            }
+            for state in reads.states {
+                self.bindings
+                    .properties
+                    .entry(state)
+                    .or_default()
+                    .push(PropertyBinding {
+                        tag: tag.ident,
+                        key: *key,
+                        value: *value,
+                        locals: reads.locals.clone(),
+                    });
+            }
+        }
+        for child in &tag.children.0 {
+            if let TagChild::Tag(child) = child {
+                self.tag(child);
+            }
+        }
+    }
+}
+
+/// Adds a function `Tag::update_state(instance)` for every state that `view()` reads.
+/// Once the instance was rendered, it sets the properties that read the state to their
+/// new values, builds the subtrees that read it again and leaves the rest of the elements
+/// alone. If anything else reads the state, it runs `view()` again and replaces the element
+/// it returned before with the new one. Returns the functions by state.
+pub fn compile_update_functions(
+    module: &mut Module,
+    tag_def: &TagDef,
+    callees: Callees,
+    layout: &StateLayout,
+    unit: &CompilationUnit,
+    bindings: &ViewBindings,
+) -> Result<HashMap<String, FunctionId>, WasmCodegenError> {
+    let Ident(tag_name, tag_span) = &unit[tag_def.ident];
+    let states = bindings
+        .properties
+        .keys()
+        .chain(&bindings.rerender)
+        .chain(bindings.subtrees.iter().flat_map(|subtree| &subtree.states))
+        .collect::<BTreeSet<_>>();
+    let mut updates = HashMap::new();
+    for state in states {
+        let instance = module.locals.add(ValType::I32);
+        let callees = callees.with_instance(instance, layout);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+        let mut body = builder.func_body();
+        // Before `view()` there are no elements to update
+        if let Some(rendered) = callees.rendered() {
+            rendered.load(&mut body);
+            body.unop(UnaryOp::I32Eqz).if_else(
+                None,
+                |then| {
+                    then.return_();
+                },
+                |_| {},
+            );
+        }
+        if bindings.rerender.contains(state) {
+            let unsupported =
+                || WasmCodegenError::Unsupported("Rendering this tag again", *tag_span);
+            let view = callees.view(tag_name).ok_or_else(unsupported)?;
+            let root = callees.root().ok_or_else(unsupported)?;
+            let host = callees.host().ok_or_else(unsupported)?;
+            // `view()` keeps the new element as the root
+            let old = module.locals.add(ValType::I32);
+            root.load(&mut body);
+            body.local_set(old)
+                .local_get(old)
+                .local_get(instance)
+                .call(view.id)
+                .call(host.replace_element);
+        } else {
+            for property in bindings.properties.get(state).into_iter().flatten() {
+                let span = unit[property.tag].1;
+                let element = callees
+                    .element(property.tag)
+                    .ok_or(WasmCodegenError::Unsupported("Updating this tag", span))?;
+                let mut locals = LocalMap::default();
+                compile_lets(
+                    &mut body,
+                    &mut locals,
+                    module,
+                    callees,
+                    unit,
+                    &property.locals,
+                )?;
+                element.load(&mut body);
+                compile_set_property(
+                    &mut body,
+                    &mut locals,
+                    &mut module.locals,
+                    callees,
+                    unit,
+                    property.key,
+                    property.value,
+                )?;
+            }
+            let subtrees = bindings
+                .subtrees
+                .iter()
+                .filter(|subtree| subtree.states.contains(state));
+            for subtree in subtrees {
+                compile_subtree(&mut body, module, callees, unit, subtree)?;
+            }
+        }
+        builder.name(format!("{tag_name}::update_{state}"));
+        updates.insert(
+            state.clone(),
+            builder.finish(vec![instance], &mut module.funcs),
+        );
+    }
+    Ok(updates)
+}
+
+/// Evaluates the `let`s in `locals` again, in the order they appear in `view()`
+fn compile_lets(
+    body: &mut InstrSeqBuilder,
+    locals: &mut LocalMap,
+    module: &mut Module,
+    callees: Callees,
+    unit: &CompilationUnit,
+    lets: &BTreeSet<StmtId>,
+) -> Result<(), WasmCodegenError> {
+    for decl in lets {
+        compile_statement_wasm(body, locals, &mut module.locals, callees, unit, *decl)?;
+    }
+    Ok(())
+}
+
+/// Builds `subtree` again and puts the new element where the old one is
+fn compile_subtree(
+    body: &mut InstrSeqBuilder,
+    module: &mut Module,
+    callees: Callees,
+    unit: &CompilationUnit,
+    subtree: &Subtree,
+) -> Result<(), WasmCodegenError> {
+    let span = match subtree.root {
+        SubtreeRoot::Branch(id) => unit.span(id).unwrap_or_default(),
+        SubtreeRoot::Tag(tag) => unit[tag.ident].1,
+    };
+    let unsupported = || WasmCodegenError::Unsupported("Building this tag again", span);
+    let host = callees.host().ok_or_else(unsupported)?;
+    let mut locals = LocalMap::default();
+    compile_lets(body, &mut locals, module, callees, unit, &subtree.locals)?;
+    let old = module.locals.add(ValType::I32);
+    match subtree.root {
+        SubtreeRoot::Branch(id) => {
+            // The branch keeps its new element in the same slot
+            callees.branch(id).ok_or_else(unsupported)?.load(body);
+            body.local_set(old);
+            compile_statement_wasm(body, &mut locals, &mut module.locals, callees, unit, id)?;
+        }
+        SubtreeRoot::Tag(tag) => {
+            // Read before the old instance is dropped
+            let child = callees.child(tag.ident).ok_or_else(unsupported)?;
+            let instance = module.locals.add(ValType::I32);
+            child.load(body);
+            body.local_set(instance);
+            let root = InstanceSlot {
+                memory: child.memory,
+                instance,
+                offset: ROOT_OFFSET,
+            };
+            root.load(body);
+            body.local_set(old);
+            compile_tag(body, &mut locals, &mut module.locals, callees, unit, tag)?;
+        }
+    }
+    let new = module.locals.add(ValType::I32);
+    body.local_set(new)
+        .local_get(old)
+        .local_get(new)
+        .call(host.replace_element);
+    // The subtree might be all that `view()` returned
+    if let Some(root) = callees.root() {
+        root.load(body);
+        body.local_get(old).binop(BinaryOp::I32Eq).if_else(
+            None,
+            |then| root.store(then, new),
+            |_| {},
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,399 @@
+//! Prints compiled modules in the wasm text format, for the `.wat` target
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+
+use walrus::ir::{Instr, InstrSeqId, InstrSeqType, LoadKind, MemArg, StoreKind, Value};
+use walrus::{
+    DataKind, ExportItem, FunctionId, FunctionKind, GlobalKind, ImportKind, InitExpr,
+    LocalFunction, LocalId, Module, ValType,
+};
+
+/// Prints a module this compiler emitted. Functions are named after their name in the
+/// module, locals and blocks after their index.
+pub fn print_wat(wasm: &[u8]) -> anyhow::Result<String> {
+    let module = Module::from_buffer(wasm)?;
+    let mut printer = Printer {
+        module: &module,
+        names: function_names(&module),
+        out: String::new(),
+    };
+    printer.module()?;
+    Ok(printer.out)
+}
+
+struct Printer<'m> {
+    module: &'m Module,
+    names: HashMap<FunctionId, String>,
+    out: String,
+}
+
+impl Printer<'_> {
+    fn module(&mut self) -> anyhow::Result<()> {
+        let module = self.module;
+        self.out.push_str("(module\n");
+        for import in module.imports.iter() {
+            if let ImportKind::Function(id) = import.kind {
+                let signature = self.signature(id);
+                writeln!(
+                    self.out,
+                    "  (import {:?} {:?} (func ${}{signature}))",
+                    import.module, import.name, self.names[&id]
+                )?;
+            }
+        }
+        for memory in module.memories.iter() {
+            write!(
+                self.out,
+                "  (memory $m{} {}",
+                memory.id().index(),
+                memory.initial
+            )?;
+            if let Some(maximum) = memory.maximum {
+                write!(self.out, " {maximum}")?;
+            }
+            self.out.push_str(")\n");
+        }
+        for global in module.globals.iter() {
+            let GlobalKind::Local(InitExpr::Value(value)) = &global.kind else {
+                anyhow::bail!("Can't print global {:?} as text", global.kind);
+            };
+            let ty = val_type(global.ty);
+            let ty = match global.mutable {
+                true => format!("(mut {ty})"),
+                false => ty.into(),
+            };
+            writeln!(
+                self.out,
+                "  (global $g{} {ty} ({}))",
+                global.id().index(),
+                constant(value)
+            )?;
+        }
+        for export in module.exports.iter() {
+            let item = match export.item {
+                ExportItem::Function(id) => format!("func ${}", self.names[&id]),
+                ExportItem::Memory(id) => format!("memory $m{}", id.index()),
+                ExportItem::Global(id) => format!("global $g{}", id.index()),
+                ExportItem::Table(_) => anyhow::bail!("Can't print tables as text"),
+            };
+            writeln!(self.out, "  (export {:?} ({item}))", export.name)?;
+        }
+        for fun in module.funcs.iter() {
+            if let FunctionKind::Local(local) = &fun.kind {
+                self.function(fun.id(), local)?;
+            }
+        }
+        for data in module.data.iter() {
+            let DataKind::Active(active) = &data.kind else {
+                anyhow::bail!("Can't print passive data as text");
+            };
+            let walrus::ActiveDataLocation::Absolute(offset) = active.location else {
+                anyhow::bail!("Can't print relative data as text");
+            };
+            // The compiled modules only have one memory
+            writeln!(
+                self.out,
+                "  (data (i32.const {offset}) \"{}\")",
+                escape(&data.value)
+            )?;
+        }
+        self.out.push_str(")\n");
+        Ok(())
+    }
+
+    /// ` (param i32 i32) (result i32)`, without names
+    fn signature(&self, id: FunctionId) -> String {
+        let ty = self.module.funcs.get(id).ty();
+        let mut signature = String::new();
+        let params = self.module.types.params(ty);
+        if !params.is_empty() {
+            signature.push_str(&format!(" (param {})", val_types(params)));
+        }
+        signature.push_str(&results(self.module.types.results(ty)));
+        signature
+    }
+
+    fn function(&mut self, id: FunctionId, fun: &LocalFunction) -> anyhow::Result<()> {
+        let module = self.module;
+        write!(self.out, "  (func ${}", self.names[&id])?;
+        for arg in &fun.args {
+            write!(
+                self.out,
+                " (param $l{} {})",
+                arg.index(),
+                self.local_type(*arg)
+            )?;
+        }
+        self.out
+            .push_str(&results(module.types.results(module.funcs.get(id).ty())));
+        self.out.push('\n');
+
+        let mut locals = BTreeMap::new();
+        collect_locals(fun, fun.entry_block(), &mut locals);
+        for arg in &fun.args {
+            locals.remove(&arg.index());
+        }
+        for (index, local) in locals {
+            writeln!(self.out, "    (local $l{index} {})", self.local_type(local))?;
+        }
+        self.seq(fun, fun.entry_block(), 2)?;
+        self.out.push_str("  )\n");
+        Ok(())
+    }
+
+    fn local_type(&self, local: LocalId) -> &'static str {
+        val_type(self.module.locals.get(local).ty())
+    }
+
+    fn seq(&mut self, fun: &LocalFunction, seq: InstrSeqId, depth: usize) -> anyhow::Result<()> {
+        for (instr, _) in &fun.block(seq).instrs {
+            let indent = "  ".repeat(depth);
+            let line = match instr {
+                Instr::Block(block) => {
+                    self.nested(fun, "block", block.seq, depth)?;
+                    continue;
+                }
+                Instr::Loop(block) => {
+                    self.nested(fun, "loop", block.seq, depth)?;
+                    continue;
+                }
+                Instr::IfElse(if_else) => {
+                    self.nested(fun, "if", if_else.consequent, depth)?;
+                    self.out.truncate(self.out.len() - "end\n".len());
+                    self.out.push_str("else\n");
+                    self.seq(fun, if_else.alternative, depth + 1)?;
+                    writeln!(self.out, "{indent}end")?;
+                    continue;
+                }
+                Instr::Call(call) => format!("call ${}", self.names[&call.func]),
+                Instr::LocalGet(get) => format!("local.get $l{}", get.local.index()),
+                Instr::LocalSet(set) => format!("local.set $l{}", set.local.index()),
+                Instr::LocalTee(tee) => format!("local.tee $l{}", tee.local.index()),
+                Instr::GlobalGet(get) => format!("global.get $g{}", get.global.index()),
+                Instr::GlobalSet(set) => format!("global.set $g{}", set.global.index()),
+                Instr::Const(value) => constant(&value.value),
+                Instr::Binop(binop) => op_name(&format!("{:?}", binop.op)),
+                Instr::Unop(unop) => op_name(&format!("{:?}", unop.op)),
+                Instr::Br(br) => format!("br $b{}", br.block.index()),
+                Instr::BrIf(br) => format!("br_if $b{}", br.block.index()),
+                Instr::Select(_) => "select".into(),
+                Instr::Drop(_) => "drop".into(),
+                Instr::Return(_) => "return".into(),
+                Instr::Unreachable(_) => "unreachable".into(),
+                Instr::MemorySize(_) => "memory.size".into(),
+                Instr::MemoryGrow(_) => "memory.grow".into(),
+                Instr::Load(load) => {
+                    let name = match load.kind {
+                        LoadKind::I32 { .. } => "i32.load",
+                        LoadKind::I64 { .. } => "i64.load",
+                        LoadKind::F32 => "f32.load",
+                        LoadKind::F64 => "f64.load",
+                        kind => anyhow::bail!("Can't print {kind:?} loads as text"),
+                    };
+                    memory_instr(name, load.arg)
+                }
+                Instr::Store(store) => {
+                    let name = match store.kind {
+                        StoreKind::I32 { .. } => "i32.store",
+                        StoreKind::I64 { .. } => "i64.store",
+                        StoreKind::F32 => "f32.store",
+                        StoreKind::F64 => "f64.store",
+                        kind => anyhow::bail!("Can't print {kind:?} stores as text"),
+                    };
+                    memory_instr(name, store.arg)
+                }
+                instr => anyhow::bail!("Can't print {instr:?} as text"),
+            };
+            writeln!(self.out, "{indent}{line}")?;
+        }
+        Ok(())
+    }
+
+    /// `block`, `loop` or `if`, labeled with the index of `seq`
+    fn nested(
+        &mut self,
+        fun: &LocalFunction,
+        keyword: &str,
+        seq: InstrSeqId,
+        depth: usize,
+    ) -> anyhow::Result<()> {
+        let indent = "  ".repeat(depth);
+        let block_type = match fun.block(seq).ty {
+            InstrSeqType::Simple(None) => String::new(),
+            InstrSeqType::Simple(Some(ty)) => format!(" (result {})", val_type(ty)),
+            InstrSeqType::MultiValue(ty) => {
+                let params = self.module.types.params(ty);
+                let mut block_type = String::new();
+                if !params.is_empty() {
+                    block_type.push_str(&format!(" (param {})", val_types(params)));
+                }
+                block_type + &results(self.module.types.results(ty))
+            }
+        };
+        writeln!(self.out, "{indent}{keyword} $b{}{block_type}", seq.index())?;
+        self.seq(fun, seq, depth + 1)?;
+        writeln!(self.out, "{indent}end")?;
+        Ok(())
+    }
+}
+
+/// The name of every function, made unique with its index where needed.
+/// Imports are named after the function they import.
+fn function_names(module: &Module) -> HashMap<FunctionId, String> {
+    let mut names = HashMap::new();
+    let mut taken = HashSet::new();
+    for fun in module.funcs.iter() {
+        let name = match &fun.kind {
+            FunctionKind::Import(import) => {
+                let import = module.imports.get(import.import);
+                Some(format!("{}.{}", import.module, import.name))
+            }
+            _ => fun.name.clone(),
+        };
+        let name = match name {
+            Some(name) if is_id(&name) && !taken.contains(&name) => name,
+            _ => format!("f{}", fun.id().index()),
+        };
+        taken.insert(name.clone());
+        names.insert(fun.id(), name);
+    }
+    names
+}
+
+/// Whether `name` can follow a `$` without quotes
+fn is_id(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-./:<=>?@\\^_`|~".contains(c))
+}
+
+/// The locals `seq` uses by index, so they are declared in order
+fn collect_locals(fun: &LocalFunction, seq: InstrSeqId, locals: &mut BTreeMap<usize, LocalId>) {
+    for (instr, _) in &fun.block(seq).instrs {
+        let local = match instr {
+            Instr::LocalGet(get) => get.local,
+            Instr::LocalSet(set) => set.local,
+            Instr::LocalTee(tee) => tee.local,
+            Instr::Block(block) => {
+                collect_locals(fun, block.seq, locals);
+                continue;
+            }
+            Instr::Loop(block) => {
+                collect_locals(fun, block.seq, locals);
+                continue;
+            }
+            Instr::IfElse(if_else) => {
+                collect_locals(fun, if_else.consequent, locals);
+                collect_locals(fun, if_else.alternative, locals);
+                continue;
+            }
+            _ => continue,
+        };
+        locals.insert(local.index(), local);
+    }
+}
+
+fn val_type(ty: ValType) -> &'static str {
+    match ty {
+        ValType::I32 => "i32",
+        ValType::I64 => "i64",
+        ValType::F32 => "f32",
+        ValType::F64 => "f64",
+        ValType::V128 => "v128",
+        ValType::Externref => "externref",
+        ValType::Funcref => "funcref",
+    }
+}
+
+fn val_types(types: &[ValType]) -> String {
+    types
+        .iter()
+        .map(|ty| val_type(*ty))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn results(types: &[ValType]) -> String {
+    match types {
+        [] => String::new(),
+        types => format!(" (result {})", val_types(types)),
+    }
+}
+
+fn constant(value: &Value) -> String {
+    match value {
+        Value::I32(value) => format!("i32.const {value}"),
+        Value::I64(value) => format!("i64.const {value}"),
+        Value::F32(value) => format!("f32.const {}", float(f64::from(*value))),
+        Value::F64(value) => format!("f64.const {}", float(*value)),
+        Value::V128(value) => format!(
+            "v128.const i64x2 {} {}",
+            *value as u64,
+            (value >> 64) as u64
+        ),
+    }
+}
+
+/// Rust prints the shortest decimal that reads back as the same float
+fn float(value: f64) -> String {
+    match value {
+        value if value.is_nan() => "nan".into(),
+        f64::INFINITY => "inf".into(),
+        f64::NEG_INFINITY => "-inf".into(),
+        value => value.to_string(),
+    }
+}
+
+fn memory_instr(name: &str, arg: MemArg) -> String {
+    format!("{name} offset={} align={}", arg.offset, arg.align)
+}
+
+/// The text name of a `walrus` operator: `I32GtU` is `i32.gt_u`, `F64ConvertSI64` is
+/// `f64.convert_i64_s` and `I32WrapI64` is `i32.wrap_i64`
+fn op_name(debug: &str) -> String {
+    let (ty, op) = debug.split_at(3);
+    let (op, from) = match ["I32", "I64", "F32", "F64"]
+        .into_iter()
+        .find(|from| op.len() > 3 && op.ends_with(from))
+    {
+        Some(from) => (&op[..op.len() - 3], Some(from)),
+        None => (op, None),
+    };
+    let (op, sign) = match op.strip_suffix('S').or_else(|| op.strip_suffix('U')) {
+        Some(rest) if !rest.is_empty() && !rest.ends_with(|c: char| c.is_ascii_uppercase()) => {
+            (rest, Some(&op[rest.len()..]))
+        }
+        _ => (op, None),
+    };
+    let mut name = format!("{}.", ty.to_ascii_lowercase());
+    for (i, c) in op.char_indices() {
+        if c.is_ascii_uppercase() && i > 0 {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    if let Some(from) = from {
+        name.push('_');
+        name.push_str(&from.to_ascii_lowercase());
+    }
+    if let Some(sign) = sign {
+        name.push('_');
+        name.push_str(&sign.to_ascii_lowercase());
+    }
+    name
+}
+
+/// Printable ASCII stays as it is, everything else is a `\hh` escape
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+    for byte in bytes {
+        match byte {
+            b'"' | b'\\' => escaped.push_str(&format!("\\{}", *byte as char)),
+            0x20..=0x7e => escaped.push(*byte as char),
+            _ => escaped.push_str(&format!("\\{byte:02x}")),
+        }
+    }
+    escaped
+}